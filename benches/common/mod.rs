@@ -0,0 +1,20 @@
+//! Input shared by the benchmarks.
+
+/// Number of clients in the synthetic input.
+pub const CLIENTS: u32 = 10_000;
+
+/// Every client gets two deposits and a withdrawal in turns, so all
+/// of the transactions are applied. With a timestamp and a reason
+/// column, so that parsing costs about as much as in real inputs.
+pub fn synthetic_csv(count: u32) -> String {
+    let mut input = String::from("type,client,tx,amount,reason,timestamp\n");
+    for tx_id in 0..count {
+        let client_id = tx_id % CLIENTS;
+        let tx_type = match (tx_id / CLIENTS) % 3 {
+            2 => "withdrawal",
+            _ => "deposit",
+        };
+        input += &format!("{},{},{},1.5,,{}\n", tx_type, client_id, tx_id, 1_600_000_000 + tx_id);
+    }
+    input
+}
@@ -0,0 +1,69 @@
+//! Time it takes to read a large synthetic csv input into the bank.
+//!
+//! Compares parsing on the current thread, which is the only way before
+//! `Pipeline`, with parsing on multiple threads, and sending transactions to
+//! the bank threads one by one with sending them in batches. Run with
+//! `cargo bench --bench ingest`, input size can be changed with `BENCH_TXS`
+//! env var and bank threads with `BENCH_THREADS`.
+
+use std::time::{Duration, Instant};
+
+use payments_engine_rs::{Bank, BasicBank, ConcurrentBank, Pipeline, Policy};
+use payments_engine_rs::concurrent_bank::Builder;
+
+mod common;
+use common::{synthetic_csv, CLIENTS};
+
+/// Time `f` takes to apply input and get the accounts out of the bank.
+fn measure<B: Bank>(name: &str, count: u32, f: impl FnOnce() -> B) {
+    let start = Instant::now();
+    let accounts = f().into_accounts_iter().count();
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<24} {:>8.2?} {:>6.2} M tx/s {:>6} accounts",
+        name,
+        elapsed,
+        count as f64 / elapsed.as_secs_f64() / 1e6,
+        accounts,
+    );
+}
+
+fn env_var(name: &str) -> Option<usize> {
+    std::env::var(name).ok().and_then(|val| val.parse().ok())
+}
+
+fn main() {
+    let count = env_var("BENCH_TXS").unwrap_or(2_000_000) as u32;
+    let threads = env_var("BENCH_THREADS").unwrap_or_else(num_cpus::get);
+    let input = synthetic_csv(count);
+    println!("{} transactions, {} clients, {} bank threads", count, CLIENTS, threads);
+
+    measure("basic", count, || {
+        BasicBank::from_input_transactions_csv(input.as_bytes()).unwrap()
+    });
+    measure("concurrent", count, || {
+        let mut bank = ConcurrentBank::new_with_thread_count(threads);
+        bank.apply_input_transactions_csv(input.as_bytes()).unwrap();
+        bank
+    });
+
+    measure("concurrent, batches", count, || {
+        let mut bank = Builder::new(Policy::default())
+            .with_thread_count(threads)
+            .with_batching(256, Duration::from_millis(10))
+            .build()
+            .unwrap();
+        bank.apply_input_transactions_csv(input.as_bytes()).unwrap();
+        bank
+    });
+
+    for &parsers in &[1, 2, 4, 8] {
+        let name = format!("concurrent, {} parsers", parsers);
+        measure(&name, count, || {
+            let mut bank = ConcurrentBank::new_with_thread_count(threads);
+            Pipeline::new(parsers).apply_csv(&mut bank, input.as_bytes()).unwrap();
+            bank
+        });
+    }
+}
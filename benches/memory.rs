@@ -0,0 +1,143 @@
+//! Memory used by the transaction history on a large synthetic input.
+//!
+//! Compares bank's history (id set plus records of disputable transactions
+//! only) with keeping every deposit and withdrawal in a map, like the bank
+//! used to, and with the bank that keeps history and owners of transactions
+//! on disk. Index of the transaction owners, that the bank and the router of
+//! the concurrent bank check transactions against, is measured on it's own,
+//! and so is the concurrent bank, which also has one in every thread. Run
+//! with `cargo bench --bench memory`, input size can be changed with
+//! `BENCH_TXS` env var.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use payments_engine_rs::{Bank, BasicBank, ConcurrentBank, Policy, Transaction};
+use payments_engine_rs::concurrent_bank::Builder;
+use payments_engine_rs::input_transaction::InputTransaction;
+use payments_engine_rs::tx_index::TxIndex;
+use payments_engine_rs::types::{ClientID, TransactionID};
+
+mod common;
+use common::{synthetic_csv, CLIENTS};
+
+/// Transactions the bank on disk keeps in memory, per thread.
+const DISK_CACHE: usize = 10_000;
+
+/// Threads of the concurrent bank.
+const THREADS: usize = 4;
+
+/// Allocator that keeps track of currently allocated bytes.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn transactions(input: &str) -> impl Iterator<Item = Transaction> + '_ {
+    csv::Reader::from_reader(input.as_bytes())
+        .into_deserialize::<InputTransaction>()
+        .map(|input_tx| Transaction::try_from(input_tx.unwrap()).unwrap())
+}
+
+/// Bytes allocated by the value that `f` builds, while it's alive.
+fn measure<T>(name: &str, count: u32, f: impl FnOnce() -> T) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let value = f();
+    let elapsed = start.elapsed();
+    let used = ALLOCATED.load(Ordering::Relaxed).saturating_sub(before);
+
+    println!(
+        "{:<16} {:>8.1} MiB {:>6.1} bytes/tx {:>8.2?}",
+        name,
+        used as f64 / (1 << 20) as f64,
+        used as f64 / count as f64,
+        elapsed,
+    );
+    drop(value);
+}
+
+fn main() {
+    let count = std::env::var("BENCH_TXS").ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(5_000_000);
+    let input = synthetic_csv(count);
+    println!("{} transactions, {} clients", count, CLIENTS);
+
+    measure("full history", count, || {
+        let mut owners: HashMap<TransactionID, ClientID> = HashMap::new();
+        let mut accounts: HashMap<ClientID, HashMap<TransactionID, Transaction>> = HashMap::new();
+        for tx in transactions(&input) {
+            owners.insert(tx.get_tx_id(), tx.get_client_id());
+            accounts.entry(tx.get_client_id()).or_default().insert(tx.get_tx_id(), tx);
+        }
+        (owners, accounts)
+    });
+
+    measure("bank", count, || {
+        BasicBank::from_input_transactions_csv(input.as_bytes()).unwrap()
+    });
+
+    let dir = std::env::temp_dir().join(format!("payments-engine-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    measure("bank on disk", count, || {
+        let mut bank = BasicBank::new().with_disk_store(dir.join("bank.bin"), DISK_CACHE).unwrap();
+        bank.apply_input_transactions_csv(input.as_bytes()).unwrap();
+        bank
+    });
+
+    measure("owners index", count, || {
+        let mut index = TxIndex::new_with_policy(Policy::default());
+        transactions(&input).for_each(|tx| index.check(&tx).unwrap());
+        index
+    });
+    measure("owners on disk", count, || {
+        let mut index = TxIndex::new_with_policy(Policy::default())
+            .with_owners_on_disk(dir.join("owners.bin"))
+            .unwrap();
+        transactions(&input).for_each(|tx| index.check(&tx).unwrap());
+        index
+    });
+
+    measure("concurrent", count, || {
+        let mut bank = ConcurrentBank::new_with_thread_count(THREADS);
+        bank.apply_input_transactions_csv(input.as_bytes()).unwrap();
+        // waits for threads to apply everything.
+        bank.accounts();
+        bank
+    });
+    measure("concurrent, disk", count, || {
+        let mut bank = Builder::new(Policy::default())
+            .with_thread_count(THREADS)
+            .with_disk_stores(&dir, DISK_CACHE)
+            .build()
+            .unwrap();
+        bank.apply_input_transactions_csv(input.as_bytes()).unwrap();
+        bank.accounts();
+        bank
+    });
+    std::fs::remove_dir(&dir).unwrap();
+}
@@ -1,177 +1,935 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use rust_decimal::prelude::Zero;
+use serde::{Serialize, Deserialize};
 
 use crate::types::{ClientID, TransactionID, Amount};
-use crate::transaction::Transaction;
+use crate::transaction::{
+    Transaction, TransactionInfo, TransferInfo, Adjustment, Conversion, check_ref_amount,
+};
+use crate::tx_store::{TxStore, MemoryTxStore, TxRecord, TxKind};
+use crate::id_set::IdSet;
 use crate::output_account::OutputAccount;
+use crate::error::EngineError;
+use crate::policy::Policy;
+use crate::audit::{AuditAction, AuditRecord};
+use crate::expiry::{Moment, Expiration, ExpiryAction};
+use crate::currency::Currency;
+use crate::rates::SpreadTotal;
+
+/// Funds reserved by the authorization, that aren't captured or voided yet.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Authorization {
+    reserved: Amount,
+    currency: Currency,
+    /// When authorization happened.
+    at: Moment,
+}
+
+/// Account's balance in one currency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    /// Amount on the balance that can be withdrawn.
+    pub available: Amount,
+    /// Amount that is `held` because of the ongoing disputes.
+    pub held: Amount,
+    /// Amount that is reserved by authorizations untill
+    /// it's captured or voided.
+    pub reserved: Amount,
+    /// Sum of all fees charged.
+    pub fees: Amount,
+    /// Sum of all manual corrections, positive or negative.
+    pub adjustments: Amount,
+}
+
+impl Balance {
+    /// Total amount: **available + held + reserved**
+    pub fn total(&self) -> Amount {
+        self.available + self.held + self.reserved
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Account {
     client_id: ClientID,
-    /// Amount on the balance that.
-    available: Amount,
-    /// Amount that is `held` because of the ongoing dispute.
-    held: Amount,
+    /// Balances by currency. Currency gets one with
+    /// the first transaction applied in it.
+    balances: BTreeMap<Currency, Balance>,
     /// Whether account is locked/frozen.
     /// Happens if we encounter `Transaction::Chargeback`
     locked: bool,
-    transactions: HashMap<TransactionID, Transaction>,
+    /// Ids of all transactions that have their own id, to detect duplicates.
+    tx_ids: IdSet,
+    /// Records of the transactions that can be disputed.
+    transactions: Box<dyn TxStore>,
+    /// Isn't part of the account's state, it's set by the bank.
+    #[serde(skip)]
+    policy: Policy,
+    /// Changes of the `locked` state and manual corrections.
+    audit_log: Vec<AuditRecord>,
+    /// When disputes of the transactions, that are
+    /// under dispute, were opened.
+    open_disputes: HashMap<TransactionID, Moment>,
+    /// Disputes that expired and were settled automatically.
+    expirations: Vec<Expiration>,
+    /// Authorizations with reserved funds left.
+    authorizations: HashMap<TransactionID, Authorization>,
+    /// Totals of the conversions, one per currency pair.
+    conversions: Vec<SpreadTotal>,
 }
 
 impl Account {
     /// Creates an **unlocked** account with **zero** balance
     /// and with no transactions.
     pub fn new(client_id: ClientID) -> Self {
+        Self::new_with_policy(client_id, Policy::default())
+    }
+
+    /// Same as [Account::new], but with custom [Policy].
+    pub fn new_with_policy(client_id: ClientID, policy: Policy) -> Self {
+        Self::new_with_store(client_id, policy, Box::new(MemoryTxStore::new()))
+    }
+
+    /// Same as [Account::new_with_policy], but transactions are kept in `store`.
+    pub fn new_with_store(
+        client_id: ClientID,
+        policy: Policy,
+        store: Box<dyn TxStore>,
+    ) -> Self {
         Self {
             client_id,
-            available: Default::default(),
-            held: Default::default(),
+            balances: BTreeMap::new(),
             locked: false,
-            transactions: HashMap::new(),
+            tx_ids: IdSet::new(),
+            transactions: store,
+            policy,
+            audit_log: Vec::new(),
+            open_disputes: HashMap::new(),
+            expirations: Vec::new(),
+            authorizations: HashMap::new(),
+            conversions: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Moves all transactions to the `store`, which is used from now on.
+    pub(crate) fn set_store(&mut self, mut store: Box<dyn TxStore>) -> io::Result<()> {
+        for record in self.transactions.all()? {
+            let (tx_id, record) = record?;
+            store.put(tx_id, record)?;
         }
+        self.transactions = store;
+        Ok(())
+    }
+
+    /// Changes of the account's locked state and manual
+    /// corrections of it's balance, in order they happened.
+    pub fn audit_log(&self) -> &[AuditRecord] {
+        &self.audit_log
+    }
+
+    /// Disputes that stayed open past the deadline of the
+    /// [Policy::dispute_expiry], in order they expired.
+    pub fn expirations(&self) -> &[Expiration] {
+        &self.expirations
+    }
+
+    /// Sums of the conversions and spread collected by
+    /// them, one per currency pair, in order of first use.
+    pub fn conversions(&self) -> &[SpreadTotal] {
+        &self.conversions
+    }
+
+    fn audit(&mut self, tx_id: TransactionID, action: AuditAction, reason: Option<String>) {
+        self.audit_log.push(AuditRecord {
+            client_id: self.client_id,
+            tx_id,
+            action,
+            reason,
+        });
+    }
+
+    pub fn client_id(&self) -> ClientID {
+        self.client_id
+    }
+
+    /// Balance in `currency`, zero if there were no transactions in it.
+    pub fn balance(&self, currency: Currency) -> Balance {
+        self.balances.get(&currency).copied().unwrap_or_default()
     }
 
-    /// Total amount that user has: **available + held**
+    /// Balances in all currencies that account had transactions in.
+    pub fn balances(&self) -> &BTreeMap<Currency, Balance> {
+        &self.balances
+    }
+
+    /// Should only be called once transaction is checked, so
+    /// refused transactions don't leave empty balances behind.
+    fn balance_mut(&mut self, currency: Currency) -> &mut Balance {
+        self.balances.entry(currency).or_default()
+    }
+
+    /// Amount on the balance that can be withdrawn,
+    /// in the default currency.
+    pub fn available(&self) -> Amount {
+        self.balance(Currency::default()).available
+    }
+
+    /// Amount that is `held` because of the ongoing disputes,
+    /// in the default currency.
+    pub fn held(&self) -> Amount {
+        self.balance(Currency::default()).held
+    }
+
+    /// Amount that is reserved by authorizations,
+    /// in the default currency.
+    pub fn reserved(&self) -> Amount {
+        self.balance(Currency::default()).reserved
+    }
+
+    /// Total amount that user has in the default
+    /// currency: **available + held + reserved**
     pub fn total(&self) -> Amount {
-        self.available + self.held
+        self.balance(Currency::default()).total()
+    }
+
+    /// Whether account is locked/frozen.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Sum of all fees charged from the account,
+    /// in the default currency.
+    pub fn fees(&self) -> Amount {
+        self.balance(Currency::default()).fees
     }
 
+    /// Sum of all manual corrections of the account's
+    /// balance, in the default currency.
+    pub fn adjustments(&self) -> Amount {
+        self.balance(Currency::default()).adjustments
+    }
+
+    /// Records of the transactions in account's history, that can be
+    /// referenced by disputes, with their ids. In no particular order.
+    pub fn transactions(&self) -> io::Result<Vec<(TransactionID, TxRecord)>> {
+        self.transactions.all()?.collect()
+    }
+
+    /// Record of the transaction with `tx_id` from account's history.
+    pub fn transaction(&self, tx_id: TransactionID) -> io::Result<Option<TxRecord>> {
+        self.transactions.get(tx_id)
+    }
+
+    /// Record of the transaction that dispute, resolve
+    /// or chargeback references.
+    fn disputable_record(&self, tx_id: TransactionID) -> Result<TxRecord, EngineError> {
+        let client_id = self.client_id;
+        let record = self.transactions.get(tx_id)
+            .map_err(|err| self.storage_error(tx_id, err))?;
+
+        match record {
+            Some(record) => Ok(record),
+            // transaction exists, but wasn't kept since it can't be disputed.
+            None if self.tx_ids.contains(tx_id) => {
+                Err(EngineError::NotDisputable { client_id, tx_id })
+            },
+            None => Err(EngineError::TxNotFound { client_id, tx_id }),
+        }
+    }
+
+    fn save_record(&mut self, tx_id: TransactionID, record: TxRecord) -> Result<(), EngineError> {
+        self.transactions.put(tx_id, record)
+            .map_err(|err| self.storage_error(tx_id, err))
+    }
+
+    fn storage_error(&self, tx_id: TransactionID, err: io::Error) -> EngineError {
+        EngineError::Storage {
+            client_id: self.client_id,
+            tx_id,
+            message: err.to_string(),
+        }
+    }
+
+    /// Disputes `amount` of the transaction, or all of it's undisputed
+    /// amount if `amount` is `None`. Can be called multiple times for
+    /// the same transaction, as long as disputed amount doesn't exceed
+    /// transaction's amount.
+    ///
     /// Should be called cautiously outside `apply_tx`, since `apply_tx`
     /// does bunch of checks before calling this method, which we don't
     /// do here. Also transation won't be added to `Self::transactions`.
-    fn dispute_tx_with_id(&mut self, tx_id: TransactionID) -> Result<(), &'static str> {
-        let tx = self.transactions.get_mut(&tx_id)
-            .ok_or("transaction for dispute not found")?;
-
-        match tx {
-            Transaction::Deposit(tx_info) => {
-                if tx_info.under_dispute {
-                    return Err("can't dispute transaction that's already under dispute");
-                }
+    fn dispute_tx_with_id(
+        &mut self,
+        tx_id: TransactionID,
+        amount: Option<Amount>,
+        at: Moment,
+    ) -> Result<(), EngineError> {
+        let client_id = self.client_id;
+        // zero would open a dispute, that holds nothing.
+        check_ref_amount(client_id, tx_id, amount)?;
+        let mut record = self.disputable_record(tx_id)?;
+
+        let is_deposit = match record.kind {
+            TxKind::Deposit => true,
+            TxKind::Withdrawal if self.policy.withdrawal_disputes => false,
+            TxKind::Withdrawal => {
+                return Err(EngineError::NotDisputable { client_id, tx_id });
+            },
+        };
 
-                if self.available < tx_info.amount {
-                    return Err("insufficient funds for dispute");
-                }
+        if let Some(window) = self.policy.dispute_window {
+            if window.is_exceeded(record.at, at) {
+                return Err(EngineError::DisputeWindowExpired { client_id, tx_id });
+            }
+        }
 
-                tx_info.under_dispute = true;
-                self.available -= tx_info.amount;
-                self.held += tx_info.amount;
+        let undisputed = record.undisputed();
+        let amount = match amount {
+            Some(amount) if amount > undisputed => {
+                return Err(EngineError::DisputeExceedsTx {
+                    client_id,
+                    tx_id,
+                    requested: amount,
+                    undisputed,
+                });
+            },
+            Some(amount) => amount,
+            None if undisputed.is_zero() => {
+                return Err(EngineError::AlreadyUnderDispute { client_id, tx_id });
             },
-            _ => return Err("only deposit transaction can be disputed"),
+            None => undisputed,
         };
 
+        // disputed funds are held in the currency of the transaction.
+        let available = self.balance(record.currency).available;
+        if is_deposit && available < amount {
+            return Err(EngineError::InsufficientFunds {
+                client_id,
+                tx_id,
+                needed: amount,
+                available,
+            });
+        }
+
+        let is_opened = !record.is_under_dispute();
+        record.disputed += amount;
+        self.save_record(tx_id, record)?;
+
+        if is_opened {
+            self.open_disputes.insert(tx_id, at);
+        }
+        let balance = self.balance_mut(record.currency);
+        if is_deposit {
+            balance.available -= amount;
+        }
+        // for withdrawal, withdrawn funds are credited back,
+        // but held until resolved.
+        balance.held += amount;
+
         Ok(())
     }
 
+    /// Resolves `amount` of the disputed transaction, or all of it's
+    /// disputed amount if `amount` is `None`.
+    ///
     /// Should be called cautiously outside `apply_tx`, since `apply_tx`
     /// does bunch of checks before calling this method, which we don't
     /// do here. Also transation won't be added to `Self::transactions`.
-    fn resolve_tx_with_id(&mut self, tx_id: TransactionID) -> Result<(), &'static str> {
-        let tx = self.transactions.get_mut(&tx_id)
-            .ok_or("transaction for resolve not found")?;
-
-        match tx {
-            Transaction::Deposit(tx_info) => {
-                if !tx_info.under_dispute {
-                    return Err("transaction is not under dispute");
-                }
+    fn resolve_tx_with_id(
+        &mut self,
+        tx_id: TransactionID,
+        amount: Option<Amount>,
+    ) -> Result<(), EngineError> {
+        let (amount, record) = self.settle_tx_with_id(tx_id, amount)?;
+
+        // for withdrawal, withdrawal stands, so funds
+        // credited on dispute are simply removed.
+        if record.kind == TxKind::Deposit {
+            self.balance_mut(record.currency).available += amount;
+        }
 
-                if self.held < tx_info.amount {
-                    unreachable!("held amount is less then disputed amount");
-                }
+        Ok(())
+    }
+
+    /// Charges back `amount` of the disputed transaction, or all of it's
+    /// disputed amount if `amount` is `None`. Locks the account.
+    ///
+    /// Should be called cautiously outside `apply_tx`, since `apply_tx`
+    /// does bunch of checks before calling this method, which we don't
+    /// do here. Also transation won't be added to `Self::transactions`.
+    fn chargeback_tx_with_id(
+        &mut self,
+        tx_id: TransactionID,
+        amount: Option<Amount>,
+    ) -> Result<(), EngineError> {
+        let (amount, record) = self.settle_tx_with_id(tx_id, amount)?;
+
+        // for withdrawal, withdrawal is reversed,
+        // so funds are returned to the client.
+        if record.kind == TxKind::Withdrawal {
+            self.balance_mut(record.currency).available += amount;
+        }
+
+        // should lock account if chargeback occured.
+        self.locked = true;
+        self.audit(tx_id, AuditAction::Chargeback, None);
+        Ok(())
+    }
+
+    /// Releases settled amount of the disputed transaction from `held`,
+    /// common part of resolve and chargeback. Returns released amount
+    /// and updated record of the transaction.
+    fn settle_tx_with_id(
+        &mut self,
+        tx_id: TransactionID,
+        amount: Option<Amount>,
+    ) -> Result<(Amount, TxRecord), EngineError> {
+        let mut record = self.disputable_record(tx_id)?;
+        let amount = settled_amount(self.client_id, tx_id, &record, amount)?;
+
+        if self.balance(record.currency).held < amount {
+            unreachable!("held amount is less then disputed amount");
+        }
+
+        record.disputed -= amount;
+        self.save_record(tx_id, record)?;
+        self.balance_mut(record.currency).held -= amount;
+
+        if !record.is_under_dispute() {
+            self.open_disputes.remove(&tx_id);
+        }
+
+        Ok((amount, record))
+    }
+
+    /// Records deposit or withdrawal in account's history
+    /// and applies it to the balance. Only id is kept of
+    /// transactions that can't be disputed.
+    fn record_tx(&mut self, tx: Transaction, at: Moment) -> Result<(), EngineError> {
+        let client_id = self.client_id;
+        let tx_id = tx.get_tx_id();
+
+        if self.tx_ids.contains(tx_id) {
+            return Err(EngineError::DuplicateTx { client_id, tx_id });
+        }
+
+        let (kind, tx_info) = match &tx {
+            Transaction::Deposit(tx_info) => (TxKind::Deposit, tx_info),
+            Transaction::Withdrawal(tx_info) => (TxKind::Withdrawal, tx_info),
+            _ => unreachable!("only deposits and withdrawals are recorded"),
+        };
+        let TransactionInfo { amount, currency, .. } = *tx_info;
+
+        let available = self.balance(currency).available;
+        if kind == TxKind::Withdrawal && amount > available {
+            return Err(EngineError::InsufficientFunds {
+                client_id,
+                tx_id,
+                needed: amount,
+                available,
+            });
+        }
+
+        if self.policy.is_disputable(&tx) {
+            let record = TxRecord::new(kind, amount, at).with_currency(currency);
+            self.save_record(tx_id, record)?;
+        }
+        self.tx_ids.insert(tx_id);
+
+        let balance = self.balance_mut(currency);
+        match kind {
+            TxKind::Deposit => balance.available += amount,
+            TxKind::Withdrawal => balance.available -= amount,
+        }
+        Ok(())
+    }
+
+    /// Checks that account's side of the `transfer` can be applied, so
+    /// that bank can check both sides before applying it to either.
+    pub fn check_transfer(&self, transfer: &TransferInfo) -> Result<(), EngineError> {
+        let client_id = self.client_id;
+        let tx_id = transfer.tx_id;
+
+        if self.locked {
+            return Err(EngineError::AccountLocked { client_id, tx_id });
+        }
+        if self.tx_ids.contains(tx_id) {
+            return Err(EngineError::DuplicateTx { client_id, tx_id });
+        }
+        let available = self.balance(transfer.currency).available;
+        if transfer.client_id == client_id && transfer.amount > available {
+            return Err(EngineError::InsufficientFunds {
+                client_id,
+                tx_id,
+                needed: transfer.amount,
+                available,
+            });
+        }
+        Ok(())
+    }
+
+    /// Applies account's side of the `transfer`, debiting
+    /// the source and crediting the destination.
+    fn transfer(&mut self, transfer: &TransferInfo) -> Result<(), EngineError> {
+        self.check_transfer(transfer)?;
+        self.tx_ids.insert(transfer.tx_id);
+
+        let is_source = transfer.client_id == self.client_id;
+        let balance = self.balance_mut(transfer.currency);
+        if is_source {
+            balance.available -= transfer.amount;
+        } else {
+            balance.available += transfer.amount;
+        }
+        Ok(())
+    }
+
+    /// Debits the fee. Below zero only if [Policy::negative_fees] allows it.
+    fn charge_fee(&mut self, tx_info: &TransactionInfo) -> Result<(), EngineError> {
+        let client_id = self.client_id;
+        let TransactionInfo { tx_id, amount, currency, .. } = *tx_info;
+
+        if self.tx_ids.contains(tx_id) {
+            return Err(EngineError::DuplicateTx { client_id, tx_id });
+        }
+        let available = self.balance(currency).available;
+        if !self.policy.negative_fees && amount > available {
+            return Err(EngineError::InsufficientFunds {
+                client_id,
+                tx_id,
+                needed: amount,
+                available,
+            });
+        }
+
+        self.tx_ids.insert(tx_id);
+        let balance = self.balance_mut(currency);
+        balance.available -= amount;
+        balance.fees += amount;
+        Ok(())
+    }
+
+    /// Credits or debits the correction and keeps it's reason in
+    /// the audit log. Debit can't take more than is `available`.
+    fn adjust(&mut self, adjustment: &Adjustment) -> Result<(), EngineError> {
+        let client_id = self.client_id;
+        let tx_id = adjustment.tx_id;
+
+        if self.tx_ids.contains(tx_id) {
+            return Err(EngineError::DuplicateTx { client_id, tx_id });
+        }
+        let available = self.balance(adjustment.currency).available;
+        if adjustment.amount.is_sign_negative() && -adjustment.amount > available {
+            return Err(EngineError::InsufficientFunds {
+                client_id,
+                tx_id,
+                needed: -adjustment.amount,
+                available,
+            });
+        }
+
+        self.tx_ids.insert(tx_id);
+        let balance = self.balance_mut(adjustment.currency);
+        balance.available += adjustment.amount;
+        balance.adjustments += adjustment.amount;
+        self.audit(tx_id, AuditAction::Adjustment, Some(adjustment.reason.clone()));
+        Ok(())
+    }
+
+    /// Debits `amount` in one currency and credits it in the other, at
+    /// the rate of [Policy::rates] effective `at`. Bank keeps the spread.
+    fn convert(&mut self, conversion: &Conversion, at: Moment) -> Result<(), EngineError> {
+        let client_id = self.client_id;
+        let Conversion { tx_id, amount, currency, to_currency, .. } = *conversion;
+
+        if self.tx_ids.contains(tx_id) {
+            return Err(EngineError::DuplicateTx { client_id, tx_id });
+        }
+        let quote = self.policy.rates.as_ref()
+            .and_then(|rates| rates.convert(currency, to_currency, amount, at.timestamp))
+            .ok_or(EngineError::RateNotFound {
+                client_id,
+                tx_id,
+                from: currency,
+                to: to_currency,
+            })?;
+        if quote.credited <= Amount::zero() {
+            return Err(EngineError::NothingCredited { client_id, tx_id });
+        }
+        let available = self.balance(currency).available;
+        if amount > available {
+            return Err(EngineError::InsufficientFunds {
+                client_id,
+                tx_id,
+                needed: amount,
+                available,
+            });
+        }
+
+        self.tx_ids.insert(tx_id);
+        self.balance_mut(currency).available -= amount;
+        self.balance_mut(to_currency).available += quote.credited;
+
+        let pair = self.conversions.iter_mut()
+            .find(|total| (total.from, total.to) == (currency, to_currency));
+        match pair {
+            Some(total) => {
+                total.debited += amount;
+                total.credited += quote.credited;
+                total.spread += quote.spread;
+            },
+            None => self.conversions.push(SpreadTotal {
+                from: currency,
+                to: to_currency,
+                debited: amount,
+                credited: quote.credited,
+                spread: quote.spread,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Moves amount of the authorization from `available` to `reserved`.
+    fn authorize(&mut self, tx_info: &TransactionInfo, at: Moment) -> Result<(), EngineError> {
+        let client_id = self.client_id;
+        let TransactionInfo { tx_id, amount, currency, .. } = *tx_info;
+
+        if self.tx_ids.contains(tx_id) {
+            return Err(EngineError::DuplicateTx { client_id, tx_id });
+        }
+        let available = self.balance(currency).available;
+        if amount > available {
+            return Err(EngineError::InsufficientFunds {
+                client_id,
+                tx_id,
+                needed: amount,
+                available,
+            });
+        }
+
+        self.tx_ids.insert(tx_id);
+        let balance = self.balance_mut(currency);
+        balance.available -= amount;
+        balance.reserved += amount;
+        self.authorizations.insert(tx_id, Authorization { reserved: amount, currency, at });
+        Ok(())
+    }
 
-                tx_info.under_dispute = false;
-                self.available += tx_info.amount;
-                self.held -= tx_info.amount;
+    /// Takes `amount` out of `reserved` of the authorization, common part
+    /// of capture and void. All that is left if `amount` is `None`.
+    /// Returns released amount and it's currency.
+    fn release_reserved(
+        &mut self,
+        tx_id: TransactionID,
+        amount: Option<Amount>,
+    ) -> Result<(Amount, Currency), EngineError> {
+        let client_id = self.client_id;
+        check_ref_amount(client_id, tx_id, amount)?;
+        let authorization = self.authorizations.get_mut(&tx_id)
+            .ok_or(EngineError::NotAuthorized { client_id, tx_id })?;
+
+        let amount = match amount {
+            Some(amount) if amount > authorization.reserved => {
+                return Err(EngineError::ExceedsReserved {
+                    client_id,
+                    tx_id,
+                    requested: amount,
+                    reserved: authorization.reserved,
+                });
             },
-            _ => return Err("only deposit transaction can be disputed"),
+            Some(amount) => amount,
+            None => authorization.reserved,
         };
 
+        authorization.reserved -= amount;
+        let currency = authorization.currency;
+        if authorization.reserved.is_zero() {
+            self.authorizations.remove(&tx_id);
+        }
+        self.balance_mut(currency).reserved -= amount;
+        Ok((amount, currency))
+    }
+
+    /// Settles reserved funds, they leave the account.
+    fn capture(&mut self, tx_id: TransactionID, amount: Option<Amount>) -> Result<(), EngineError> {
+        self.release_reserved(tx_id, amount)?;
         Ok(())
     }
 
-    /// Should be called cautiously outside `apply_tx`, since `apply_tx`
-    /// does bunch of checks before calling this method, which we don't
-    /// do here. Also transation won't be added to `Self::transactions`.
-    fn chargeback_tx_with_id(&mut self, tx_id: TransactionID) -> Result<(), &'static str> {
-        let tx = self.transactions.get_mut(&tx_id)
-            .ok_or("transaction for chargeback not found")?;
-
-        match tx {
-            Transaction::Deposit(tx_info) => {
-                if !tx_info.under_dispute {
-                    return Err("transaction is not under dispute");
-                }
+    /// Returns reserved funds to `available`.
+    fn void(&mut self, tx_id: TransactionID, amount: Option<Amount>) -> Result<(), EngineError> {
+        let (amount, currency) = self.release_reserved(tx_id, amount)?;
+        self.balance_mut(currency).available += amount;
+        Ok(())
+    }
 
-                if self.held < tx_info.amount {
-                    unreachable!("held amount is less then disputed amount");
-                }
+    /// Point in units of the [Policy::authorization_expiry] age,
+    /// after which the oldest of the authorizations expires.
+    pub fn next_release(&self) -> Option<u64> {
+        let age = self.policy.authorization_expiry?;
+        self.authorizations.values()
+            .filter_map(|authorization| age.deadline(authorization.at))
+            .min()
+    }
+
+    /// Voids authorizations, that stayed open past the deadline
+    /// of the [Policy::authorization_expiry] at `now`.
+    ///
+    /// Goes on with the rest, if one of them fails, and returns
+    /// errors of all that failed.
+    pub fn release_authorizations(&mut self, now: Moment) -> Result<(), Vec<EngineError>> {
+        let age = match self.policy.authorization_expiry {
+            Some(age) => age,
+            None => return Ok(()),
+        };
 
-                tx_info.under_dispute = false;
-                self.held -= tx_info.amount;
+        let mut expired: Vec<_> = self.authorizations.iter()
+            .filter(|(_, authorization)| age.is_exceeded(authorization.at, now))
+            .map(|(&tx_id, _)| tx_id)
+            .collect();
+        expired.sort_unstable();
+
+        let errors: Vec<_> = expired.into_iter()
+            .filter_map(|tx_id| self.void(tx_id, None).err())
+            .collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Point in units of the [Policy::dispute_expiry] age, after which
+    /// the oldest of the open disputes expires.
+    pub fn next_expiry(&self) -> Option<u64> {
+        let expiry = self.policy.dispute_expiry?;
+        self.open_disputes.values()
+            .filter_map(|&since| expiry.after.deadline(since))
+            .min()
+    }
+
+    /// Settles disputes, that stayed open past the deadline of
+    /// the [Policy::dispute_expiry] at `now`, and reports them
+    /// in [Account::expirations].
+    ///
+    /// Dispute that fails to settle doesn't hold back the others,
+    /// errors of all of them are returned. Dispute stays open only
+    /// if it's record couldn't be stored, so it's retried later.
+    pub fn expire_disputes(&mut self, now: Moment) -> Result<(), Vec<EngineError>> {
+        let expiry = match self.policy.dispute_expiry {
+            Some(expiry) => expiry,
+            None => return Ok(()),
+        };
+
+        let mut expired: Vec<_> = self.open_disputes.iter()
+            .filter(|(_, &since)| expiry.after.is_exceeded(since, now))
+            .filter_map(|(&tx_id, &since)| Some((expiry.after.deadline(since)?, tx_id)))
+            .collect();
+        expired.sort_unstable();
+
+        let errors: Vec<_> = expired.into_iter()
+            .filter_map(|(deadline, tx_id)| self.expire_dispute(tx_id, expiry.action, deadline).err())
+            .collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    fn expire_dispute(
+        &mut self,
+        tx_id: TransactionID,
+        action: ExpiryAction,
+        deadline: u64,
+    ) -> Result<(), EngineError> {
+        let record = match self.disputable_record(tx_id) {
+            Ok(record) if record.is_under_dispute() => record,
+            // already settled, nothing left to expire.
+            Ok(_) => {
+                self.open_disputes.remove(&tx_id);
+                return Ok(());
+            },
+            Err(err) => {
+                if !matches!(err, EngineError::Storage { .. }) {
+                    self.open_disputes.remove(&tx_id);
+                }
+                return Err(err);
             },
-            _ => return Err("only deposit transaction can be disputed"),
         };
 
-        // should lock account if chargeback occured.
-        self.locked = true;
+        match action {
+            ExpiryAction::Resolve => self.resolve_tx_with_id(tx_id, None)?,
+            ExpiryAction::Chargeback => self.chargeback_tx_with_id(tx_id, None)?,
+        }
+        self.expirations.push(Expiration {
+            client_id: self.client_id,
+            tx_id,
+            action,
+            amount: record.disputed,
+            currency: record.currency,
+            deadline,
+        });
         Ok(())
     }
 
-    // TODO: create errors enum.
     /// Apply transaction to the account.
-    pub fn apply_tx(&mut self, tx: Transaction) -> Result<(), &'static str> {
-        if self.locked {
-            return Err("can't apply transaction to a locked account");
-        }
+    pub fn apply_tx(&mut self, tx: Transaction) -> Result<(), EngineError> {
+        self.apply_tx_at(tx, Moment::default())
+    }
 
-        if !tx.is_ref() && self.transactions.contains_key(&tx.get_tx_id()) {
-            return Err("transaction with same id already applied");
+    /// Apply transaction, that happened `at`, to the account.
+    pub fn apply_tx_at(&mut self, tx: Transaction, at: Moment) -> Result<(), EngineError> {
+        let client_id = self.client_id;
+        let tx_id = tx.get_tx_id();
+
+        if self.locked && !matches!(tx, Transaction::Unlock(_)) {
+            return Err(EngineError::AccountLocked { client_id, tx_id });
         }
 
         match &tx {
-            Transaction::Deposit(tx_info) => self.available += tx_info.amount,
-            Transaction::Withdrawal(tx_info) => {
-                if tx_info.amount > self.available {
-                    return Err("insufficient funds");
-                }
-                self.available -= tx_info.amount;
+            Transaction::Deposit(_) | Transaction::Withdrawal(_) => {
+                self.record_tx(tx, at)?;
             },
             Transaction::Dispute(tx_ref) => {
-                self.dispute_tx_with_id(tx_ref.tx_id)?;
+                self.dispute_tx_with_id(tx_ref.tx_id, tx_ref.amount, at)?;
             },
             Transaction::Resolve(tx_ref) => {
-                self.resolve_tx_with_id(tx_ref.tx_id)?;
+                self.resolve_tx_with_id(tx_ref.tx_id, tx_ref.amount)?;
             },
             Transaction::ChargeBack(tx_ref) => {
-                self.chargeback_tx_with_id(tx_ref.tx_id)?;
-            }
+                self.chargeback_tx_with_id(tx_ref.tx_id, tx_ref.amount)?;
+            },
+            Transaction::Lock(action) => {
+                self.locked = true;
+                self.audit(tx_id, AuditAction::Lock, action.reason.clone());
+            },
+            Transaction::Unlock(action) => {
+                if !self.locked {
+                    return Err(EngineError::AccountNotLocked { client_id, tx_id });
+                }
+                self.locked = false;
+                self.audit(tx_id, AuditAction::Unlock, action.reason.clone());
+            },
+            Transaction::Transfer(transfer) => {
+                self.transfer(transfer)?;
+            },
+            Transaction::Fee(tx_info) => {
+                self.charge_fee(tx_info)?;
+            },
+            Transaction::Adjustment(adjustment) => {
+                self.adjust(adjustment)?;
+            },
+            Transaction::Authorize(tx_info) => {
+                self.authorize(tx_info, at)?;
+            },
+            Transaction::Capture(tx_ref) => {
+                self.capture(tx_ref.tx_id, tx_ref.amount)?;
+            },
+            Transaction::Void(tx_ref) => {
+                self.void(tx_ref.tx_id, tx_ref.amount)?;
+            },
+            Transaction::Convert(conversion) => {
+                self.convert(conversion, at)?;
+            },
         };
 
-        if !tx.is_ref() {
-            self.transactions.insert(tx.get_tx_id(), tx);
-        }
         Ok(())
     }
 }
 
-impl Into<OutputAccount> for Account {
-    fn into(self) -> OutputAccount {
+/// Amount of the disputed transaction that resolve or chargeback
+/// settles. All of disputed amount if `amount` is `None`.
+fn settled_amount(
+    client_id: ClientID,
+    tx_id: TransactionID,
+    record: &TxRecord,
+    amount: Option<Amount>,
+) -> Result<Amount, EngineError> {
+    let disputed = record.disputed;
+    check_ref_amount(client_id, tx_id, amount)?;
+
+    if !record.is_under_dispute() {
+        return Err(EngineError::NotUnderDispute { client_id, tx_id });
+    }
+
+    match amount {
+        Some(amount) if amount > disputed => {
+            Err(EngineError::ExceedsDisputed {
+                client_id,
+                tx_id,
+                requested: amount,
+                disputed,
+            })
+        },
+        Some(amount) => Ok(amount),
+        None => Ok(disputed),
+    }
+}
+
+/// Row of the account's balance in the default currency.
+impl From<&Account> for OutputAccount {
+    fn from(account: &Account) -> Self {
+        Self::new_with_balance(account, Currency::default(), &account.balance(Currency::default()))
+    }
+}
+
+impl OutputAccount {
+    /// Row of the account's `balance` in `currency`.
+    fn new_with_balance(account: &Account, currency: Currency, balance: &Balance) -> Self {
         OutputAccount {
-            client_id: self.client_id,
-            available: self.available,
-            held: self.held,
-            total: self.total(),
-            locked: self.locked,
+            client_id: account.client_id,
+            currency: Some(currency).filter(|currency| !currency.is_default()),
+            available: balance.available,
+            held: balance.held,
+            total: balance.total(),
+            locked: account.locked,
+            reserved: None,
+            fees: None,
+            adjustments: None,
         }
     }
+
+    /// Rows of the account, one per currency ordered by it. Single
+    /// row of zero balance, if account had no transactions in any.
+    /// With the extended `breakdown` of reserved funds, fees and
+    /// manual corrections if it's set.
+    pub fn rows(account: &Account, breakdown: bool) -> Vec<Self> {
+        let mut balances: Vec<_> = account.balances.iter()
+            .map(|(&currency, &balance)| (currency, balance))
+            .collect();
+        if balances.is_empty() {
+            balances.push((Currency::default(), Balance::default()));
+        }
+
+        balances.into_iter()
+            .map(|(currency, balance)| {
+                let row = Self::new_with_balance(account, currency, &balance);
+                if !breakdown {
+                    return row;
+                }
+                Self {
+                    reserved: Some(balance.reserved),
+                    fees: Some(balance.fees),
+                    adjustments: Some(balance.adjustments),
+                    ..row
+                }
+            })
+            .collect()
+    }
+}
+
+impl From<Account> for OutputAccount {
+    fn from(account: Account) -> Self {
+        Self::from(&account)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::str::FromStr;
-    use rust_decimal::prelude::*;
-    use crate::transaction::{TransactionInfo, TransactionRef};
+    use crate::transaction::{TransactionInfo, TransactionRef, AccountAction};
+    use crate::transaction::TransferInfo;
+    use crate::expiry::{Age, DisputeExpiry};
+    use crate::rates::RateTable;
+    use std::sync::Arc;
 
     fn dec(val: &str) -> Amount {
         Amount::from_str(val).unwrap()
@@ -189,21 +947,21 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
-        assert_eq!(acc.available, dec("1.05"));
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), dec("1.05"));
+        assert_eq!(acc.held(), zero());
 
         assert!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo {
             client_id: 1,
             tx_id: 2,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), zero());
     }
 
     #[test]
@@ -214,11 +972,11 @@ mod tests {
             client_id: 1,
             tx_id: 2,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_err());
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), zero());
     }
 
 
@@ -230,18 +988,23 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
-        assert!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo {
+        assert_eq!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo {
             client_id: 1,
             tx_id: 2,
             amount: dec("1.06"),
-            under_dispute: false,
-        })).is_err());
+            currency: Default::default(),
+        })), Err(EngineError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 2,
+            needed: dec("1.06"),
+            available: dec("1.05"),
+        }));
 
-        assert_eq!(acc.available, dec("1.05"));
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), dec("1.05"));
+        assert_eq!(acc.held(), zero());
     }
 
     #[test]
@@ -252,26 +1015,27 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
         assert!(acc.apply_tx(Transaction::Dispute(TransactionRef {
             client_id: 1,
             tx_id: 1,
+            amount: None,
         })).is_ok());
 
         assert!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo {
             client_id: 1,
             tx_id: 2,
             amount: dec("1.04"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_err());
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, dec("1.05"));
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), dec("1.05"));
     }
 
-    /// for now only deposit disputes are allowed, it should error if
+    /// by default only deposit disputes are allowed, it should error if
     /// we try to dispute "withdrawal" transaction.
     #[test]
     fn dispute_withdrawal() {
@@ -281,26 +1045,27 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
         assert!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo {
             client_id: 1,
             tx_id: 2,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), zero());
 
-        assert!(acc.apply_tx(Transaction::Dispute(TransactionRef {
+        assert_eq!(acc.apply_tx(Transaction::Dispute(TransactionRef {
             client_id: 1,
             tx_id: 2,
-        })).is_err());
+            amount: None,
+        })), Err(EngineError::NotDisputable { client_id: 1, tx_id: 2 }));
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), zero());
     }
 
     #[test]
@@ -311,24 +1076,26 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
         assert!(acc.apply_tx(Transaction::Dispute(TransactionRef {
             client_id: 1,
             tx_id: 1,
+            amount: None,
         })).is_ok());
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, dec("1.05"));
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), dec("1.05"));
 
         assert!(acc.apply_tx(Transaction::Resolve(TransactionRef {
             client_id: 1,
             tx_id: 1,
+            amount: None,
         })).is_ok());
 
-        assert_eq!(acc.available, dec("1.05"));
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), dec("1.05"));
+        assert_eq!(acc.held(), zero());
     }
 
     #[test]
@@ -339,24 +1106,26 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
         assert!(acc.apply_tx(Transaction::Dispute(TransactionRef {
             client_id: 1,
             tx_id: 1,
+            amount: None,
         })).is_ok());
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, dec("1.05"));
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), dec("1.05"));
 
         assert!(acc.apply_tx(Transaction::ChargeBack(TransactionRef {
             client_id: 1,
             tx_id: 1,
+            amount: None,
         })).is_ok());
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), zero());
         assert!(acc.locked);
     }
 
@@ -364,13 +1133,14 @@ mod tests {
     fn resolve_not_existant_tx() {
         let mut acc = Account::new(1);
 
-        assert!(acc.apply_tx(Transaction::Resolve(TransactionRef {
+        assert_eq!(acc.apply_tx(Transaction::Resolve(TransactionRef {
             client_id: 1,
             tx_id: 1,
-        })).is_err());
+            amount: None,
+        })), Err(EngineError::TxNotFound { client_id: 1, tx_id: 1 }));
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), zero());
     }
 
     #[test]
@@ -381,16 +1151,17 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
-        assert!(acc.apply_tx(Transaction::Resolve(TransactionRef {
+        assert_eq!(acc.apply_tx(Transaction::Resolve(TransactionRef {
             client_id: 1,
             tx_id: 1,
-        })).is_err());
+            amount: None,
+        })), Err(EngineError::NotUnderDispute { client_id: 1, tx_id: 1 }));
 
-        assert_eq!(acc.available, dec("1.05"));
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), dec("1.05"));
+        assert_eq!(acc.held(), zero());
     }
 
     #[test]
@@ -400,10 +1171,11 @@ mod tests {
         assert!(acc.apply_tx(Transaction::ChargeBack(TransactionRef {
             client_id: 1,
             tx_id: 1,
+            amount: None,
         })).is_err());
 
-        assert_eq!(acc.available, zero());
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), zero());
         assert!(!acc.locked);
     }
 
@@ -415,17 +1187,691 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: dec("1.05"),
-            under_dispute: false,
+            currency: Default::default(),
         })).is_ok());
 
         assert!(acc.apply_tx(Transaction::ChargeBack(TransactionRef {
             client_id: 1,
             tx_id: 1,
+            amount: None,
         })).is_err());
 
 
-        assert_eq!(acc.available, dec("1.05"));
-        assert_eq!(acc.held, zero());
+        assert_eq!(acc.available(), dec("1.05"));
+        assert_eq!(acc.held(), zero());
+        assert!(!acc.locked);
+    }
+
+    #[test]
+    fn apply_to_locked_account() {
+        let mut acc = Account::new(1);
+        acc.locked = true;
+
+        assert_eq!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("1.05"),
+            currency: Default::default(),
+        })), Err(EngineError::AccountLocked { client_id: 1, tx_id: 1 }));
+
+        assert_eq!(acc.available(), zero());
+    }
+
+    #[test]
+    fn deposit_duplicate() {
+        let mut acc = Account::new(1);
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("1.05"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert_eq!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("1.05"),
+            currency: Default::default(),
+        })), Err(EngineError::DuplicateTx { client_id: 1, tx_id: 1 }));
+
+        assert_eq!(acc.available(), dec("1.05"));
+    }
+
+    #[test]
+    fn only_disputable_kept() {
+        let mut acc = Account::new(1);
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo::new(1, 1, dec("2")))).is_ok());
+        assert!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo::new(1, 2, dec("1")))).is_ok());
+
+        assert_eq!(acc.transactions().unwrap(), vec![
+            (1, TxRecord::new(TxKind::Deposit, dec("2"), Moment::default())),
+        ]);
+        assert_eq!(
+            acc.apply_tx(Transaction::Withdrawal(TransactionInfo::new(1, 2, dec("1")))),
+            Err(EngineError::DuplicateTx { client_id: 1, tx_id: 2 }),
+        );
+        assert_eq!(
+            acc.apply_tx(dispute(2, None)),
+            Err(EngineError::NotDisputable { client_id: 1, tx_id: 2 }),
+        );
+        assert_eq!(
+            acc.apply_tx(dispute(3, None)),
+            Err(EngineError::TxNotFound { client_id: 1, tx_id: 3 }),
+        );
+    }
+
+    fn at(seq: u64) -> Moment {
+        Moment { seq, timestamp: None }
+    }
+
+    #[test]
+    fn dispute_window_and_expiry() {
+        let policy = Policy {
+            dispute_window: Some(Age::Transactions(3)),
+            dispute_expiry: Some(DisputeExpiry {
+                after: Age::Transactions(2),
+                action: ExpiryAction::Resolve,
+            }),
+            ..Policy::default()
+        };
+        let mut acc = Account::new_with_policy(1, policy);
+
+        let deposit = |tx_id| Transaction::Deposit(TransactionInfo::new(1, tx_id, dec("2")));
+        assert!(acc.apply_tx_at(deposit(1), at(1)).is_ok());
+        assert!(acc.apply_tx_at(deposit(2), at(2)).is_ok());
+        assert_eq!(
+            acc.apply_tx_at(dispute(1, None), at(5)),
+            Err(EngineError::DisputeWindowExpired { client_id: 1, tx_id: 1 }),
+        );
+        assert!(acc.apply_tx_at(dispute(2, Some("1.5")), at(5)).is_ok());
+        assert_eq!(acc.next_expiry(), Some(7));
+
+        // still in time.
+        acc.expire_disputes(at(7)).unwrap();
+        assert_eq!(acc.held(), dec("1.5"));
+
+        acc.expire_disputes(at(8)).unwrap();
+        assert_eq!(acc.held(), zero());
+        assert_eq!(acc.available(), dec("4"));
+        assert_eq!(acc.next_expiry(), None);
+        assert_eq!(acc.expirations(), &[Expiration {
+            client_id: 1,
+            tx_id: 2,
+            action: ExpiryAction::Resolve,
+            amount: dec("1.5"),
+            currency: Currency::default(),
+            deadline: 7,
+        }]);
+    }
+
+    #[test]
+    fn fees() {
+        let mut acc = Account::new(1);
+        let fee = |tx_id, amount| Transaction::Fee(TransactionInfo::new(1, tx_id, dec(amount)));
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo::new(1, 1, dec("1")))).is_ok());
+        assert!(acc.apply_tx(fee(2, "0.25")).is_ok());
+        assert_eq!(acc.apply_tx(fee(3, "1")), Err(EngineError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 3,
+            needed: dec("1"),
+            available: dec("0.75"),
+        }));
+
+        acc.set_policy(Policy { negative_fees: true, ..Policy::default() });
+        assert!(acc.apply_tx(fee(3, "1")).is_ok());
+        assert_eq!(acc.available(), dec("-0.25"));
+        assert_eq!(acc.fees(), dec("1.25"));
+        assert_eq!(
+            acc.apply_tx(dispute(2, None)),
+            Err(EngineError::NotDisputable { client_id: 1, tx_id: 2 }),
+        );
+    }
+
+    #[test]
+    fn currencies() {
+        let mut acc = Account::new(1);
+        let usd: Currency = "USD".parse().unwrap();
+        let eur: Currency = "EUR".parse().unwrap();
+        let info = |tx_id, amount, currency| {
+            TransactionInfo::new(1, tx_id, dec(amount)).with_currency(currency)
+        };
+
+        assert!(acc.apply_tx(Transaction::Deposit(info(1, "10", usd))).is_ok());
+        assert!(acc.apply_tx(Transaction::Deposit(info(2, "5", eur))).is_ok());
+        // funds in one currency can't pay for the other.
+        assert_eq!(acc.apply_tx(Transaction::Withdrawal(info(3, "6", eur))), Err(
+            EngineError::InsufficientFunds {
+                client_id: 1,
+                tx_id: 3,
+                needed: dec("6"),
+                available: dec("5"),
+            },
+        ));
+        assert_eq!(
+            acc.apply_tx(Transaction::Withdrawal(info(4, "1", Currency::default()))),
+            Err(EngineError::InsufficientFunds {
+                client_id: 1,
+                tx_id: 4,
+                needed: dec("1"),
+                available: zero(),
+            }),
+        );
+        assert!(acc.apply_tx(Transaction::Withdrawal(info(5, "4", eur))).is_ok());
+
+        // dispute holds funds in the currency of the disputed transaction.
+        let tx_ref = TransactionRef { client_id: 1, tx_id: 1, amount: None };
+        assert!(acc.apply_tx(Transaction::Dispute(tx_ref.clone())).is_ok());
+        assert_eq!(acc.balance(usd).held, dec("10"));
+        assert_eq!(acc.balance(usd).available, zero());
+        assert_eq!(acc.balance(eur).available, dec("1"));
+        assert!(acc.apply_tx(Transaction::Resolve(tx_ref)).is_ok());
+        assert_eq!(acc.balance(usd).available, dec("10"));
+
+        // refused transactions don't leave balances behind.
+        assert_eq!(acc.balances().keys().copied().collect::<Vec<_>>(), vec![eur, usd]);
+        assert_eq!(acc.total(), zero());
+
+        let rows = OutputAccount::rows(&acc, false);
+        assert_eq!(
+            rows.iter().map(|row| (row.currency, row.total)).collect::<Vec<_>>(),
+            vec![(Some(eur), dec("1")), (Some(usd), dec("10"))],
+        );
+        let rows = OutputAccount::rows(&Account::new(2), false);
+        assert_eq!((rows.len(), rows[0].currency, rows[0].total), (1, None, zero()));
+    }
+
+    #[test]
+    fn conversions() {
+        let rates = RateTable::from_csv("\
+from,to,rate,spread,effective_from
+USD,EUR,0.9,0.01,100
+USD,EUR,0.8,0,200
+".as_bytes()).unwrap();
+        let policy = Policy { rates: Some(Arc::new(rates)), ..Policy::default() };
+        let mut acc = Account::new_with_policy(1, policy);
+        let usd: Currency = "USD".parse().unwrap();
+        let eur: Currency = "EUR".parse().unwrap();
+        let convert = |tx_id, amount, from, to| {
+            Transaction::Convert(Conversion::new(1, tx_id, dec(amount), from, to))
+        };
+        let at = |timestamp| Moment { seq: 0, timestamp: Some(timestamp) };
+
+        let deposit = TransactionInfo::new(1, 1, dec("10")).with_currency(usd);
+        assert!(acc.apply_tx(Transaction::Deposit(deposit)).is_ok());
+        assert_eq!(acc.apply_tx_at(convert(2, "1", usd, eur), at(99)), Err(
+            EngineError::RateNotFound { client_id: 1, tx_id: 2, from: usd, to: eur },
+        ));
+        assert_eq!(acc.apply_tx_at(convert(3, "1", eur, usd), at(150)), Err(
+            EngineError::RateNotFound { client_id: 1, tx_id: 3, from: eur, to: usd },
+        ));
+        assert_eq!(acc.apply_tx_at(convert(4, "11", usd, eur), at(150)), Err(
+            EngineError::InsufficientFunds {
+                client_id: 1,
+                tx_id: 4,
+                needed: dec("11"),
+                available: dec("10"),
+            },
+        ));
+        assert_eq!(acc.balances().len(), 1);
+        // 0.00009 * 0.9 is rounded down to zero.
+        assert_eq!(acc.apply_tx_at(convert(7, "0.00009", usd, eur), at(150)), Err(
+            EngineError::NothingCredited { client_id: 1, tx_id: 7 },
+        ));
+        assert_eq!(acc.balance(usd).available, dec("10"));
+
+        // 1.23456 * 0.9 = 1.111104, 1% of it is kept and the rest rounded down.
+        assert!(acc.apply_tx_at(convert(5, "1.23456", usd, eur), at(150)).is_ok());
+        assert!(acc.apply_tx_at(convert(6, "1", usd, eur), at(200)).is_ok());
+        assert_eq!(acc.balance(usd).available, dec("7.76544"));
+        assert_eq!(acc.balance(eur).available, dec("1.8999"));
+        assert_eq!(acc.conversions(), &[SpreadTotal {
+            from: usd,
+            to: eur,
+            debited: dec("2.23456"),
+            credited: dec("1.8999"),
+            spread: dec("0.011204"),
+        }]);
+    }
+
+    #[test]
+    fn adjustments() {
+        let mut acc = Account::new(1);
+        let adjustment = |tx_id, amount| {
+            Transaction::Adjustment(Adjustment::new(1, tx_id, dec(amount), "correction".to_owned()))
+        };
+
+        assert!(acc.apply_tx(adjustment(1, "2")).is_ok());
+        assert!(acc.apply_tx(adjustment(2, "-0.5")).is_ok());
+        assert_eq!(acc.apply_tx(adjustment(3, "-2")), Err(EngineError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 3,
+            needed: dec("2"),
+            available: dec("1.5"),
+        }));
+        assert_eq!(acc.available(), dec("1.5"));
+        assert_eq!(acc.adjustments(), dec("1.5"));
+
+        let output = &OutputAccount::rows(&acc, true)[0];
+        assert_eq!((output.fees, output.adjustments), (Some(zero()), Some(dec("1.5"))));
+        assert_eq!(acc.audit_log().len(), 2);
+        assert_eq!(acc.audit_log()[1].action, AuditAction::Adjustment);
+        assert_eq!(acc.audit_log()[1].reason.as_deref(), Some("correction"));
+    }
+
+    #[test]
+    fn authorize_capture_void() {
+        let policy = Policy {
+            authorization_expiry: Some(Age::Transactions(2)),
+            ..Policy::default()
+        };
+        let mut acc = Account::new_with_policy(1, policy);
+        let info = |tx_id, amount| TransactionInfo::new(1, tx_id, dec(amount));
+        let tx_ref = |tx_id, amount: Option<&str>| {
+            TransactionRef { client_id: 1, tx_id, amount: amount.map(dec) }
+        };
+
+        assert!(acc.apply_tx_at(Transaction::Deposit(info(1, "5")), at(1)).is_ok());
+        assert!(acc.apply_tx_at(Transaction::Authorize(info(2, "4")), at(2)).is_ok());
+        assert_eq!((acc.available(), acc.reserved(), acc.total()), (dec("1"), dec("4"), dec("5")));
+
+        // reserved funds can't be withdrawn.
+        assert_eq!(
+            acc.apply_tx_at(Transaction::Withdrawal(info(3, "2")), at(3)),
+            Err(EngineError::InsufficientFunds {
+                client_id: 1,
+                tx_id: 3,
+                needed: dec("2"),
+                available: dec("1"),
+            }),
+        );
+        assert!(acc.apply_tx_at(Transaction::Capture(tx_ref(2, Some("1.5"))), at(3)).is_ok());
+        assert_eq!(
+            acc.apply_tx_at(Transaction::Void(tx_ref(2, Some("3"))), at(3)),
+            Err(EngineError::ExceedsReserved {
+                client_id: 1,
+                tx_id: 2,
+                requested: dec("3"),
+                reserved: dec("2.5"),
+            }),
+        );
+        assert!(acc.apply_tx_at(Transaction::Void(tx_ref(2, None)), at(3)).is_ok());
+        assert_eq!((acc.available(), acc.reserved(), acc.total()), (dec("3.5"), zero(), dec("3.5")));
+        assert_eq!(
+            acc.apply_tx_at(Transaction::Capture(tx_ref(2, None)), at(3)),
+            Err(EngineError::NotAuthorized { client_id: 1, tx_id: 2 }),
+        );
+        assert_eq!(
+            acc.apply_tx_at(dispute(2, None), at(3)),
+            Err(EngineError::NotDisputable { client_id: 1, tx_id: 2 }),
+        );
+
+        // expires after two more transactions.
+        assert!(acc.apply_tx_at(Transaction::Authorize(info(4, "1")), at(4)).is_ok());
+        assert_eq!(acc.next_release(), Some(6));
+        acc.release_authorizations(at(6)).unwrap();
+        assert_eq!(acc.reserved(), dec("1"));
+        acc.release_authorizations(at(7)).unwrap();
+        assert_eq!((acc.available(), acc.reserved()), (dec("3.5"), zero()));
+        assert_eq!(acc.next_release(), None);
+    }
+
+    #[test]
+    fn transfer_sides() {
+        let mut source = Account::new(1);
+        let mut destination = Account::new(2);
+        let transfer = |tx_id, amount| {
+            Transaction::Transfer(TransferInfo::new(1, 2, tx_id, dec(amount)))
+        };
+
+        assert!(source.apply_tx(Transaction::Deposit(TransactionInfo::new(1, 1, dec("3")))).is_ok());
+        assert!(source.apply_tx(transfer(2, "2")).is_ok());
+        assert!(destination.apply_tx(transfer(2, "2")).is_ok());
+        assert_eq!(source.available(), dec("1"));
+        assert_eq!(destination.available(), dec("2"));
+
+        assert_eq!(source.apply_tx(transfer(3, "2")), Err(EngineError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 3,
+            needed: dec("2"),
+            available: dec("1"),
+        }));
+        // destination doesn't need funds.
+        assert!(destination.apply_tx(transfer(3, "2")).is_ok());
+        assert_eq!(
+            destination.apply_tx(transfer(2, "1")),
+            Err(EngineError::DuplicateTx { client_id: 2, tx_id: 2 }),
+        );
+        // transfers can't be disputed.
+        assert_eq!(
+            destination.apply_tx(dispute(2, None)),
+            Err(EngineError::NotDisputable { client_id: 2, tx_id: 2 }),
+        );
+    }
+
+    fn allow_withdrawal_disputes() -> Policy {
+        Policy { withdrawal_disputes: true, ..Policy::default() }
+    }
+
+    #[test]
+    fn dispute_withdrawal_resolve() {
+        let mut acc = Account::new_with_policy(1, allow_withdrawal_disputes());
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("1.05"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo {
+            client_id: 1,
+            tx_id: 2,
+            amount: dec("1.00"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert!(acc.apply_tx(Transaction::Dispute(TransactionRef {
+            client_id: 1,
+            tx_id: 2,
+            amount: None,
+        })).is_ok());
+
+        assert_eq!(acc.available(), dec("0.05"));
+        assert_eq!(acc.held(), dec("1.00"));
+
+        assert!(acc.apply_tx(Transaction::Resolve(TransactionRef {
+            client_id: 1,
+            tx_id: 2,
+            amount: None,
+        })).is_ok());
+
+        assert_eq!(acc.available(), dec("0.05"));
+        assert_eq!(acc.held(), zero());
+        assert!(!acc.locked);
+    }
+
+    #[test]
+    fn dispute_withdrawal_chargeback() {
+        let mut acc = Account::new_with_policy(1, allow_withdrawal_disputes());
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("1.05"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo {
+            client_id: 1,
+            tx_id: 2,
+            amount: dec("1.00"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert!(acc.apply_tx(Transaction::Dispute(TransactionRef {
+            client_id: 1,
+            tx_id: 2,
+            amount: None,
+        })).is_ok());
+
+        assert_eq!(acc.available(), dec("0.05"));
+        assert_eq!(acc.held(), dec("1.00"));
+
+        assert!(acc.apply_tx(Transaction::ChargeBack(TransactionRef {
+            client_id: 1,
+            tx_id: 2,
+            amount: None,
+        })).is_ok());
+
+        assert_eq!(acc.available(), dec("1.05"));
+        assert_eq!(acc.held(), zero());
+        assert!(acc.locked);
+    }
+
+    #[test]
+    fn dispute_withdrawal_twice() {
+        let mut acc = Account::new_with_policy(1, allow_withdrawal_disputes());
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("1.05"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert!(acc.apply_tx(Transaction::Withdrawal(TransactionInfo {
+            client_id: 1,
+            tx_id: 2,
+            amount: dec("1.00"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert!(acc.apply_tx(Transaction::Dispute(TransactionRef {
+            client_id: 1,
+            tx_id: 2,
+            amount: None,
+        })).is_ok());
+
+        assert_eq!(acc.apply_tx(Transaction::Dispute(TransactionRef {
+            client_id: 1,
+            tx_id: 2,
+            amount: None,
+        })), Err(EngineError::AlreadyUnderDispute { client_id: 1, tx_id: 2 }));
+
+        assert_eq!(acc.held(), dec("1.00"));
+    }
+
+    fn dispute(tx_id: TransactionID, amount: Option<&str>) -> Transaction {
+        Transaction::Dispute(TransactionRef {
+            client_id: 1,
+            tx_id,
+            amount: amount.map(dec),
+        })
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let mut acc = Account::new(1);
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("10"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert!(acc.apply_tx(dispute(1, Some("3"))).is_ok());
+        assert!(acc.apply_tx(dispute(1, Some("4"))).is_ok());
+
+        assert_eq!(acc.available(), dec("3"));
+        assert_eq!(acc.held(), dec("7"));
+
+        assert_eq!(acc.apply_tx(dispute(1, Some("4"))), Err(EngineError::DisputeExceedsTx {
+            client_id: 1,
+            tx_id: 1,
+            requested: dec("4"),
+            undisputed: dec("3"),
+        }));
+
+        // without amount, disputes what's left undisputed.
+        assert!(acc.apply_tx(dispute(1, None)).is_ok());
+
+        assert_eq!(acc.available(), zero());
+        assert_eq!(acc.held(), dec("10"));
+
+        assert_eq!(
+            acc.apply_tx(dispute(1, None)),
+            Err(EngineError::AlreadyUnderDispute { client_id: 1, tx_id: 1 }),
+        );
+    }
+
+    #[test]
+    fn expiry_goes_on_past_failures() {
+        let policy = Policy {
+            dispute_expiry: Some(DisputeExpiry {
+                after: Age::Transactions(2),
+                action: ExpiryAction::Chargeback,
+            }),
+            ..Policy::default()
+        };
+        let mut acc = Account::new_with_policy(1, policy);
+
+        let deposit = |tx_id| Transaction::Deposit(TransactionInfo::new(1, tx_id, dec("2")));
+        assert!(acc.apply_tx_at(deposit(1), at(1)).is_ok());
+        assert!(acc.apply_tx_at(deposit(2), at(2)).is_ok());
+        assert!(acc.apply_tx_at(dispute(2, None), at(3)).is_ok());
+        // entries, that can't be settled, expire before it.
+        acc.open_disputes.insert(1, at(1));
+        acc.open_disputes.insert(9, at(1));
+
+        assert_eq!(
+            acc.expire_disputes(at(6)),
+            Err(vec![EngineError::TxNotFound { client_id: 1, tx_id: 9 }]),
+        );
+        assert_eq!(acc.held(), zero());
+        assert_eq!(acc.available(), dec("2"));
+        assert!(acc.is_locked());
+        assert_eq!(acc.expirations().len(), 1);
+        assert_eq!(acc.expirations()[0].tx_id, 2);
+        // none of them is left to fail again.
+        assert_eq!(acc.next_expiry(), None);
+        assert_eq!(acc.expire_disputes(at(7)), Ok(()));
+    }
+
+    #[test]
+    fn zero_amount_dispute() {
+        let policy = Policy {
+            dispute_expiry: Some(DisputeExpiry {
+                after: Age::Transactions(2),
+                action: ExpiryAction::Resolve,
+            }),
+            ..Policy::default()
+        };
+        let mut acc = Account::new_with_policy(1, policy);
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo::new(1, 1, dec("10")))).is_ok());
+        assert_eq!(
+            acc.apply_tx(dispute(1, Some("0"))),
+            Err(EngineError::ZeroAmount { client_id: 1, tx_id: 1 }),
+        );
+        // nothing is left open by it.
+        assert_eq!(acc.next_expiry(), None);
+        assert_eq!(acc.held(), zero());
+    }
+
+    #[test]
+    fn partial_resolve_chargeback() {
+        let mut acc = Account::new(1);
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("10"),
+            currency: Default::default(),
+        })).is_ok());
+
+        assert!(acc.apply_tx(dispute(1, Some("6"))).is_ok());
+
+        assert!(acc.apply_tx(Transaction::Resolve(TransactionRef {
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(dec("2")),
+        })).is_ok());
+
+        assert_eq!(acc.available(), dec("6"));
+        assert_eq!(acc.held(), dec("4"));
+
+        assert_eq!(acc.apply_tx(Transaction::ChargeBack(TransactionRef {
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(dec("5")),
+        })), Err(EngineError::ExceedsDisputed {
+            client_id: 1,
+            tx_id: 1,
+            requested: dec("5"),
+            disputed: dec("4"),
+        }));
+
+        assert!(acc.apply_tx(Transaction::ChargeBack(TransactionRef {
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+        })).is_ok());
+
+        assert_eq!(acc.available(), dec("6"));
+        assert_eq!(acc.held(), zero());
+        assert!(acc.locked);
+    }
+
+    #[test]
+    fn unlock_after_chargeback() {
+        let mut acc = Account::new(1);
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: dec("1.05"),
+            currency: Default::default(),
+        })).is_ok());
+        assert!(acc.apply_tx(dispute(1, None)).is_ok());
+        assert!(acc.apply_tx(Transaction::ChargeBack(TransactionRef {
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+        })).is_ok());
+        assert!(acc.locked);
+
+        assert!(acc.apply_tx(Transaction::Unlock(AccountAction {
+            client_id: 1,
+            tx_id: 2,
+            reason: Some("reinstated".to_owned()),
+        })).is_ok());
         assert!(!acc.locked);
+
+        assert!(acc.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 3,
+            amount: dec("1.00"),
+            currency: Default::default(),
+        })).is_ok());
+        assert_eq!(acc.available(), dec("1.00"));
+
+        let actions: Vec<_> = acc.audit_log().iter()
+            .map(|record| (record.tx_id, record.action, record.reason.as_deref()))
+            .collect();
+        assert_eq!(actions, vec![
+            (1, AuditAction::Chargeback, None),
+            (2, AuditAction::Unlock, Some("reinstated")),
+        ]);
+    }
+
+    #[test]
+    fn lock_manually() {
+        let mut acc = Account::new(1);
+
+        assert_eq!(acc.apply_tx(Transaction::Unlock(AccountAction {
+            client_id: 1,
+            tx_id: 1,
+            reason: None,
+        })), Err(EngineError::AccountNotLocked { client_id: 1, tx_id: 1 }));
+
+        assert!(acc.apply_tx(Transaction::Lock(AccountAction {
+            client_id: 1,
+            tx_id: 2,
+            reason: Some("fraud".to_owned()),
+        })).is_ok());
+        assert!(acc.locked);
+
+        assert_eq!(acc.apply_tx(Transaction::Lock(AccountAction {
+            client_id: 1,
+            tx_id: 3,
+            reason: None,
+        })), Err(EngineError::AccountLocked { client_id: 1, tx_id: 3 }));
+
+        assert_eq!(acc.audit_log().len(), 1);
+        assert_eq!(acc.audit_log()[0].action, AuditAction::Lock);
     }
 }
@@ -0,0 +1,44 @@
+use std::io;
+use serde::{Serialize, Deserialize};
+
+use crate::types::{ClientID, TransactionID};
+
+/// Change of the account's locked state or manual correction of it's balance.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum AuditAction {
+    /// Account was locked manually.
+    Lock,
+    /// Account was unlocked/reinstated.
+    Unlock,
+    /// Account was locked because of the chargeback.
+    Chargeback,
+    /// Balance was corrected manually.
+    Adjustment,
+}
+
+/// Record in the account's audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    #[serde(rename = "client")]
+    pub client_id: ClientID,
+    /// Id of the transaction that caused the change.
+    #[serde(rename = "tx")]
+    pub tx_id: TransactionID,
+    pub action: AuditAction,
+    pub reason: Option<String>,
+}
+
+/// Serializes audit records to writer as csv.
+pub fn write_csv<'a, W, I>(writer: W, records: I) -> Result<(), csv::Error>
+where W: io::Write,
+      I: IntoIterator<Item = &'a AuditRecord>,
+{
+    let mut wtr = csv::Writer::from_writer(writer);
+    for record in records {
+        wtr.serialize(record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
@@ -1,45 +1,99 @@
 use std::fs::File;
-use std::io;
+use std::io::{self, BufRead};
 use std::convert::TryFrom;
 
-use crate::input_transaction::InputTransaction;
+use crate::input_transaction::{InputTransaction, InputFormat};
 use crate::transaction::Transaction;
 use crate::account::Account;
-use crate::output_account::OutputAccount;
+use crate::output_account::{OutputAccount, OutputFormat, write_accounts};
+use crate::error::EngineError;
+use crate::rejects::{Origin, Reject, RejectReason, Rejects};
+use crate::policy::Policy;
+use crate::snapshot::Snapshot;
+use crate::types::Timestamp;
 
 pub trait Bank: Default {
     type AccountsIter: Iterator<Item = Account>;
 
+    /// Create new empty `Bank`, whose accounts follow `policy`.
+    fn new_with_policy(policy: Policy) -> Self;
+
+    /// Restores `Bank` from the `snapshot`, whose accounts follow `policy`.
+    fn from_snapshot(snapshot: Snapshot, policy: Policy) -> Self;
+
+    /// Consumes `Bank` returning it's complete state, from
+    /// which it can be restored with [Bank::from_snapshot].
+    /// Fails if parts of it kept on disk can't be read.
+    fn into_snapshot(self) -> io::Result<Snapshot>;
+
     /// Apply `Transaction` to the `Account` in `Bank`.
-    fn apply_tx<T: Into<Transaction>>(&mut self, tx: T) -> Result<(), ()>;
+    fn apply_tx<T: Into<Transaction>>(&mut self, tx: T) -> Result<(), EngineError> {
+        self.apply_tx_at(tx.into(), None)
+    }
+
+    /// Apply `Transaction`, that happened at unix time `timestamp`
+    /// if input had one, to the `Account` in `Bank`.
+    fn apply_tx_at(
+        &mut self,
+        tx: Transaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), EngineError>;
+
+    /// Consumes `Bank` returning it's accounts, ordered by client id.
     fn into_accounts_iter(self) -> Self::AccountsIter;
 
+    /// Current state of all accounts in the `Bank`, ordered by client id.
+    /// Unlike [Bank::into_accounts_iter] `Bank` can be used after.
+    fn accounts(&self) -> Vec<OutputAccount>;
+
+    /// Apply `Transaction` that came from `origin` in the input. If it's
+    /// refused, instead of returning an error it's reported to `rejects`.
+    fn apply_tx_or_reject(
+        &mut self,
+        tx: Transaction,
+        timestamp: Option<Timestamp>,
+        origin: Origin,
+        rejects: &Rejects,
+    ) -> Result<(), csv::Error> {
+        if let Err(err) = self.apply_tx_at(tx, timestamp) {
+            rejects.report(Reject { origin, reason: err.into() })?;
+        }
+        Ok(())
+    }
+
     /// Reads and deserializes input csv from file and applies
     /// transactions to the new/empty `Bank`. Returning `Bank`.
-    fn from_input_transactions_csv_file(filename: &str) -> Self {
-        let file = File::open(filename).unwrap();
+    fn from_input_transactions_csv_file(filename: &str) -> Result<Self, csv::Error> {
+        let file = File::open(filename)?;
         Self::from_input_transactions_csv(file)
     }
 
     /// Reads and deserializes input csv from reader and applies
     /// transactions to the new/empty `Bank`. Returning `Bank`.
-    fn from_input_transactions_csv<R: io::Read>(reader: R) -> Self {
-        Self::from_input_transactions(
-            csv::Reader::from_reader(reader)
-                .deserialize::<InputTransaction>()
-                .filter_map(Result::ok)
-        )
+    fn from_input_transactions_csv<R: io::Read>(reader: R) -> Result<Self, csv::Error> {
+        let mut bank = Self::default();
+        bank.apply_input_transactions_csv(reader)?;
+        Ok(bank)
+    }
+
+    /// Same as [Bank::from_input_transactions_csv], but every row that
+    /// failed to parse or was refused by the `Bank` is reported to `rejects`.
+    fn from_input_transactions_csv_with_rejects<R: io::Read>(
+        reader: R,
+        rejects: &Rejects,
+    ) -> Result<Self, csv::Error> {
+        let mut bank = Self::default();
+        bank.apply_input_transactions_csv_with_rejects(reader, rejects)?;
+        Ok(bank)
     }
 
     /// Applies `InputTransaction`-s to the new/empty `Bank`. Returning `Bank`.
     fn from_input_transactions<I>(iter: I) -> Self
     where I: Iterator<Item = InputTransaction>,
     {
-        let iter = iter
-            .map(Transaction::try_from)
-            .filter_map(Result::ok);
-
-        Self::from_transactions(iter)
+        let mut bank = Self::default();
+        bank.apply_input_transactions(iter);
+        bank
     }
 
     /// Applies `Transaction`-s to the new/empty `Bank`. Returning `Bank`.
@@ -47,26 +101,569 @@ pub trait Bank: Default {
     where I: Iterator<Item = Transaction>,
     {
         let mut bank = Self::default();
+        bank.apply_transactions(it);
+        bank
+    }
+
+    /// Reads and deserializes input csv from reader and applies
+    /// transactions to the `Bank`. Rows that fail to parse are skipped,
+    /// but failure to read from `reader` is returned as an error.
+    fn apply_input_transactions_csv<R: io::Read>(&mut self, reader: R) -> Result<(), csv::Error> {
+        let mut rdr = csv::Reader::from_reader(reader);
+
+        for input in rdr.deserialize::<InputTransaction>() {
+            match input {
+                Ok(input) => self.apply_input_transactions(std::iter::once(input)),
+                Err(err) if err.is_io_error() => return Err(err),
+                Err(_) => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as [Bank::apply_input_transactions_csv], but every row that
+    /// failed to parse or was refused by the `Bank` is reported to `rejects`.
+    fn apply_input_transactions_csv_with_rejects<R: io::Read>(
+        &mut self,
+        reader: R,
+        rejects: &Rejects,
+    ) -> Result<(), csv::Error> {
+        let mut rdr = csv::Reader::from_reader(reader);
+        let headers = rdr.byte_headers()?.clone();
+        // read as bytes, so that fields of the malformed row are there to report.
+        let mut record = csv::ByteRecord::new();
+
+        loop {
+            match rdr.read_byte_record(&mut record) {
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(err) if err.is_io_error() => return Err(err),
+                Err(err) => {
+                    let line = err.position().map_or(0, |pos| pos.line());
+                    rejects.report(Reject {
+                        origin: Origin { line, raw: raw_row(&record) },
+                        reason: RejectReason::Parse(err.to_string()),
+                    })?;
+                    continue;
+                }
+            }
+            let origin = Origin {
+                line: record.position().map_or(0, |pos| pos.line()),
+                raw: raw_row(&record),
+            };
+
+            let input = record.deserialize::<InputTransaction>(Some(&headers))
+                .map_err(|err| err.to_string());
+            self.apply_input_or_reject(input, origin, rejects)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads and deserializes input json lines from reader and applies
+    /// transactions to the `Bank`. Lines that fail to parse are skipped,
+    /// but failure to read from `reader` is returned as an error.
+    fn apply_input_transactions_jsonl<R: io::Read>(&mut self, reader: R) -> io::Result<()> {
+        for line in io::BufReader::new(reader).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Ok(input) = serde_json::from_str::<InputTransaction>(&line) {
+                self.apply_input_transactions(std::iter::once(input));
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as [Bank::apply_input_transactions_jsonl], but every line that
+    /// failed to parse or was refused by the `Bank` is reported to `rejects`.
+    fn apply_input_transactions_jsonl_with_rejects<R: io::Read>(
+        &mut self,
+        reader: R,
+        rejects: &Rejects,
+    ) -> Result<(), csv::Error> {
+        for (i, line) in io::BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let input = serde_json::from_str::<InputTransaction>(&line)
+                .map_err(|err| err.to_string());
+            let origin = Origin { line: i as u64 + 1, raw: line };
+            self.apply_input_or_reject(input, origin, rejects)?;
+        }
+        Ok(())
+    }
+
+    /// Reads input in the given `format` and applies transactions to the `Bank`.
+    fn apply_input(&mut self, reader: impl io::Read, format: InputFormat) -> Result<(), csv::Error> {
+        match format {
+            InputFormat::Csv => self.apply_input_transactions_csv(reader),
+            InputFormat::JsonLines => Ok(self.apply_input_transactions_jsonl(reader)?),
+        }
+    }
+
+    /// Same as [Bank::apply_input], but every row that failed to parse
+    /// or was refused by the `Bank` is reported to `rejects`.
+    fn apply_input_with_rejects(
+        &mut self,
+        reader: impl io::Read,
+        format: InputFormat,
+        rejects: &Rejects,
+    ) -> Result<(), csv::Error> {
+        match format {
+            InputFormat::Csv => {
+                self.apply_input_transactions_csv_with_rejects(reader, rejects)
+            },
+            InputFormat::JsonLines => {
+                self.apply_input_transactions_jsonl_with_rejects(reader, rejects)
+            },
+        }
+    }
+
+    /// Applies row parsed from `origin` in the input. If it failed to
+    /// parse or was refused by the `Bank`, it's reported to `rejects`.
+    fn apply_input_or_reject(
+        &mut self,
+        input: Result<InputTransaction, String>,
+        origin: Origin,
+        rejects: &Rejects,
+    ) -> Result<(), csv::Error> {
+        let input = match input {
+            Ok(input) => input,
+            Err(msg) => {
+                return rejects.report(Reject { origin, reason: RejectReason::Parse(msg) });
+            },
+        };
+
+        let timestamp = input.timestamp;
+        match Transaction::try_from(input) {
+            Ok(tx) => self.apply_tx_or_reject(tx, timestamp, origin, rejects),
+            Err(err) => rejects.report(Reject { origin, reason: err.into() }),
+        }
+    }
+
+    /// Applies `InputTransaction`-s to the `Bank`.
+    fn apply_input_transactions<I>(&mut self, iter: I)
+    where I: Iterator<Item = InputTransaction>,
+    {
+        for input in iter {
+            let timestamp = input.timestamp;
+            if let Ok(tx) = Transaction::try_from(input) {
+                // ignore result
+                let _ = self.apply_tx_at(tx, timestamp);
+            }
+        }
+    }
+
+    /// Applies `Transaction`-s to the `Bank`.
+    fn apply_transactions<I>(&mut self, it: I)
+    where I: Iterator<Item = Transaction>,
+    {
         it.for_each(|tx: Transaction| {
             // ignore result
-            let _ = bank.apply_tx(tx);
+            let _ = self.apply_tx(tx);
         });
-        bank
     }
 
-
     /// Extracts accounts data from the bank and serializes
-    /// [OutputAccount](crate::output_account::OutputAccount) to writer.
-    /// No need to create BufWriter since `csv::Writer` uses it's own buffer.
+    /// [OutputAccount](crate::output_account::OutputAccount) to writer,
+    /// a row per client and currency.
     fn accounts_to_csv<W>(self, writer: W) -> Result<(), csv::Error>
     where W: io::Write,
     {
-        let mut wtr = csv::Writer::from_writer(writer);
+        let rows = self.into_accounts_iter()
+            .flat_map(|account| OutputAccount::rows(&account, false));
+        write_accounts(writer, rows, OutputFormat::Csv)?;
+        Ok(())
+    }
+
+    /// Same as [Bank::accounts_to_csv], but also serializes accounts'
+    /// [audit logs](crate::account::Account::audit_log) to `audit_writer`.
+    fn accounts_to_csv_with_audit<W, A>(
+        self,
+        writer: W,
+        audit_writer: A,
+    ) -> Result<(), csv::Error>
+    where W: io::Write,
+          A: io::Write,
+    {
+        let mut audit_wtr = csv::Writer::from_writer(audit_writer);
+        let mut rows = vec![];
 
         for account in self.into_accounts_iter() {
-            let output: OutputAccount = account.into();
-            wtr.serialize(output)?;
+            for record in account.audit_log() {
+                audit_wtr.serialize(record)?;
+            }
+            rows.extend(OutputAccount::rows(&account, false));
         }
+        write_accounts(writer, rows, OutputFormat::Csv)?;
+        audit_wtr.flush()?;
         Ok(())
     }
 }
+
+/// Serializes record back to the csv row, without the line terminator.
+/// Bytes that aren't valid UTF-8 are replaced.
+pub(crate) fn raw_row(record: &csv::ByteRecord) -> String {
+    let mut wtr = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(vec![]);
+    // writing to `Vec` can't fail.
+    wtr.write_record(record).unwrap();
+
+    let mut raw = String::from_utf8_lossy(&wtr.into_inner().unwrap()).into_owned();
+    raw.pop();
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_bank::BasicBank;
+    use crate::concurrent_bank::ConcurrentBank;
+    use crate::types::Amount;
+    use crate::expiry::{Age, DisputeExpiry, ExpiryAction};
+    use crate::test_util::SharedBuf;
+
+    /// Returns sorted (line, reason) pairs of rejected rows.
+    fn rejected_rows<B: Bank>(input: &str) -> Vec<(String, String)> {
+        rejected_rows_in_format::<B>(input, InputFormat::Csv)
+    }
+
+    fn rejected_rows_in_format<B: Bank>(
+        input: &str,
+        format: InputFormat,
+    ) -> Vec<(String, String)> {
+        let buf = SharedBuf::default();
+        let rejects = Rejects::new(buf.clone());
+
+        let mut bank = B::default();
+        bank.apply_input_with_rejects(input.as_bytes(), format, &rejects).unwrap();
+        // wait for bank to finish.
+        bank.into_accounts_iter().for_each(drop);
+        rejects.flush().unwrap();
+        reported_rows(&buf)
+    }
+
+    fn reported_rows(buf: &SharedBuf) -> Vec<(String, String)> {
+        let output = buf.contents();
+        let mut rdr = csv::Reader::from_reader(output.as_bytes());
+        let mut rows: Vec<_> = rdr.records()
+            .map(|r| r.unwrap())
+            .map(|r| (r[1].to_owned(), r[2].to_owned()))
+            .collect();
+        rows.sort_by_key(|(line, _)| line.parse::<u64>().unwrap());
+        rows
+    }
+
+    const INPUT: &str = "\
+type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,5.0
+deposit,x,3,1.0
+refund,2,4,1.0
+deposit,2,5
+dispute,2,9,
+";
+
+    fn expected() -> Vec<(String, String)> {
+        vec![
+            ("3".to_owned(), "insufficient_funds".to_owned()),
+            ("4".to_owned(), "parse_error".to_owned()),
+            ("5".to_owned(), "unknown_type".to_owned()),
+            ("6".to_owned(), "parse_error".to_owned()),
+            ("7".to_owned(), "tx_not_found".to_owned()),
+        ]
+    }
+
+    #[test]
+    fn rejects_basic_bank() {
+        assert_eq!(rejected_rows::<BasicBank>(INPUT), expected());
+    }
+
+    #[test]
+    fn rejects_concurrent_bank() {
+        assert_eq!(rejected_rows::<ConcurrentBank>(INPUT), expected());
+    }
+
+    #[test]
+    fn raw_of_malformed_rows() {
+        let buf = SharedBuf::default();
+        let rejects = Rejects::new(buf.clone());
+        let input = b"type,client,tx,amount\ndeposit,2,5\ndeposit,1,\xff,1.0\n\"deposit\",1,1\n";
+
+        let mut bank = BasicBank::default();
+        bank.apply_input_transactions_csv_with_rejects(&input[..], &rejects).unwrap();
+        rejects.flush().unwrap();
+
+        let output = buf.contents();
+        let mut rdr = csv::Reader::from_reader(output.as_bytes());
+        let rows: Vec<_> = rdr.records()
+            .map(|r| r.unwrap())
+            .map(|r| (r[2].to_owned(), r[4].to_owned()))
+            .collect();
+        assert_eq!(rows, vec![
+            ("parse_error".to_owned(), "deposit,2,5".to_owned()),
+            ("parse_error".to_owned(), "deposit,1,\u{fffd},1.0".to_owned()),
+            ("parse_error".to_owned(), "deposit,1,1".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn rejects_jsonl() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.0"}
+{"type":"withdrawal","client":1,"tx":2,"amount":"5.0"}
+
+{"type":"deposit","client":"x","tx":3,"amount":"1.0"}
+{"type":"dispute","client":1,"tx":9}
+"#;
+        assert_eq!(rejected_rows_in_format::<BasicBank>(input, InputFormat::JsonLines), vec![
+            ("2".to_owned(), "insufficient_funds".to_owned()),
+            ("4".to_owned(), "parse_error".to_owned()),
+            ("5".to_owned(), "tx_not_found".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn rejects_zero_dispute() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,1,0
+dispute,1,1,
+";
+        assert_eq!(rejected_rows::<BasicBank>(input), vec![
+            ("3".to_owned(), "zero_amount".to_owned()),
+        ]);
+
+        let bank = BasicBank::from_input_transactions_csv(input.as_bytes()).unwrap();
+        assert_eq!(bank.accounts()[0].held, Amount::new(5, 0));
+    }
+
+    const CROSS_CLIENT_INPUT: &str = "\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,1,1.0
+dispute,3,1,
+deposit,3,2,1.0
+";
+
+    fn cross_client_expected() -> Vec<(String, String)> {
+        vec![
+            ("3".to_owned(), "duplicate_tx".to_owned()),
+            ("4".to_owned(), "client_mismatch".to_owned()),
+        ]
+    }
+
+    #[test]
+    fn cross_client_basic_bank() {
+        assert_eq!(
+            rejected_rows::<BasicBank>(CROSS_CLIENT_INPUT),
+            cross_client_expected(),
+        );
+    }
+
+    #[test]
+    fn cross_client_concurrent_bank() {
+        assert_eq!(
+            rejected_rows::<ConcurrentBank>(CROSS_CLIENT_INPUT),
+            cross_client_expected(),
+        );
+    }
+
+    fn accounts_csv<B: Bank>(input: &str) -> String {
+        let mut buf = vec![];
+        B::from_input_transactions_csv(input.as_bytes()).unwrap()
+            .accounts_to_csv(&mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn same_output_in_basic_and_concurrent() {
+        let mut input = "type,client,tx,amount\n".to_owned();
+        for tx_id in 0..1000 {
+            let client_id = (tx_id * 7919) % 97;
+            input += &format!("deposit,{},{},{}.5\n", client_id, tx_id, tx_id);
+        }
+
+        let basic = accounts_csv::<BasicBank>(&input);
+        let concurrent = accounts_csv::<ConcurrentBank>(&input);
+
+        assert_eq!(basic, concurrent);
+        assert_eq!(basic.lines().count(), 98);
+        assert!(basic.lines().nth(1).unwrap().starts_with("0,"));
+    }
+
+    /// Processes `first` input and restores new bank from it's snapshot.
+    fn restored_bank<B: Bank>(first: &str) -> B {
+        let bank = B::from_input_transactions_csv(first.as_bytes()).unwrap();
+        let mut buf = vec![];
+        bank.into_snapshot().unwrap().write_to(&mut buf).unwrap();
+
+        let snapshot = Snapshot::read_from(&buf[..]).unwrap();
+        B::from_snapshot(snapshot, Policy::default())
+    }
+
+    fn continue_after_snapshot<B: Bank>() {
+        let mut bank = restored_bank::<B>("\
+type,client,tx,amount
+deposit,1,1,2.0
+deposit,2,2,1.0
+dispute,1,1,
+");
+
+        let buf = SharedBuf::default();
+        let rejects = Rejects::new(buf.clone());
+        bank.apply_input_with_rejects("\
+type,client,tx,amount
+resolve,1,1,
+deposit,3,1,1.0
+withdrawal,2,3,1.0
+".as_bytes(), InputFormat::Csv, &rejects).unwrap();
+
+        let accounts = bank.accounts();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].available, Amount::new(2, 0));
+        assert_eq!(accounts[0].held, Amount::new(0, 0));
+        assert_eq!(accounts[1].available, Amount::new(0, 0));
+
+        // wait for bank to finish.
+        bank.into_accounts_iter().for_each(drop);
+        rejects.flush().unwrap();
+        assert_eq!(reported_rows(&buf), vec![
+            ("3".to_owned(), "duplicate_tx".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn continue_after_snapshot_basic_bank() {
+        continue_after_snapshot::<BasicBank>();
+    }
+
+    #[test]
+    fn continue_after_snapshot_concurrent_bank() {
+        continue_after_snapshot::<ConcurrentBank>();
+    }
+
+    fn dispute_window_and_expiry<B: Bank>() {
+        let policy = Policy {
+            dispute_window: Some(Age::Seconds(100)),
+            dispute_expiry: Some(DisputeExpiry {
+                after: Age::Transactions(2),
+                action: ExpiryAction::Chargeback,
+            }),
+            ..Policy::default()
+        };
+        let mut bank = B::new_with_policy(policy);
+
+        let buf = SharedBuf::default();
+        let rejects = Rejects::new(buf.clone());
+        bank.apply_input_with_rejects("\
+type,client,tx,amount,timestamp
+deposit,1,1,2.0,1000
+deposit,2,2,3.0,1000
+dispute,1,1,,1050
+dispute,2,2,,1200
+deposit,3,3,1.0,1210
+deposit,3,4,1.0,1220
+".as_bytes(), InputFormat::Csv, &rejects).unwrap();
+
+        let accounts: Vec<_> = bank.into_accounts_iter().collect();
+        rejects.flush().unwrap();
+        assert_eq!(reported_rows(&buf), vec![
+            ("5".to_owned(), "dispute_window_expired".to_owned()),
+        ]);
+
+        // dispute stayed open for more than two transactions.
+        assert!(accounts[0].is_locked());
+        assert_eq!(accounts[0].total(), Amount::new(0, 0));
+        let expirations: Vec<_> = accounts.iter()
+            .flat_map(|account| account.expirations())
+            .map(|expiration| (expiration.tx_id, expiration.deadline))
+            .collect();
+        assert_eq!(expirations, vec![(1, 5)]);
+    }
+
+    #[test]
+    fn dispute_window_and_expiry_basic_bank() {
+        dispute_window_and_expiry::<BasicBank>();
+    }
+
+    #[test]
+    fn dispute_window_and_expiry_concurrent_bank() {
+        dispute_window_and_expiry::<ConcurrentBank>();
+    }
+
+    fn transfers<B: Bank>(mut bank: B) {
+        let buf = SharedBuf::default();
+        let rejects = Rejects::new(buf.clone());
+        bank.apply_input_with_rejects("\
+type,client,tx,amount,to
+deposit,1,1,5.0,
+deposit,3,2,1.0,
+transfer,1,3,2.0,2
+transfer,1,4,4.0,2
+lock,2,5,,
+transfer,1,6,1.0,2
+transfer,1,3,1.0,3
+transfer,3,7,1.0,1
+transfer,1,8,1.0,1
+transfer,1,9,1.0,
+".as_bytes(), InputFormat::Csv, &rejects).unwrap();
+
+        let accounts: Vec<_> = bank.into_accounts_iter().collect();
+        rejects.flush().unwrap();
+        assert_eq!(reported_rows(&buf), vec![
+            ("5".to_owned(), "insufficient_funds".to_owned()),
+            ("7".to_owned(), "account_locked".to_owned()),
+            ("8".to_owned(), "duplicate_tx".to_owned()),
+            ("10".to_owned(), "self_transfer".to_owned()),
+            ("11".to_owned(), "missing_destination".to_owned()),
+        ]);
+
+        let balances: Vec<_> = accounts.iter()
+            .map(|account| (account.client_id(), account.available()))
+            .collect();
+        assert_eq!(balances, vec![
+            (1, Amount::new(4, 0)),
+            (2, Amount::new(2, 0)),
+            (3, Amount::new(0, 0)),
+        ]);
+    }
+
+    #[test]
+    fn transfers_basic_bank() {
+        transfers(BasicBank::new());
+    }
+
+    #[test]
+    fn transfers_concurrent_bank() {
+        // clients 1 and 2 are on different threads, 1 and 3 on the same.
+        transfers(ConcurrentBank::new_with_thread_count(2));
+    }
+
+    const CURRENCIES_INPUT: &str = "\
+type,client,tx,amount,currency
+deposit,1,1,10,usd
+deposit,1,2,5,EUR
+withdrawal,1,3,6,EUR
+deposit,2,4,3,
+dispute,1,1,,
+withdrawal,2,5,1,usd
+";
+
+    #[test]
+    fn currencies() {
+        let expected = "\
+client,currency,available,held,total,locked
+1,EUR,5,0,5,false
+1,USD,0,10,10,false
+2,,3,0,3,false
+";
+        assert_eq!(accounts_csv::<BasicBank>(CURRENCIES_INPUT), expected);
+        assert_eq!(accounts_csv::<ConcurrentBank>(CURRENCIES_INPUT), expected);
+    }
+}
@@ -1,43 +1,524 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::mem;
+use std::path::Path;
 
-use crate::types::ClientID;
-use crate::transaction::Transaction;
+use crate::types::{ClientID, Timestamp};
+use crate::transaction::{Transaction, TransferInfo};
 use crate::account::Account;
+use crate::output_account::{OutputAccount, AccountOrder};
 use crate::bank::Bank;
+use crate::error::EngineError;
+use crate::policy::Policy;
+use crate::tx_index::TxIndex;
+use crate::snapshot::Snapshot;
+use crate::wal::{Wal, WalError, SyncPolicy};
+use crate::tx_store::{TxStore, MemoryTxStore, DiskTxStore};
+use crate::expiry::Moment;
 
 /// Stores and manages accounts in the bank.
 #[derive(Default)]
 pub struct BasicBank {
     accounts: HashMap<ClientID, Account>,
+    tx_index: TxIndex,
+    policy: Policy,
+    /// Log every accepted transaction is written to.
+    wal: Option<Wal>,
+    /// Number of inputs covered by the `wal`, including
+    /// refused ones, which aren't written to it.
+    replayed: u64,
+    /// Number of next transactions to skip, since they
+    /// were already replayed from the `wal`.
+    skip: u64,
+    /// Store shared by accounts, if their transactions are kept on disk.
+    disk_store: Option<DiskTxStore>,
+    /// Moment of the last transaction.
+    clock: Moment,
+    /// Deadlines of the accounts' open disputes, in
+    /// units of the [Policy::dispute_expiry] age.
+    dispute_deadlines: Deadlines,
+    /// Deadlines of the accounts' authorizations, in units
+    /// of the [Policy::authorization_expiry] age.
+    release_deadlines: Deadlines,
+    /// Errors of the disputes and authorizations that failed to
+    /// expire, untill they're taken with [BasicBank::expiry_errors].
+    expiry_errors: Vec<EngineError>,
+}
+
+/// Next deadlines of the accounts. Can have outdated entries,
+/// account is just checked again then.
+#[derive(Default)]
+struct Deadlines(BTreeSet<(u64, ClientID)>);
+
+impl Deadlines {
+    fn insert(&mut self, deadline: Option<u64>, client_id: ClientID) {
+        if let Some(deadline) = deadline {
+            self.0.insert((deadline, client_id));
+        }
+    }
+
+    /// Removes and returns clients, whose deadline is before `now`.
+    fn take_due(&mut self, now: u64) -> BTreeSet<(u64, ClientID)> {
+        let not_due = self.0.split_off(&(now, 0));
+        mem::replace(&mut self.0, not_due)
+    }
 }
 
 impl Bank for BasicBank {
     type AccountsIter = Box<dyn Iterator<Item = Account>>;
 
-    // TODO: propagate error from apply_tx.
-    /// Apply `Transaction` to the `Account` in `BasicBank`.
-    fn apply_tx<T: Into<Transaction>>(&mut self, tx: T) -> Result<(), ()> {
-        let tx: Transaction = tx.into();
-        let client_id = tx.get_client_id();
+    /// Create new empty `BasicBank`, whose accounts follow `policy`.
+    fn new_with_policy(policy: Policy) -> Self {
+        Self {
+            tx_index: TxIndex::new_with_policy(policy.clone()),
+            policy,
+            ..Self::default()
+        }
+    }
 
-        self.accounts
-            .entry(client_id)
-            .or_insert(Account::new(client_id))
-            .apply_tx(tx)
-            .or(Err(())) // ignore error
+    fn from_snapshot(snapshot: Snapshot, policy: Policy) -> Self {
+        let clock = snapshot.clock();
+        let (accounts, tx_ids, tx_owners) = snapshot.into_parts();
+        let accounts: HashMap<_, _> = accounts.into_iter()
+            .map(|mut account| {
+                account.set_policy(policy.clone());
+                (account.client_id(), account)
+            })
+            .collect();
+        let client_ids: Vec<_> = accounts.keys().copied().collect();
+
+        let mut bank = Self {
+            accounts,
+            tx_index: TxIndex::from_parts(tx_ids, tx_owners, policy.clone()),
+            policy,
+            clock,
+            ..Self::default()
+        };
+        for client_id in client_ids {
+            bank.track_deadlines(client_id);
+        }
+        bank
+    }
+
+    fn into_snapshot(self) -> io::Result<Snapshot> {
+        let tx_ids = self.tx_index.ids().clone();
+        let tx_owners = self.tx_index.owners()?;
+        let accounts = self.accounts.into_values().collect();
+        Ok(Snapshot::new(accounts, tx_ids, tx_owners, self.clock))
     }
 
-    /// Consumes `BasicBank` returning accounts iterator.
+    /// Apply `Transaction` to the `Account` in `BasicBank`. If bank has
+    /// write-ahead log, transaction is written to it before it's applied,
+    /// so account doesn't change if it can't be written, and taken back
+    /// from it if it's refused.
+    fn apply_tx_at(
+        &mut self,
+        tx: Transaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), EngineError> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return Ok(());
+        }
+
+        let at = self.next_moment(timestamp);
+        if let Some(wal) = &mut self.wal {
+            wal.append(&tx, at).map_err(|err| EngineError::WalWrite {
+                client_id: tx.get_client_id(),
+                tx_id: tx.get_tx_id(),
+                message: err.to_string(),
+            })?;
+        }
+
+        let result = self.apply_at(tx, at);
+        if let (Err(_), Some(wal)) = (&result, &mut self.wal) {
+            // if it stays in the log, it's just refused again on replay.
+            let _ = wal.retract();
+        }
+        result
+    }
+
+    fn accounts(&self) -> Vec<OutputAccount> {
+        let mut accounts: Vec<_> = self.accounts.values()
+            .flat_map(|account| OutputAccount::rows(account, false))
+            .collect();
+        AccountOrder::ClientId.sort(&mut accounts);
+        accounts
+    }
+
+    /// Consumes `BasicBank` returning accounts iterator,
+    /// ordered by client id.
     fn into_accounts_iter(self) -> Self::AccountsIter {
-        Box::new(
-            self.accounts.into_iter().map(|(_, account)| account)
-        )
+        let mut accounts: Vec<_> = self.accounts.into_values().collect();
+        accounts.sort_by_key(Account::client_id);
+        Box::new(accounts.into_iter())
     }
 }
 
 impl BasicBank {
     /// Create new empty `BasicBank`
     pub fn new() -> Self {
-        Self { accounts: HashMap::new() }
+        Self::new_with_policy(Policy::default())
+    }
+
+    /// Keeps transactions of the accounts in a file at `path`, with at
+    /// most `cache_capacity` of them in memory, instead of keeping all
+    /// of them in memory. Transactions of existing accounts are moved there.
+    /// Owners of the transactions are kept next to it, at `path` with
+    /// `.owners` added.
+    pub fn with_disk_store<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        cache_capacity: usize,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        let store = DiskTxStore::create(path, cache_capacity)?;
+        for account in self.accounts.values_mut() {
+            account.set_store(Box::new(store.for_client(account.client_id())))?;
+        }
+        let mut owners_path = path.as_os_str().to_owned();
+        owners_path.push(".owners");
+        self.tx_index = mem::take(&mut self.tx_index).with_owners_on_disk(owners_path)?;
+        self.disk_store = Some(store);
+        Ok(self)
+    }
+
+    /// Opens write-ahead log at `path` and replays transactions
+    /// already in it, restoring the state bank had when it crashed.
+    /// From then on, every accepted transaction is written to the log.
+    ///
+    /// Log should start together with the bank's state, so when bank
+    /// is restored from the snapshot, log has to be started after it,
+    /// see [Wal::reset].
+    pub fn with_wal<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        sync: SyncPolicy,
+    ) -> Result<Self, WalError> {
+        let (wal, logged) = Wal::open(path, sync)?;
+        let start = self.clock.seq;
+        for (tx, at) in logged {
+            // accepted again, since result depends only on the state. Refused
+            // ones in between only moved the clock, which `at` moves as well.
+            let _ = self.apply_at(tx, at);
+            self.replayed = at.seq.saturating_sub(start);
+        }
+        self.wal = Some(wal);
+        Ok(self)
+    }
+
+    /// Number of inputs covered by the write-ahead log, accepted
+    /// transactions replayed from it and refused ones between them.
+    pub fn replayed(&self) -> u64 {
+        self.replayed
+    }
+
+    /// Skips as many next inputs as are covered by the log.
+    ///
+    /// After the crash, same inputs can be fed again from the start
+    /// and only transactions that didn't make it to the log are applied.
+    pub fn skip_replayed(&mut self) {
+        self.skip = self.replayed;
+    }
+
+    /// Takes errors of the disputes and authorizations, that
+    /// failed to expire since the last time they were taken.
+    pub fn expiry_errors(&mut self) -> impl Iterator<Item = EngineError> + '_ {
+        self.expiry_errors.drain(..)
+    }
+
+    /// Moment of the next transaction in the input.
+    fn next_moment(&self, timestamp: Option<Timestamp>) -> Moment {
+        Moment { seq: self.clock.seq + 1, timestamp }
+    }
+
+    /// Applies transaction, that happened `at`, after
+    /// expiring disputes that stayed open untill then.
+    pub(crate) fn apply_at(&mut self, tx: Transaction, at: Moment) -> Result<(), EngineError> {
+        self.advance_clock(at);
+        self.tx_index.check(&tx)?;
+
+        let (tx_id, claims_id) = (tx.get_tx_id(), tx.claims_id());
+        let result = self.apply_to_accounts(tx, at);
+        if result.is_err() && claims_id {
+            // so that refused transaction can be retried with the same id.
+            self.tx_index.unclaim(tx_id);
+        }
+        result
+    }
+
+    fn apply_to_accounts(&mut self, tx: Transaction, at: Moment) -> Result<(), EngineError> {
+        if let Transaction::Transfer(transfer) = &tx {
+            // both sides are checked first, so it's applied to both or neither.
+            self.check_transfer(transfer, transfer.client_id)?;
+            self.check_transfer(transfer, transfer.to)?;
+            self.commit_transfer(transfer, transfer.client_id, at)?;
+            return self.commit_transfer(transfer, transfer.to, at);
+        }
+
+        let client_id = tx.get_client_id();
+        let result = self.account_mut(client_id).apply_tx_at(tx, at);
+        self.track_deadlines(client_id);
+        result
+    }
+
+    /// Keeps next deadlines of the account's disputes and authorizations.
+    fn track_deadlines(&mut self, client_id: ClientID) {
+        if let Some(account) = self.accounts.get(&client_id) {
+            self.dispute_deadlines.insert(account.next_expiry(), client_id);
+            self.release_deadlines.insert(account.next_release(), client_id);
+        }
+    }
+
+    /// Checks that side of the `transfer` of `client_id`
+    /// can be applied to it's account.
+    pub(crate) fn check_transfer(
+        &self,
+        transfer: &TransferInfo,
+        client_id: ClientID,
+    ) -> Result<(), EngineError> {
+        match self.accounts.get(&client_id) {
+            Some(account) => account.check_transfer(transfer),
+            None => {
+                Account::new_with_policy(client_id, self.policy.clone()).check_transfer(transfer)
+            },
+        }
+    }
+
+    /// Applies side of the `transfer` of `client_id` to it's account.
+    /// Should be checked with [BasicBank::check_transfer] first.
+    pub(crate) fn commit_transfer(
+        &mut self,
+        transfer: &TransferInfo,
+        client_id: ClientID,
+        at: Moment,
+    ) -> Result<(), EngineError> {
+        self.account_mut(client_id).apply_tx_at(Transaction::Transfer(transfer.clone()), at)
+    }
+
+    /// Removes account of `client_id`, so it can be moved to another bank.
+    ///
+    /// Owners of it's transactions stay in the index, since they're
+    /// checked by the router of the [ConcurrentBank](crate::ConcurrentBank)
+    /// before they get here, and it's deadlines are just skipped.
+    pub(crate) fn take_account(&mut self, client_id: ClientID) -> Option<Account> {
+        self.accounts.remove(&client_id)
+    }
+
+    /// Adds account moved from another bank.
+    pub(crate) fn put_account(&mut self, account: Account) {
+        let client_id = account.client_id();
+        self.accounts.insert(client_id, account);
+        self.track_deadlines(client_id);
+    }
+
+    /// Account of `client_id`, created if it doesn't exist yet.
+    fn account_mut(&mut self, client_id: ClientID) -> &mut Account {
+        let policy = &self.policy;
+        let disk_store = &self.disk_store;
+
+        self.accounts
+            .entry(client_id)
+            .or_insert_with(|| {
+                Account::new_with_store(client_id, policy.clone(), new_store(disk_store, client_id))
+            })
+    }
+
+    /// Moves clock forward to `now`, settles disputes and voids
+    /// authorizations that stayed open past their deadline.
+    pub(crate) fn advance_clock(&mut self, now: Moment) {
+        self.clock = self.clock.max(now);
+        let clock = self.clock;
+
+        let now = self.policy.dispute_expiry.and_then(|expiry| expiry.after.now(clock));
+        if let Some(now) = now {
+            for (_, client_id) in self.dispute_deadlines.take_due(now) {
+                if let Some(account) = self.accounts.get_mut(&client_id) {
+                    // disputes that stay open are retried at the next transaction.
+                    if let Err(errors) = account.expire_disputes(clock) {
+                        self.expiry_errors.extend(errors);
+                    }
+                    self.dispute_deadlines.insert(account.next_expiry(), client_id);
+                }
+            }
+        }
+
+        let now = self.policy.authorization_expiry.and_then(|age| age.now(clock));
+        if let Some(now) = now {
+            for (_, client_id) in self.release_deadlines.take_due(now) {
+                if let Some(account) = self.accounts.get_mut(&client_id) {
+                    if let Err(errors) = account.release_authorizations(clock) {
+                        self.expiry_errors.extend(errors);
+                    }
+                    self.release_deadlines.insert(account.next_release(), client_id);
+                }
+            }
+        }
+    }
+}
+
+/// Store for the transactions of the new account.
+fn new_store(disk_store: &Option<DiskTxStore>, client_id: ClientID) -> Box<dyn TxStore> {
+    match disk_store {
+        Some(store) => Box::new(store.for_client(client_id)),
+        None => Box::new(MemoryTxStore::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::types::Amount;
+    use crate::transaction::{TransactionInfo, TransactionRef, AccountAction};
+    use crate::expiry::{Age, DisputeExpiry, ExpiryAction};
+
+    fn txs() -> Vec<Transaction> {
+        vec![
+            Transaction::Deposit(TransactionInfo::new(1, 1, Amount::new(5, 0))),
+            Transaction::Deposit(TransactionInfo::new(2, 2, Amount::new(3, 0))),
+            Transaction::Dispute(TransactionRef::new(1, 1).with_amount(Amount::new(2, 0))),
+            Transaction::Dispute(TransactionRef::new(1, 1).with_amount(Amount::new(1, 0))),
+            Transaction::Withdrawal(TransactionInfo::new(2, 3, Amount::new(1, 0))),
+        ]
+    }
+
+    #[test]
+    fn resume_after_crash() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-resume", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut bank = BasicBank::new().with_wal(&path, SyncPolicy::Always).unwrap();
+            // crashes after applying first three transactions.
+            bank.apply_transactions(txs().into_iter().take(3));
+        }
+
+        let mut bank = BasicBank::new().with_wal(&path, SyncPolicy::Always).unwrap();
+        assert_eq!(bank.replayed(), 3);
+        bank.skip_replayed();
+        bank.apply_transactions(txs().into_iter());
+
+        assert_eq!(bank.accounts(), BasicBank::from_transactions(txs().into_iter()).accounts());
+        assert_eq!(bank.accounts()[0].held, Amount::new(3, 0));
+
+        // everything is in the log now.
+        assert_eq!(BasicBank::new().with_wal(&path, SyncPolicy::Never).unwrap().replayed(), 5);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refused_not_logged() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-resume-refused", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut txs = txs();
+        // refused, since there isn't enough funds.
+        txs.insert(1, Transaction::Withdrawal(TransactionInfo::new(1, 9, Amount::new(50, 0))));
+        {
+            let mut bank = BasicBank::new().with_wal(&path, SyncPolicy::Always).unwrap();
+            bank.apply_transactions(txs.iter().take(4).cloned());
+        }
+        let (_, logged) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(logged.len(), 3);
+
+        // refused one still counts as input.
+        let mut bank = BasicBank::new().with_wal(&path, SyncPolicy::Always).unwrap();
+        assert_eq!(bank.replayed(), 4);
+        bank.skip_replayed();
+        bank.apply_transactions(txs.iter().cloned());
+        assert_eq!(bank.accounts(), BasicBank::from_transactions(txs.into_iter()).accounts());
+
+        // log starts over with the snapshot.
+        Wal::reset(&path).unwrap();
+        let bank = BasicBank::from_snapshot(bank.into_snapshot().unwrap(), Policy::default())
+            .with_wal(&path, SyncPolicy::Always)
+            .unwrap();
+        assert_eq!(bank.replayed(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_store_same_as_memory() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-bank-store", std::process::id()));
+
+        let mut bank = BasicBank::new();
+        // existing transactions are moved to the disk store.
+        bank.apply_tx(txs().remove(0)).unwrap();
+        let mut bank = bank.with_disk_store(&path, 1).unwrap();
+        bank.apply_transactions(txs().into_iter().skip(1));
+
+        assert_eq!(bank.accounts(), BasicBank::from_transactions(txs().into_iter()).accounts());
+
+        // records of every account make it to the snapshot.
+        let transactions = |snapshot: &Snapshot| {
+            let mut accounts: Vec<_> = snapshot.accounts().iter()
+                .map(|account| {
+                    let mut records = account.transactions().unwrap();
+                    records.sort_by_key(|&(tx_id, _)| tx_id);
+                    (account.client_id(), records)
+                })
+                .collect();
+            accounts.sort_by_key(|&(client_id, _)| client_id);
+            accounts
+        };
+        let mut written = vec![];
+        bank.into_snapshot().unwrap().write_to(&mut written).unwrap();
+        let snapshot = Snapshot::read_from(written.as_slice()).unwrap();
+        let expected = BasicBank::from_transactions(txs().into_iter()).into_snapshot().unwrap();
+        assert_eq!(transactions(&snapshot), transactions(&expected));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refused_deposit_retried_with_same_id() {
+        let mut bank = BasicBank::new();
+        let deposit = Transaction::Deposit(TransactionInfo::new(1, 2, Amount::new(5, 0)));
+
+        bank.apply_tx(Transaction::Lock(AccountAction::new(1, 1, None))).unwrap();
+        assert_eq!(
+            bank.apply_tx(deposit.clone()),
+            Err(EngineError::AccountLocked { client_id: 1, tx_id: 2 }),
+        );
+        bank.apply_tx(Transaction::Unlock(AccountAction::new(1, 3, None))).unwrap();
+        assert_eq!(bank.apply_tx(deposit.clone()), Ok(()));
+        assert_eq!(bank.accounts()[0].available, Amount::new(5, 0));
+
+        // once it's applied, id is taken.
+        assert_eq!(
+            bank.apply_tx(deposit),
+            Err(EngineError::DuplicateTx { client_id: 1, tx_id: 2 }),
+        );
+    }
+
+    #[test]
+    fn expiry_not_stuck_behind_refused_dispute() {
+        let policy = Policy {
+            dispute_expiry: Some(DisputeExpiry {
+                after: Age::Transactions(2),
+                action: ExpiryAction::Chargeback,
+            }),
+            ..Policy::default()
+        };
+        let input = "type,client,tx,amount
+deposit,1,1,1
+deposit,1,2,2
+dispute,1,1,0
+dispute,1,2,
+deposit,2,3,1
+deposit,2,4,1
+deposit,2,5,1
+";
+        let mut bank = BasicBank::new_with_policy(policy);
+        bank.apply_input_transactions_csv(input.as_bytes()).unwrap();
+
+        let accounts = bank.accounts();
+        assert_eq!(accounts[0].held, Amount::new(0, 0));
+        assert_eq!(accounts[0].available, Amount::new(1, 0));
+        assert!(accounts[0].locked);
+        assert_eq!(bank.expiry_errors().count(), 0);
     }
 }
@@ -1,63 +1,653 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{RecvTimeoutError, TrySendError};
 
-use crate::types::ClientID;
-use crate::transaction::Transaction;
+use crate::types::{ClientID, Timestamp, TransactionID};
+use crate::transaction::{Transaction, TransferInfo};
 use crate::account::Account;
+use crate::output_account::{OutputAccount, AccountOrder};
 use crate::bank::Bank;
 use crate::basic_bank::BasicBank;
+use crate::error::EngineError;
+use crate::rejects::{Origin, Reject, Rejects};
+use crate::policy::Policy;
+use crate::tx_index::TxIndex;
+use crate::snapshot::Snapshot;
+use crate::id_set::IdSet;
+use crate::expiry::Moment;
+use crate::shard::{ShardStrategy, Modulo};
+use crate::rebalance::{Rebalancing, Throughput, Move};
+
+/// Messages that can wait in the queue of every thread by default,
+/// see [Builder::with_queue_capacity].
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// Transactions that are sent to every thread in one message by
+/// default, see [Builder::with_batching].
+pub const DEFAULT_BATCH_SIZE: usize = 1;
+
+/// `Transaction` that happened `at` to apply, with optional
+/// destination for reporting it in case it's refused.
+struct QueuedTx {
+    tx: Transaction,
+    at: Moment,
+    reject_to: Option<(Origin, Rejects)>,
+}
+
+/// Message sent to the `BankThread`.
+enum Message {
+    Tx(QueuedTx),
+    /// Transactions to apply in order.
+    Txs(Vec<QueuedTx>),
+    /// Moment of the last transaction sent to any of the threads,
+    /// so that disputes past their deadline can be expired.
+    Tick(Moment),
+    /// Side of `client_id` of the transfer, whose other side is on
+    /// another thread. Thread votes whether it can be applied and
+    /// waits for the decision, before it moves on to other messages.
+    Transfer {
+        transfer: TransferInfo,
+        client_id: ClientID,
+        at: Moment,
+        vote: crossbeam_channel::Sender<Result<(), EngineError>>,
+        decision: crossbeam_channel::Receiver<bool>,
+    },
+    /// Request for the current state of the accounts.
+    Accounts(crossbeam_channel::Sender<Vec<OutputAccount>>),
+    /// Request to reply, once everything sent before is applied.
+    Sync(crossbeam_channel::Sender<()>),
+    /// Account of `client_id` to hand over to another thread,
+    /// after all transactions sent before are applied to it.
+    TakeAccount(ClientID, crossbeam_channel::Sender<Option<Account>>),
+    /// Account handed over by another thread. Thread waits for
+    /// it, before it moves on to the client's next transactions.
+    PutAccount(crossbeam_channel::Receiver<Option<Account>>),
+}
+
+/// Sending side of the thread's queue, with transactions
+/// waiting to be sent together.
+struct Outbox {
+    sender: Option<crossbeam_channel::Sender<Message>>,
+    batch: Vec<QueuedTx>,
+    /// When the first transaction of the `batch` was added.
+    since: Instant,
+}
+
+impl Outbox {
+    /// Sends transactions of the batch, if there are any.
+    fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let txs = mem::take(&mut self.batch);
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(Message::Txs(txs));
+        }
+    }
+
+    /// Same as `flush`, but doesn't block. Transactions
+    /// stay in the batch, if thread's queue is full.
+    fn try_flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let txs = mem::take(&mut self.batch);
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Full(Message::Txs(txs))) = sender.try_send(Message::Txs(txs)) {
+                self.batch = txs;
+            }
+        }
+    }
+
+    /// Sends `msg` after the batch, so the thread gets messages in the
+    /// order they were sent. **Blocks** while thread's queue is full.
+    /// `false` if thread is gone.
+    fn send(&mut self, msg: Message) -> bool {
+        self.flush();
+        match &self.sender {
+            Some(sender) => sender.send(msg).is_ok(),
+            None => false,
+        }
+    }
+}
 
 struct BankThread {
     thread: Option<thread::JoinHandle<BasicBank>>,
-    sender: Option<crossbeam_channel::Sender<Transaction>>,
+    /// Shared with the `Flusher`, every message to the
+    /// thread is sent while it's locked.
+    outbox: Arc<Mutex<Outbox>>,
+    /// Same queue as the `outbox` sends to, it's depth
+    /// is read from it without locking the `outbox`.
+    queue: Option<crossbeam_channel::Sender<Message>>,
+    batch_size: usize,
 }
 
 impl BankThread {
-    pub fn new() -> Self {
-        let (sender, rx) = crossbeam_channel::unbounded();
+    /// Spawns thread that owns `bank`, with at most `capacity` messages
+    /// waiting in it's queue, each with up to `batch_size` transactions.
+    /// Errors of the transactions that failed, and of the disputes and
+    /// authorizations that failed to expire, are sent to `errors`, if
+    /// there's room.
+    /// Ids of the refused transactions are sent to `refused`.
+    pub fn new(
+        mut bank: BasicBank,
+        errors: Option<crossbeam_channel::Sender<EngineError>>,
+        refused: crossbeam_channel::Sender<TransactionID>,
+        capacity: usize,
+        batch_size: usize,
+    ) -> Self {
+        let (sender, rx) = crossbeam_channel::bounded(capacity);
         let thread = thread::spawn(move || {
-            let mut bank = BasicBank::new();
-            while let Ok(tx) = rx.recv() {
-                // ignore result
-                let _ = bank.apply_tx(tx);
+            // dropped if nobody asked for them or they aren't taken fast enough.
+            let report = |err| {
+                if let Some(errors) = &errors {
+                    let _ = errors.try_send(err);
+                }
+            };
+            let apply = |bank: &mut BasicBank, queued: QueuedTx| {
+                let QueuedTx { tx, at, reject_to } = queued;
+                let (tx_id, claims_id) = (tx.get_tx_id(), tx.claims_id());
+                let err = match bank.apply_at(tx, at) {
+                    Ok(_) => return,
+                    Err(err) => err,
+                };
+                // duplicate's id belongs to the transaction that was applied.
+                if claims_id && !matches!(err, EngineError::DuplicateTx { .. }) {
+                    let _ = refused.send(tx_id);
+                }
+                if let Some((origin, rejects)) = reject_to {
+                    // write errors will surface on `Rejects::flush`.
+                    let _ = rejects.report(Reject {
+                        origin,
+                        reason: err.clone().into(),
+                    });
+                }
+                report(err);
+            };
+
+            while let Ok(msg) = rx.recv() {
+                match msg {
+                    Message::Tx(queued) => apply(&mut bank, queued),
+                    Message::Txs(txs) => {
+                        for queued in txs {
+                            apply(&mut bank, queued);
+                        }
+                    },
+                    Message::Tick(now) => bank.advance_clock(now),
+                    Message::Transfer { transfer, client_id, at, vote, decision } => {
+                        bank.advance_clock(at);
+                        let _ = vote.send(bank.check_transfer(&transfer, client_id));
+                        if let Ok(true) = decision.recv() {
+                            // can't fail, since it was checked.
+                            let _ = bank.commit_transfer(&transfer, client_id, at);
+                        }
+                    },
+                    Message::Accounts(reply) => {
+                        let _ = reply.send(bank.accounts());
+                    },
+                    Message::Sync(reply) => {
+                        let _ = reply.send(());
+                    },
+                    Message::TakeAccount(client_id, reply) => {
+                        let _ = reply.send(bank.take_account(client_id));
+                    },
+                    Message::PutAccount(account) => {
+                        if let Ok(Some(account)) = account.recv() {
+                            bank.put_account(account);
+                        }
+                    },
+                }
+                bank.expiry_errors().for_each(&report);
             }
             bank
         });
 
         BankThread {
-            sender: Some(sender),
+            queue: Some(sender.clone()),
+            outbox: Arc::new(Mutex::new(Outbox {
+                sender: Some(sender),
+                batch: vec![],
+                since: Instant::now(),
+            })),
             thread: Some(thread),
+            batch_size,
         }
     }
 
-    pub fn apply_tx(&mut self, tx: Transaction) {
-        if let Some(sender) = &self.sender {
-            let _ = sender.send(tx);
+    /// Number of messages waiting in the thread's queue, batch counts as one.
+    pub fn queue_depth(&self) -> usize {
+        self.queue.as_ref().map_or(0, |queue| queue.len())
+    }
+
+    /// Adds transaction to the batch, which is sent once it's full.
+    /// **Blocks** while thread's queue is full.
+    pub fn apply_tx(
+        &self,
+        tx: Transaction,
+        at: Moment,
+        reject_to: Option<(Origin, Rejects)>,
+    ) {
+        let queued = QueuedTx { tx, at, reject_to };
+        let mut outbox = self.outbox.lock().unwrap();
+        if self.batch_size <= 1 {
+            outbox.send(Message::Tx(queued));
+            return;
+        }
+
+        if outbox.batch.is_empty() {
+            outbox.since = Instant::now();
+            outbox.batch.reserve(self.batch_size);
+        }
+        outbox.batch.push(queued);
+        if outbox.batch.len() >= self.batch_size {
+            outbox.flush();
+        }
+    }
+
+    /// Sends side of `client_id` of the `transfer` to the thread.
+    /// Thread is blocked untill decision is sent to the returned transfer.
+    pub fn prepare_transfer(
+        &self,
+        transfer: &TransferInfo,
+        client_id: ClientID,
+        at: Moment,
+    ) -> PreparedTransfer {
+        let (vote, votes) = crossbeam_channel::bounded(1);
+        let (decide, decision) = crossbeam_channel::bounded(1);
+        let transfer = transfer.clone();
+        self.send(Message::Transfer { transfer, client_id, at, vote, decision });
+        PreparedTransfer { votes, decide }
+    }
+
+    pub fn tick(&self, now: Moment) {
+        self.send(Message::Tick(now));
+    }
+
+    /// Sends `msg` after the transactions waiting in the batch.
+    fn send(&self, msg: Message) -> bool {
+        self.outbox.lock().unwrap().send(msg)
+    }
+
+    /// Requests current state of the accounts. Reply is sent after
+    /// all previously sent transactions are applied.
+    pub fn request_accounts(&self) -> Option<crossbeam_channel::Receiver<Vec<OutputAccount>>> {
+        let (reply, rx) = crossbeam_channel::bounded(1);
+        Some(rx).filter(|_| self.send(Message::Accounts(reply)))
+    }
+
+    /// Requests reply, that's sent after all previously
+    /// sent transactions are applied.
+    pub fn request_sync(&self) -> Option<crossbeam_channel::Receiver<()>> {
+        let (reply, rx) = crossbeam_channel::bounded(1);
+        Some(rx).filter(|_| self.send(Message::Sync(reply)))
+    }
+
+    /// Moves account of `client_id` to the `other` thread. Neither
+    /// of them waits for the other, untill `other` gets to it.
+    pub fn move_account(&self, client_id: ClientID, other: &BankThread) {
+        let (reply, account) = crossbeam_channel::bounded(1);
+        if self.send(Message::TakeAccount(client_id, reply)) {
+            other.send(Message::PutAccount(account));
         }
     }
 
     pub fn join(&mut self) -> Option<BasicBank> {
-        // drop `Sender` to let thread no that it's
-        // work is finished and it can return.
-        drop(self.sender.take()?);
+        {
+            let mut outbox = self.outbox.lock().unwrap();
+            outbox.flush();
+            // drop `Sender`-s to let thread no that it's
+            // work is finished and it can return.
+            drop(outbox.sender.take()?);
+            drop(self.queue.take());
+        }
         self.thread.take()?.join().ok()
     }
 }
 
+/// Side of the transfer sent to the `BankThread`, waiting for the decision.
+struct PreparedTransfer {
+    votes: crossbeam_channel::Receiver<Result<(), EngineError>>,
+    decide: crossbeam_channel::Sender<bool>,
+}
+
+impl PreparedTransfer {
+    /// **Blocks** untill thread checks whether it's side can be applied.
+    fn vote(&self) -> Result<(), EngineError> {
+        self.votes.recv().expect("bank thread is gone")
+    }
+
+    /// Lets thread apply it's side of the transfer or drop it.
+    fn decide(self, commit: bool) {
+        let _ = self.decide.send(commit);
+    }
+}
+
 impl Drop for BankThread {
     fn drop(&mut self) {
         let _ = self.join();
     }
 }
 
+/// Thread that sends batches, which waited long enough for more
+/// transactions, e.g. when input comes in slower than it's read.
+struct Flusher {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Flusher {
+    /// Checks batches of the `threads` every `after`, and sends
+    /// the ones, whose first transaction waited at least that long.
+    /// Batch of the thread, whose queue is full, waits for the next
+    /// check, so outbox is never locked while waiting for the thread.
+    fn spawn(threads: &[BankThread], after: Duration) -> Self {
+        let outboxes: Vec<_> = threads.iter()
+            .map(|bank_thread| bank_thread.outbox.clone())
+            .collect();
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let thread = thread::spawn(move || {
+            // runs untill `stop` is dropped.
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(after) {
+                for outbox in &outboxes {
+                    let mut outbox = outbox.lock().unwrap();
+                    if !outbox.batch.is_empty() && outbox.since.elapsed() >= after {
+                        outbox.try_flush();
+                    }
+                }
+            }
+        });
+        Self { stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Parts of the bank that are kept when threads are spawned again.
+struct Settings {
+    queue_capacity: usize,
+    batch_size: usize,
+    /// How long batch that isn't full waits, `None` if it waits
+    /// untill anything else is sent to the thread.
+    flush_after: Option<Duration>,
+    policy: Policy,
+    strategy: Box<dyn ShardStrategy>,
+    rebalancing: Option<Rebalancing>,
+    /// Threads of the clients that were moved by rebalancing,
+    /// instead of the ones `strategy` gave them.
+    moved: HashMap<ClientID, usize>,
+    /// Most errors waiting to be received, `None` if they aren't kept.
+    errors_capacity: Option<usize>,
+}
+
+impl Settings {
+    fn new(policy: Policy) -> Self {
+        Self {
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            batch_size: DEFAULT_BATCH_SIZE,
+            flush_after: None,
+            policy,
+            strategy: Box::new(Modulo),
+            rebalancing: None,
+            moved: HashMap::new(),
+            errors_capacity: None,
+        }
+    }
+
+    /// Thread of the client, same as [ConcurrentBank::thread_index].
+    fn shard(&mut self, client_id: ClientID, loads: &[usize]) -> usize {
+        match self.moved.get(&client_id) {
+            Some(&shard) => shard,
+            None => self.strategy.shard(client_id, loads),
+        }
+    }
+}
+
+/// Settings of the [ConcurrentBank], which it's threads are spawned
+/// with once they're all set, by [Builder::build].
+pub struct Builder {
+    count: usize,
+    settings: Settings,
+    source: Source,
+    /// Whether accounts have to be moved to the threads, that
+    /// `settings` give them, e.g. once the strategy is changed.
+    reshard: bool,
+    /// Directory and cache capacity of the threads' stores, if
+    /// their transactions are kept on disk.
+    disk_stores: Option<(PathBuf, usize)>,
+}
+
+/// Where accounts of the bank come from.
+enum Source {
+    Snapshot(Snapshot),
+    /// Banks of the stopped threads, see [ConcurrentBank::into_builder].
+    Banks {
+        banks: Vec<BasicBank>,
+        tx_index: TxIndex,
+        clock: Moment,
+    },
+}
+
+impl Builder {
+    /// Settings of the new empty bank, whose accounts follow `policy`,
+    /// with a thread for every cpu core.
+    pub fn new(policy: Policy) -> Self {
+        Self::from_snapshot(Snapshot::default(), policy)
+    }
+
+    /// Settings of the bank restored from the `snapshot`.
+    pub fn from_snapshot(snapshot: Snapshot, policy: Policy) -> Self {
+        Self {
+            count: num_cpus::get(),
+            settings: Settings::new(policy),
+            source: Source::Snapshot(snapshot),
+            reshard: false,
+            disk_stores: None,
+        }
+    }
+
+    pub fn with_thread_count(mut self, count: usize) -> Self {
+        self.reshard |= self.count != count;
+        self.count = count;
+        self
+    }
+
+    /// Distributes clients across threads with `strategy`,
+    /// instead of [Modulo]. Accounts that bank already has
+    /// are moved to the threads that `strategy` gives them.
+    pub fn with_shard_strategy(mut self, strategy: Box<dyn ShardStrategy>) -> Self {
+        self.settings.strategy = strategy;
+        self.settings.moved.clear();
+        self.reshard = true;
+        self
+    }
+
+    /// Measures how many transactions every thread gets, and after every
+    /// window of `rebalancing` moves clients from the thread that gets
+    /// much more than the others to the ones that get the least, e.g. when
+    /// one client makes most of the input. Busiest client of the thread
+    /// stays, so it ends up with a thread of it's own.
+    ///
+    /// `Account` is handed over by the thread when it gets to it, with
+    /// all it's transactions, and the thread it goes to waits for it, so
+    /// results are the same as without moving it.
+    pub fn with_rebalancing(mut self, rebalancing: Rebalancing) -> Self {
+        self.settings.rebalancing = Some(rebalancing);
+        self
+    }
+
+    /// Keeps transactions of the accounts on disk, in a file per thread
+    /// in `dir`, with at most `cache_capacity` of them in memory per thread.
+    /// Owners of the transactions, which are checked before they're sent
+    /// to threads, are kept there as well. See [BasicBank::with_disk_store].
+    pub fn with_disk_stores<P: AsRef<Path>>(mut self, dir: P, cache_capacity: usize) -> Self {
+        self.disk_stores = Some((dir.as_ref().to_owned(), cache_capacity));
+        self
+    }
+
+    /// Bounds queue of every thread to `capacity` messages, instead of
+    /// [DEFAULT_QUEUE_CAPACITY]. Once thread's queue is full, sending to
+    /// it **blocks** untill the thread catches up, so transactions don't
+    /// pile up in memory when input is read faster than they're applied.
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.settings.queue_capacity = capacity;
+        self
+    }
+
+    /// Sends transactions to threads in batches of up to `size`, instead of
+    /// one by one, which costs less for every transaction on large inputs.
+    /// Batch that isn't full is sent once it's first transaction waited
+    /// for `flush_after`, or anything else is sent to the same thread, e.g.
+    /// [Bank::accounts] is requested. Every batch takes one place of the
+    /// queue, see [Builder::with_queue_capacity].
+    ///
+    /// Errors of the transactions come through [ConcurrentBank::errors]
+    /// only after the batch is sent, so `flush_after` is also how much
+    /// later they can come.
+    pub fn with_batching(mut self, size: usize, flush_after: Duration) -> Self {
+        self.settings.batch_size = size;
+        self.settings.flush_after = Some(flush_after);
+        self
+    }
+
+    /// Keeps errors of the transactions applied by threads, so they can
+    /// be received from [ConcurrentBank::errors], at most `capacity` of
+    /// them at a time. Errors that come while it's full are dropped, so
+    /// they don't pile up in memory when they're not received.
+    ///
+    /// Without it, errors aren't kept at all.
+    pub fn with_errors(mut self, capacity: usize) -> Self {
+        self.settings.errors_capacity = Some(capacity);
+        self
+    }
+
+    /// Spawns threads of the bank with these settings.
+    pub fn build(self) -> io::Result<ConcurrentBank> {
+        let Self { count, mut settings, source, reshard, disk_stores } = self;
+        let (banks, tx_index, clock) = match source {
+            Source::Banks { banks, tx_index, clock } if !reshard => (banks, tx_index, clock),
+            Source::Banks { banks, tx_index, clock } => {
+                let tx_ids = tx_index.ids().clone();
+                let tx_owners = tx_index.owners()?;
+                let mut accounts = vec![];
+                for bank in banks {
+                    accounts.extend(bank.into_snapshot()?.into_accounts());
+                }
+                let snapshot = Snapshot::new(accounts, tx_ids, tx_owners, clock);
+                distribute(snapshot, count, &mut settings)
+            },
+            Source::Snapshot(snapshot) => distribute(snapshot, count, &mut settings),
+        };
+
+        let (banks, tx_index) = match disk_stores {
+            Some((dir, cache_capacity)) => {
+                let banks = banks.into_iter()
+                    .enumerate()
+                    .map(|(i, bank)| {
+                        let path = dir.join(format!("transactions-{}.bin", i));
+                        bank.with_disk_store(path, cache_capacity)
+                    })
+                    .collect::<io::Result<_>>()?;
+                (banks, tx_index.with_owners_on_disk(dir.join("owners.bin"))?)
+            },
+            None => (banks, tx_index),
+        };
+        Ok(ConcurrentBank::new_with_banks(banks, tx_index, clock, settings))
+    }
+}
+
+/// Distributes accounts of the `snapshot` across `count` banks,
+/// as the strategy of `settings` decides, unless they were moved.
+fn distribute(
+    snapshot: Snapshot,
+    count: usize,
+    settings: &mut Settings,
+) -> (Vec<BasicBank>, TxIndex, Moment) {
+    let clock = snapshot.clock();
+    let (accounts, tx_ids, tx_owners) = snapshot.into_parts();
+    let mut shards: Vec<_> = (0..count).map(|_| (vec![], vec![])).collect();
+    let loads = vec![0; count];
+
+    for account in accounts {
+        let shard = settings.shard(account.client_id(), &loads);
+        shards[shard].0.push(account);
+    }
+    for &(tx_id, client_id) in &tx_owners {
+        let shard = settings.shard(client_id, &loads);
+        shards[shard].1.push((tx_id, client_id));
+    }
+
+    // ids across all threads are checked by the router's index,
+    // so threads only need owners of their clients' transactions.
+    let policy = &settings.policy;
+    let banks = shards.into_iter()
+        .map(|(accounts, tx_owners)| {
+            let snapshot = Snapshot::new(accounts, IdSet::new(), tx_owners, clock);
+            BasicBank::from_snapshot(snapshot, policy.clone())
+        })
+        .collect();
+    let tx_index = TxIndex::from_parts(tx_ids, tx_owners, policy.clone());
+    (banks, tx_index, clock)
+}
+
 /// Stores and manages accounts in the bank **Concurrently**.
 ///
-/// It simply manages multiple subbanks each in it's own
-/// thread. Then based on hash of the `client_id`, `ConcurrentBank`
-/// decides to which subbank transaction should go to. This
-/// way each subbank has a **dedicated only to it** set of clients.
+/// It simply manages multiple subbanks each in it's own thread. Then
+/// based on the `client_id`, [ShardStrategy] decides to which subbank
+/// transaction should go to, [Modulo] by default. This way each
+/// subbank has a **dedicated only to it** set of clients.
+///
+/// Since transactions are applied asynchronously, errors of the
+/// `Account`-s can't be returned from `apply_tx`. Instead they can
+/// be received from [ConcurrentBank::errors], once asked for with
+/// [Builder::with_errors]. Only checks against the global
+/// transaction index are done before sending and returned directly.
+/// Threads give back ids of the transactions they refuse, so they can be
+/// retried with the same id. Transaction that's refused by the index is
+/// checked again, once threads apply everything sent before it, since
+/// id it conflicts with might be about to be given back.
+///
+/// Transfer between clients of different threads is applied with two phase
+/// commit: both threads check their side and wait untill both agree to apply
+/// it, so it's applied to both accounts or neither, at the same point in the
+/// input as in [BasicBank]. It's error is returned directly as well.
+///
+/// Queue of every thread is bounded, see [Builder::with_queue_capacity].
+/// When a thread falls behind, sending to it **blocks** untill it catches up,
+/// so input is read only as fast as the slowest thread goes.
+///
+/// With [Builder::with_rebalancing], clients that share the
+/// thread with a much busier one are moved to idle threads as they go.
 pub struct ConcurrentBank {
     threads: Vec<BankThread>,
+    /// Sends batches of the `threads` that waited too long, if batches are used.
+    flusher: Option<Flusher>,
     count: usize,
+    settings: Settings,
+    /// Queue depths of the threads, buffer for the `strategy`.
+    loads: Vec<usize>,
+    /// Transactions sent to threads since the last rebalancing.
+    throughput: Throughput,
+    /// Number of clients moved by rebalancing so far.
+    migrations: u64,
+    tx_index: TxIndex,
+    /// Ids of the transactions threads refused, to give back to the `tx_index`.
+    refused: crossbeam_channel::Receiver<TransactionID>,
+    /// Moment of the last transaction, numbering
+    /// of transactions is global across threads.
+    clock: Moment,
+    errors: crossbeam_channel::Receiver<EngineError>,
 }
 
 impl Default for ConcurrentBank {
@@ -68,23 +658,108 @@ impl Default for ConcurrentBank {
 
 impl Bank for ConcurrentBank {
     type AccountsIter = Box<dyn Iterator<Item = Account>>;
-    // TODO: propagate error from apply_tx.
-    /// Apply `Transaction` to the `Account` in `Bank`.
-    fn apply_tx<T: Into<Transaction>>(&mut self, tx: T) -> Result<(), ()> {
-        let tx: Transaction = tx.into();
-        let bank_thread = self.get_thread_for_client_mut(tx.get_client_id());
 
-        bank_thread.apply_tx(tx);
+    /// Create new empty bank, whose accounts follow `policy`.
+    fn new_with_policy(policy: Policy) -> Self {
+        Self::new_with_options(num_cpus::get(), policy)
+    }
+
+    fn from_snapshot(snapshot: Snapshot, policy: Policy) -> Self {
+        Self::from_snapshot_with_options(snapshot, num_cpus::get(), policy)
+    }
+
+    /// Consumes `ConcurrentBank` and **Blocks** untill all threads finish.
+    fn into_snapshot(mut self) -> io::Result<Snapshot> {
+        self.tick();
+        self.sync();
+        let tx_ids = self.tx_index.ids().clone();
+        let tx_owners = self.tx_index.owners()?;
+        let clock = self.clock;
+        let mut accounts = vec![];
+        for bank in self.into_inner_banks() {
+            accounts.extend(bank.into_snapshot()?.into_accounts());
+        }
+        Ok(Snapshot::new(accounts, tx_ids, tx_owners, clock))
+    }
+
+    /// Sends `Transaction` to the thread that manages it's `Account`.
+    /// Errors of applying it are reported via [ConcurrentBank::errors].
+    fn apply_tx_at(
+        &mut self,
+        tx: Transaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), EngineError> {
+        let at = self.next_moment(timestamp);
+        self.check(&tx)?;
+
+        if let Some(threads) = self.cross_thread_transfer(&tx) {
+            let result = self.transfer_across_threads(&tx, threads, at);
+            self.record_transfer(&tx, threads);
+            return result;
+        }
+
+        let client_id = tx.get_client_id();
+        let index = self.thread_index(client_id);
+        self.threads[index].apply_tx(tx, at, None);
+        self.record(client_id, index);
+        Ok(())
+    }
+
+    /// Sends `Transaction` to the thread that manages it's `Account`.
+    /// If it's refused, thread itself reports it to `rejects`.
+    fn apply_tx_or_reject(
+        &mut self,
+        tx: Transaction,
+        timestamp: Option<Timestamp>,
+        origin: Origin,
+        rejects: &Rejects,
+    ) -> Result<(), csv::Error> {
+        let at = self.next_moment(timestamp);
+        if let Err(err) = self.check(&tx) {
+            return rejects.report(Reject { origin, reason: err.into() });
+        }
+
+        if let Some(threads) = self.cross_thread_transfer(&tx) {
+            let result = self.transfer_across_threads(&tx, threads, at);
+            self.record_transfer(&tx, threads);
+            return match result {
+                Ok(_) => Ok(()),
+                Err(err) => rejects.report(Reject { origin, reason: err.into() }),
+            };
+        }
+
+        let client_id = tx.get_client_id();
+        let index = self.thread_index(client_id);
+        self.threads[index].apply_tx(tx, at, Some((origin, rejects.clone())));
+        self.record(client_id, index);
         Ok(())
     }
 
+    /// **Blocks** untill all transactions sent so far are applied.
+    fn accounts(&self) -> Vec<OutputAccount> {
+        self.tick();
+        // send all requests first, so that threads work on them in parallel.
+        let replies: Vec<_> = self.threads.iter()
+            .filter_map(BankThread::request_accounts)
+            .collect();
+
+        let mut accounts: Vec<_> = replies.into_iter()
+            .filter_map(|rx| rx.recv().ok())
+            .flatten()
+            .collect();
+        AccountOrder::ClientId.sort(&mut accounts);
+        accounts
+    }
+
     /// Consumes `ConcurrentBank` and **Blocks** untill all threads finish.
-    /// Outputs `Account` iterator.
+    /// Outputs `Account` iterator, ordered by client id.
     fn into_accounts_iter(self) -> Self::AccountsIter {
-        let iter = self.into_inner_banks()
-            .map(|bank| bank.into_accounts_iter())
-            .flatten();
-        Box::new(iter)
+        self.tick();
+        let mut accounts: Vec<_> = self.into_inner_banks()
+            .flat_map(|bank| bank.into_accounts_iter())
+            .collect();
+        accounts.sort_by_key(Account::client_id);
+        Box::new(accounts.into_iter())
     }
 }
 
@@ -95,27 +770,586 @@ impl ConcurrentBank {
     }
 
     /// Bank with custom thread count. `Default` is
-    /// [number of cpu cores](std::env::concurrency_hint)
+    /// number of cpu cores.
     pub fn new_with_thread_count(count: usize) -> Self {
-        Self {
+        Self::new_with_options(count, Policy::default())
+    }
+
+    /// Bank with custom thread count, whose accounts follow `policy`.
+    pub fn new_with_options(count: usize, policy: Policy) -> Self {
+        Self::from_snapshot_with_options(Snapshot::default(), count, policy)
+    }
+
+    /// Restores bank from the `snapshot`, with custom thread count,
+    /// whose accounts follow `policy`.
+    pub fn from_snapshot_with_options(
+        snapshot: Snapshot,
+        count: usize,
+        policy: Policy,
+    ) -> Self {
+        let mut settings = Settings::new(policy);
+        let (banks, tx_index, clock) = distribute(snapshot, count, &mut settings);
+        Self::new_with_banks(banks, tx_index, clock, settings)
+    }
+
+    /// Stops the threads, so that settings of the bank can
+    /// be changed, [Builder::build] spawns them again.
+    pub fn into_builder(mut self) -> Builder {
+        self.tick();
+        self.sync();
+        let (count, clock) = (self.count, self.clock);
+        let ConcurrentBank { threads, flusher, tx_index, settings, .. } = self;
+        // threads send what's left in their batches when joined.
+        drop(flusher);
+        let banks = threads.into_iter()
+            .map(|mut bank_thread| bank_thread.join().unwrap())
+            .collect();
+
+        Builder {
             count,
-            threads: (0..count)
-                .map(|_| BankThread::new()).collect(),
+            settings,
+            source: Source::Banks { banks, tx_index, clock },
+            reshard: false,
+            disk_stores: None,
+        }
+    }
+
+    /// Number of clients moved between threads by rebalancing so far.
+    pub fn migrations(&self) -> u64 {
+        self.migrations
+    }
+
+    /// Number of messages waiting in the queue of every thread, in
+    /// order of threads, batch counts as one. None is over the capacity.
+    pub fn queue_depths(&self) -> Vec<usize> {
+        self.threads.iter().map(BankThread::queue_depth).collect()
+    }
+
+    /// Spawns a thread for each of the `banks`.
+    fn new_with_banks(
+        banks: Vec<BasicBank>,
+        tx_index: TxIndex,
+        clock: Moment,
+        settings: Settings,
+    ) -> Self {
+        let (errors_tx, errors) = match settings.errors_capacity {
+            Some(capacity) => {
+                let (errors_tx, errors) = crossbeam_channel::bounded(capacity);
+                (Some(errors_tx), errors)
+            },
+            None => (None, crossbeam_channel::never()),
+        };
+        let (refused_tx, refused) = crossbeam_channel::unbounded();
+        let threads: Vec<_> = banks.into_iter()
+            .map(|bank| {
+                let (capacity, batch_size) = (settings.queue_capacity, settings.batch_size);
+                BankThread::new(bank, errors_tx.clone(), refused_tx.clone(), capacity, batch_size)
+            })
+            .collect();
+        let flusher = settings.flush_after
+            .filter(|_| settings.batch_size > 1)
+            .map(|after| Flusher::spawn(&threads, after));
+        Self {
+            count: threads.len(),
+            loads: vec![0; threads.len()],
+            threads,
+            flusher,
+            settings,
+            throughput: Throughput::default(),
+            migrations: 0,
+            tx_index,
+            refused,
+            clock,
+            errors,
+        }
+    }
+
+    /// Checks `tx` against the global index, after ids of the transactions
+    /// refused by threads so far are given back. If it's refused, it's checked
+    /// again, once threads apply everything sent before it.
+    fn check(&mut self, tx: &Transaction) -> Result<(), EngineError> {
+        self.unclaim_refused();
+        match self.tx_index.check(tx) {
+            Err(EngineError::DuplicateTx { .. }) | Err(EngineError::ClientMismatch { .. }) => {
+                self.sync();
+                self.tx_index.check(tx)
+            },
+            result => result,
+        }
+    }
+
+    /// **Blocks** untill all transactions sent so far are applied,
+    /// and gives back ids of the ones that were refused.
+    fn sync(&mut self) {
+        let replies: Vec<_> = self.threads.iter()
+            .filter_map(BankThread::request_sync)
+            .collect();
+        for rx in replies {
+            let _ = rx.recv();
+        }
+        self.unclaim_refused();
+    }
+
+    fn unclaim_refused(&mut self) {
+        for tx_id in self.refused.try_iter() {
+            self.tx_index.unclaim(tx_id);
+        }
+    }
+
+    /// Moment of the next transaction in the input.
+    fn next_moment(&mut self, timestamp: Option<Timestamp>) -> Moment {
+        let at = Moment { seq: self.clock.seq + 1, timestamp };
+        self.clock = self.clock.max(at);
+        at
+    }
+
+    /// Threads of the source and destination, if `tx` is a
+    /// transfer whose sides are managed by different threads.
+    fn cross_thread_transfer(&mut self, tx: &Transaction) -> Option<(usize, usize)> {
+        match tx {
+            Transaction::Transfer(transfer) => {
+                let source = self.thread_index(transfer.client_id);
+                let destination = self.thread_index(transfer.to);
+                Some((source, destination)).filter(|_| source != destination)
+            },
+            _ => None,
         }
     }
 
-    /// Get's a thread that stores account for the following client.
-    /// **Will** always return same value so only one thread/bank
-    /// manages same client.
-    fn get_thread_for_client_mut(
+    /// Applies transfer to both `threads` or neither of them.
+    /// **Blocks** untill both threads check their side of it.
+    fn transfer_across_threads(
         &mut self,
-        client_id: ClientID
-    ) -> &mut BankThread {
-        &mut self.threads[(client_id as usize) % self.count]
+        tx: &Transaction,
+        (source, destination): (usize, usize),
+        at: Moment,
+    ) -> Result<(), EngineError> {
+        let transfer = match tx {
+            Transaction::Transfer(transfer) => transfer,
+            _ => unreachable!("only transfers go across threads"),
+        };
+        let source = self.threads[source]
+            .prepare_transfer(transfer, transfer.client_id, at);
+        let destination = self.threads[destination]
+            .prepare_transfer(transfer, transfer.to, at);
+
+        // source's error comes first, same as in `BasicBank`.
+        let result = source.vote().and(destination.vote());
+        source.decide(result.is_ok());
+        destination.decide(result.is_ok());
+        if result.is_err() {
+            self.tx_index.unclaim(transfer.tx_id);
+        }
+        result
+    }
+
+    /// Counts both sides of the transfer that went across `threads`.
+    fn record_transfer(&mut self, tx: &Transaction, (source, destination): (usize, usize)) {
+        if let Transaction::Transfer(transfer) = tx {
+            self.record(transfer.client_id, source);
+            self.record(transfer.to, destination);
+        }
+    }
+
+    /// Lets every thread know the moment of the last transaction.
+    fn tick(&self) {
+        for bank_thread in &self.threads {
+            bank_thread.tick(self.clock);
+        }
+    }
+
+    /// Errors of transactions that failed so far. Doesn't block,
+    /// only yields errors that are already reported by threads.
+    /// Always empty without [Builder::with_errors].
+    pub fn errors(&self) -> impl Iterator<Item = EngineError> + '_ {
+        self.errors.try_iter()
+    }
+
+    /// Index of the thread that stores account for the following client,
+    /// as the strategy decides it, or where rebalancing moved it to.
+    /// Only one thread/bank manages same client at a time.
+    fn thread_index(&mut self, client_id: ClientID) -> usize {
+        if let Some(&index) = self.settings.moved.get(&client_id) {
+            return index;
+        }
+        if self.settings.strategy.needs_loads(client_id) {
+            for (load, bank_thread) in self.loads.iter_mut().zip(&self.threads) {
+                *load = bank_thread.queue_depth();
+            }
+        }
+        self.settings.strategy.shard(client_id, &self.loads)
+    }
+
+    /// Counts transaction of `client_id` sent to thread `index`,
+    /// and rebalances threads once the window is over.
+    fn record(&mut self, client_id: ClientID, index: usize) {
+        let rebalancing = match self.settings.rebalancing {
+            Some(rebalancing) => rebalancing,
+            None => return,
+        };
+        self.throughput.record(client_id, index, self.count);
+        if self.throughput.seen() >= rebalancing.window {
+            for Move { client_id, from, to } in self.throughput.plan(&rebalancing) {
+                // next transactions of the client are sent after
+                // the account, so they're applied to it in order.
+                self.threads[from].move_account(client_id, &self.threads[to]);
+                self.settings.moved.insert(client_id, to);
+                self.migrations += 1;
+            }
+        }
     }
 
     fn into_inner_banks(self) -> impl Iterator<Item = BasicBank> {
+        // threads send what's left in their batches when joined.
+        drop(self.flusher);
         self.threads.into_iter()
             .map(|mut bank_thread| bank_thread.join().unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Amount;
+    use crate::transaction::{TransactionInfo, AccountAction};
+    use crate::shard::Sharding;
+
+    fn builder(count: usize) -> Builder {
+        Builder::new(Policy::default()).with_thread_count(count)
+    }
+
+    #[test]
+    fn errors_reported_from_threads() {
+        let mut bank = builder(2).with_errors(16).build().unwrap();
+
+        assert!(bank.apply_tx(Transaction::Withdrawal(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: Amount::new(1, 0),
+            currency: Default::default(),
+        })).is_ok());
+
+        // wait for threads to finish.
+        let errors = bank.errors.clone();
+        bank.into_inner_banks().for_each(drop);
+
+        assert_eq!(
+            errors.try_iter().collect::<Vec<_>>(),
+            vec![EngineError::InsufficientFunds {
+                client_id: 1,
+                tx_id: 1,
+                needed: Amount::new(1, 0),
+                available: Default::default(),
+            }],
+        );
+    }
+
+    #[test]
+    fn errors_kept_only_when_asked() {
+        let withdrawal = |tx_id| Transaction::Withdrawal(
+            TransactionInfo::new(1, tx_id, Amount::new(1, 0)),
+        );
+
+        let mut bank = ConcurrentBank::new_with_thread_count(2);
+        (1..=3).for_each(|tx_id| bank.apply_tx(withdrawal(tx_id)).unwrap());
+        bank.accounts();
+        assert_eq!(bank.errors().count(), 0);
+
+        // ones that don't fit are dropped.
+        let mut bank = bank.into_builder().with_errors(2).build().unwrap();
+        (4..=6).for_each(|tx_id| bank.apply_tx(withdrawal(tx_id)).unwrap());
+        bank.accounts();
+        let tx_ids: Vec<_> = bank.errors().map(|err| err.tx_id()).collect();
+        assert_eq!(tx_ids, vec![4, 5]);
+    }
+
+    #[test]
+    fn accounts_without_consuming() {
+        let mut bank = ConcurrentBank::new_with_thread_count(2);
+
+        for client_id in 1..=3 {
+            assert!(bank.apply_tx(Transaction::Deposit(
+                TransactionInfo::new(client_id, client_id as u32, Amount::new(1, 0)),
+            )).is_ok());
+        }
+
+        let accounts = bank.accounts();
+        assert_eq!(
+            accounts.iter().map(|account| account.client_id).collect::<Vec<_>>(),
+            vec![1, 2, 3],
+        );
+        assert!(accounts.iter().all(|account| account.available == Amount::new(1, 0)));
+
+        // bank is still usable.
+        assert!(bank.apply_tx(Transaction::Deposit(
+            TransactionInfo::new(4, 4, Amount::new(1, 0)),
+        )).is_ok());
+        assert_eq!(bank.accounts().len(), 4);
+    }
+
+    #[test]
+    fn duplicate_across_threads() {
+        let mut bank = ConcurrentBank::new_with_thread_count(2);
+
+        assert!(bank.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 1,
+            tx_id: 1,
+            amount: Default::default(),
+            currency: Default::default(),
+        })).is_ok());
+        assert_eq!(bank.apply_tx(Transaction::Deposit(TransactionInfo {
+            client_id: 2,
+            tx_id: 1,
+            amount: Default::default(),
+            currency: Default::default(),
+        })), Err(EngineError::DuplicateTx { client_id: 2, tx_id: 1 }));
+    }
+
+    /// Keeps thread `index` of the `bank` waiting for an account,
+    /// untill returned sender is dropped.
+    #[test]
+    fn refused_deposit_retried_with_same_id() {
+        let mut bank = ConcurrentBank::new_with_thread_count(2);
+        let deposit = |client_id, tx_id| {
+            Transaction::Deposit(TransactionInfo::new(client_id, tx_id, Amount::new(5, 0)))
+        };
+        let lock = Transaction::Lock(AccountAction::new(1, 1, None));
+
+        // refused by the thread of client 1, after router took the id.
+        bank.apply_tx(lock.clone()).unwrap();
+        assert!(bank.apply_tx(deposit(1, 5)).is_ok());
+        bank.apply_tx(Transaction::Unlock(AccountAction::new(1, 2, None))).unwrap();
+        assert!(bank.apply_tx(deposit(1, 5)).is_ok());
+        assert_eq!(
+            bank.apply_tx(deposit(2, 5)),
+            Err(EngineError::DuplicateTx { client_id: 2, tx_id: 5 }),
+        );
+
+        // another client on another thread can take the id as well.
+        bank.apply_tx(lock).unwrap();
+        assert!(bank.apply_tx(deposit(1, 6)).is_ok());
+        assert!(bank.apply_tx(deposit(2, 6)).is_ok());
+        assert!(bank.apply_tx(deposit(1, 7)).is_ok());
+
+        let balances: Vec<_> = bank.accounts().iter()
+            .map(|account| (account.client_id, account.available))
+            .collect();
+        assert_eq!(balances, vec![(1, Amount::new(5, 0)), (2, Amount::new(5, 0))]);
+
+        // refused ids aren't kept in the snapshot.
+        let snapshot = bank.into_snapshot().unwrap();
+        let mut bank = ConcurrentBank::from_snapshot_with_options(snapshot, 2, Policy::default());
+        assert!(bank.apply_tx(deposit(2, 7)).is_ok());
+        assert_eq!(
+            bank.apply_tx(deposit(2, 6)),
+            Err(EngineError::DuplicateTx { client_id: 2, tx_id: 6 }),
+        );
+    }
+
+    /// Holds thread `index` with a transfer, untill returned transfer is
+    /// dropped. It's voted on once it's taken from the queue, so the queue
+    /// is empty after that.
+    fn stall(bank: &ConcurrentBank, index: usize) -> PreparedTransfer {
+        let transfer = TransferInfo::new(1000, 1001, 0, Amount::new(1, 0));
+        let hold = bank.threads[index].prepare_transfer(&transfer, 1000, bank.clock);
+        // refused, since there's nothing to transfer, but it's never applied anyway.
+        let _ = hold.vote();
+        hold
+    }
+
+    #[test]
+    fn backpressure_with_skewed_clients() {
+        let capacity = 8;
+        let mut bank = builder(4).with_queue_capacity(capacity).build().unwrap();
+        let mut basic = BasicBank::new();
+
+        // 9 of 10 transactions are for the client of the first thread.
+        let txs: Vec<_> = (0..40_000u32)
+            .map(|tx_id| {
+                let client_id = if tx_id % 10 == 0 { (tx_id % 97) as ClientID } else { 4 };
+                Transaction::Deposit(TransactionInfo::new(client_id, tx_id, Amount::new(1, 2)))
+            })
+            .collect();
+        txs.iter().cloned().for_each(|tx| { let _ = basic.apply_tx(tx); });
+        // first one, that doesn't fit the queue of the first thread.
+        let blocked = txs.iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.get_client_id() % 4 == 0)
+            .nth(capacity)
+            .map(|(index, _)| index)
+            .unwrap();
+
+        let hold = stall(&bank, 0);
+        let (sent, sent_count) = crossbeam_channel::unbounded();
+        let applying = thread::spawn(move || {
+            for tx in txs {
+                assert!(bank.apply_tx(tx).is_ok());
+                let _ = sent.send(());
+            }
+            bank
+        });
+
+        // sending blocks while stalled thread's queue is full.
+        for _ in 0..blocked {
+            assert!(sent_count.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+        assert!(sent_count.recv_timeout(Duration::from_millis(50)).is_err());
+
+        drop(hold);
+        let bank = applying.join().unwrap();
+        assert_eq!(sent_count.len(), 40_000 - blocked);
+        assert_eq!(bank.accounts(), basic.accounts());
+        assert_eq!(bank.queue_depths(), vec![0; 4]);
+    }
+
+    #[test]
+    fn full_queue_doesnt_hold_other_threads() {
+        let mut bank = builder(2)
+            .with_queue_capacity(1)
+            .with_batching(10, Duration::from_millis(1))
+            .build()
+            .unwrap();
+        let deposit = |client_id, tx_id| Transaction::Deposit(
+            TransactionInfo::new(client_id, tx_id, Amount::new(1, 0)),
+        );
+
+        // first thread's queue is full and a batch waits for the flusher to send it.
+        let hold = stall(&bank, 0);
+        bank.threads[0].tick(bank.clock);
+        assert_eq!(bank.queue_depths()[0], 1);
+        bank.apply_tx(deposit(0, 1)).unwrap();
+
+        let (done, finished) = crossbeam_channel::bounded(1);
+        let applying = thread::spawn(move || {
+            for tx_id in 100..200 {
+                bank.apply_tx(deposit(1, tx_id)).unwrap();
+            }
+            let _ = done.send(());
+            bank
+        });
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        drop(hold);
+        let bank = applying.join().unwrap();
+        let available: Vec<_> = bank.accounts().iter().map(|account| account.available).collect();
+        assert_eq!(available, vec![Amount::new(1, 0), Amount::new(100, 0)]);
+    }
+
+    #[test]
+    fn same_accounts_with_every_sharding() {
+        // ids that all land on one thread with `Modulo`.
+        let txs: Vec<_> = (0..2000u32)
+            .map(|tx_id| {
+                let client_id = (tx_id % 50) as ClientID * 8 + 1;
+                if tx_id % 3 == 2 {
+                    let amount = Amount::new(5, 1);
+                    Transaction::Transfer(TransferInfo::new(client_id, client_id + 8, tx_id, amount))
+                } else {
+                    Transaction::Deposit(TransactionInfo::new(client_id, tx_id, Amount::new(1, 0)))
+                }
+            })
+            .collect();
+
+        let mut basic = BasicBank::new();
+        txs.iter().cloned().for_each(|tx| { let _ = basic.apply_tx(tx); });
+
+        for sharding in &[Sharding::Modulo, Sharding::Hashed, Sharding::LoadAware] {
+            let mut bank = ConcurrentBank::new_with_thread_count(4);
+            let (first, second) = txs.split_at(1000);
+            first.iter().cloned().for_each(|tx| { let _ = bank.apply_tx(tx); });
+            // accounts move to the threads of the new strategy.
+            bank = bank.into_builder().with_shard_strategy(sharding.strategy()).build().unwrap();
+            second.iter().cloned().for_each(|tx| { let _ = bank.apply_tx(tx); });
+
+            assert_eq!(bank.accounts(), basic.accounts(), "{:?}", sharding);
+        }
+    }
+
+    #[test]
+    fn rebalancing_keeps_results() {
+        use crate::transaction::TransactionRef;
+
+        // client 4 makes most of the input, others share it's thread.
+        let txs: Vec<_> = (0..20_000u32)
+            .map(|tx_id| {
+                let client_id = if tx_id % 2 == 0 { 4 } else { (tx_id % 10) as ClientID * 4 };
+                match tx_id % 7 {
+                    // disputes transactions from before the client could be moved.
+                    3 if tx_id > 100 => Transaction::Dispute(TransactionRef::new(client_id, tx_id - 100)),
+                    5 => {
+                        let amount = Amount::new(3, 1);
+                        Transaction::Transfer(TransferInfo::new(client_id, client_id + 1, tx_id, amount))
+                    },
+                    _ => Transaction::Deposit(TransactionInfo::new(client_id, tx_id, Amount::new(1, 0))),
+                }
+            })
+            .collect();
+
+        let mut basic = BasicBank::new();
+        txs.iter().cloned().for_each(|tx| { let _ = basic.apply_tx(tx); });
+
+        let rebalancing = Rebalancing { window: 500, ..Rebalancing::default() };
+        let mut bank = builder(4)
+            .with_queue_capacity(16)
+            .with_rebalancing(rebalancing)
+            .build()
+            .unwrap();
+        let (first, second) = txs.split_at(10_000);
+        first.iter().cloned().for_each(|tx| { let _ = bank.apply_tx(tx); });
+        assert!(bank.migrations() > 0);
+        // moved clients stay on their threads, once they're spawned again.
+        bank = bank.into_builder().with_queue_capacity(32).build().unwrap();
+        second.iter().cloned().for_each(|tx| { let _ = bank.apply_tx(tx); });
+
+        assert_eq!(bank.accounts(), basic.accounts());
+    }
+
+    #[test]
+    fn batches_keep_results() {
+        let txs: Vec<_> = (0..5000u32)
+            .map(|tx_id| {
+                let client_id = (tx_id % 13) as ClientID;
+                if tx_id % 5 == 4 {
+                    let amount = Amount::new(7, 1);
+                    Transaction::Transfer(TransferInfo::new(client_id, client_id + 3, tx_id, amount))
+                } else {
+                    Transaction::Deposit(TransactionInfo::new(client_id, tx_id, Amount::new(1, 0)))
+                }
+            })
+            .collect();
+
+        let mut basic = BasicBank::new();
+        txs.iter().cloned().for_each(|tx| { let _ = basic.apply_tx(tx); });
+
+        let rebalancing = Rebalancing { window: 300, ..Rebalancing::default() };
+        let mut bank = builder(3)
+            .with_queue_capacity(4)
+            .with_batching(64, Duration::from_millis(5))
+            .with_rebalancing(rebalancing)
+            .build()
+            .unwrap();
+        let (first, second) = txs.split_at(2500);
+        first.iter().cloned().for_each(|tx| { let _ = bank.apply_tx(tx); });
+        // batches are sent before the accounts are requested.
+        assert_eq!(bank.accounts().len(), 16);
+        second.iter().cloned().for_each(|tx| { let _ = bank.apply_tx(tx); });
+
+        assert_eq!(bank.accounts(), basic.accounts());
+    }
+
+    #[test]
+    fn batch_flushed_when_idle() {
+        let mut bank = builder(2)
+            .with_batching(1000, Duration::from_millis(10))
+            .with_errors(16)
+            .build()
+            .unwrap();
+        assert!(bank.apply_tx(Transaction::Withdrawal(
+            TransactionInfo::new(1, 1, Amount::new(1, 0)),
+        )).is_ok());
+
+        // nothing else is sent, batch goes on it's own.
+        let error = bank.errors.recv_timeout(Duration::from_secs(5));
+        assert!(matches!(error, Ok(EngineError::InsufficientFunds { client_id: 1, .. })));
+    }
+}
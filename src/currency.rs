@@ -0,0 +1,89 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+
+/// Max length of the currency code.
+const MAX_LEN: usize = 8;
+
+/// Currency code, e.g. `USD` or `BTC`. Up to 8 ascii letters
+/// or digits, kept uppercase.
+///
+/// `Default` is the unnamed currency, of transactions that come
+/// without one, so single currency inputs don't need the column.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; MAX_LEN]);
+
+impl Currency {
+    /// Whether it's the unnamed currency.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(MAX_LEN);
+        // only ascii is ever stored.
+        std::str::from_utf8(&self.0[..len]).unwrap()
+    }
+
+    /// Fixed size representation, all zeros for the default currency.
+    pub(crate) fn to_bytes(self) -> [u8; MAX_LEN] {
+        self.0
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; MAX_LEN]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() > MAX_LEN || !s.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(format!("invalid currency: {:?}", s));
+        }
+
+        let mut code = [0; MAX_LEN];
+        for (i, b) in s.bytes().enumerate() {
+            code[i] = b.to_ascii_uppercase();
+        }
+        Ok(Self(code))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Empty string is the default currency.
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_currency() {
+        let usd: Currency = "usd".parse().unwrap();
+        assert_eq!(usd.as_str(), "USD");
+        assert_eq!(usd, "USD".parse().unwrap());
+        assert!("".parse::<Currency>().unwrap().is_default());
+        assert!("TOOLONGCODE".parse::<Currency>().is_err());
+        assert!("U$D".parse::<Currency>().is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use serde::Serializer;
 use rust_decimal::{Decimal, RoundingStrategy};
 
+/// Decimal places of the amounts in the output.
+pub const PRECISION: u32 = 4;
+
 /// Serializes decimal with rounded down precision 4,
 /// as requested from spec document.
 pub fn serialize<S>(
@@ -11,8 +14,22 @@ where
     S: Serializer,
 {
     let num_str = num
-        .round_dp_with_strategy(4, RoundingStrategy::RoundDown)
+        .round_dp_with_strategy(PRECISION, RoundingStrategy::RoundDown)
         .normalize()
         .to_string();
     serializer.serialize_str(&num_str)
 }
+
+/// Same as [serialize], for optional decimal.
+pub fn serialize_option<S>(
+    num: &Option<Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match num {
+        Some(num) => serialize(num, serializer),
+        None => serializer.serialize_none(),
+    }
+}
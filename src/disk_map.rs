@@ -0,0 +1,280 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::types::TransactionID;
+
+/// Size of the entry in the file: id and value.
+const ENTRY_LEN: usize = 4 + 4;
+/// Entries read at once, while looking for an id.
+const PROBE_LEN: usize = 16;
+/// Least number of entries in the file, as a power of two.
+const MIN_BITS: u32 = 10;
+
+/// Map of transaction ids to `u32` values, kept in a file, so only
+/// as much memory is used as the OS caches of it.
+///
+/// File is a hash table with linear probing, which is at most half full,
+/// so it's size grows with the number of entries, not with the ids, and
+/// doubles when it's needed. File is only a scratch space, it's removed
+/// when the map is dropped.
+#[derive(Debug)]
+pub(crate) struct DiskMap {
+    path: PathBuf,
+    file: File,
+    /// Number of entries the file has place for is `2^bits`.
+    bits: u32,
+    /// Number of entries in the map.
+    len: u64,
+}
+
+impl DiskMap {
+    /// Creates map in a new file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_bits(path.as_ref().to_owned(), MIN_BITS)
+    }
+
+    fn with_bits(path: PathBuf, bits: u32) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        // empty entries are all zeros, which file is filled with.
+        file.set_len((1 << bits) * ENTRY_LEN as u64)?;
+        Ok(Self { path, file, bits, len: 0 })
+    }
+
+    pub fn get(&mut self, id: TransactionID) -> io::Result<Option<u32>> {
+        Ok(self.find(id)?.1)
+    }
+
+    /// Sets value of `id`, replacing the one it had. Value
+    /// can be anything, but `u32::MAX`.
+    pub fn insert(&mut self, id: TransactionID, value: u32) -> io::Result<()> {
+        if value == u32::MAX {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "value is out of range"));
+        }
+
+        let (mut pos, old) = self.find(id)?;
+        if old.is_none() {
+            if (self.len + 1) * 2 > self.capacity() {
+                self.grow()?;
+                pos = self.find(id)?.0;
+            }
+            self.len += 1;
+        }
+        self.file.seek(SeekFrom::Start(pos * ENTRY_LEN as u64))?;
+        self.file.write_all(&encode(id, value))
+    }
+
+    /// All entries, in no particular order.
+    pub fn iter(&self) -> io::Result<impl Iterator<Item = io::Result<(TransactionID, u32)>> + '_> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = io::BufReader::new(file);
+        let mut data = [0; ENTRY_LEN];
+
+        Ok((0..self.capacity()).filter_map(move |_| {
+            match reader.read_exact(&mut data) {
+                Ok(()) => decode(&data).map(Ok),
+                Err(err) => Some(Err(err)),
+            }
+        }))
+    }
+
+    fn capacity(&self) -> u64 {
+        1 << self.bits
+    }
+
+    /// Position of the entry of `id` and it's value, or
+    /// position of the empty entry, where it would be.
+    fn find(&mut self, id: TransactionID) -> io::Result<(u64, Option<u32>)> {
+        let mask = self.capacity() - 1;
+        let mut pos = hash(id, self.bits);
+        let mut data = [0; PROBE_LEN * ENTRY_LEN];
+
+        // there's always an empty entry, since map is at most half full.
+        loop {
+            let count = PROBE_LEN.min((self.capacity() - pos) as usize);
+            let data = &mut data[..count * ENTRY_LEN];
+            self.file.seek(SeekFrom::Start(pos * ENTRY_LEN as u64))?;
+            self.file.read_exact(data)?;
+
+            for entry in data.chunks_exact(ENTRY_LEN) {
+                match decode(entry) {
+                    Some((found, value)) if found == id => return Ok((pos, Some(value))),
+                    Some(_) => pos = (pos + 1) & mask,
+                    None => return Ok((pos, None)),
+                }
+            }
+        }
+    }
+
+    /// Moves entries to a file twice as big.
+    ///
+    /// Twice as big table gives every id one of the two positions in
+    /// place of the one it had, so in order of the positions entries
+    /// are read in, they're written to the new file in one pass. Only
+    /// those that wrap around the end of the file are inserted after.
+    fn grow(&mut self) -> io::Result<()> {
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut grown = Self::with_bits(tmp_path.into(), self.bits + 1)?;
+        let mut writer = Writer::new(&grown.file, grown.capacity());
+        let mut pending = BinaryHeap::new();
+        let mut wrapped = vec![];
+
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = io::BufReader::new(file);
+        let mut data = [0; ENTRY_LEN];
+        for pos in 0..self.capacity() {
+            reader.read_exact(&mut data)?;
+            match decode(&data) {
+                // wrapped around from the end, rest are only there.
+                Some((id, value)) if hash(id, self.bits) > pos => wrapped.push((id, value)),
+                Some((id, value)) => pending.push(Reverse((hash(id, grown.bits), id, value))),
+                // entries after the empty one are all further than the pending ones.
+                None => writer.write_pending(&mut pending, &mut wrapped)?,
+            }
+        }
+        writer.write_pending(&mut pending, &mut wrapped)?;
+        writer.finish()?;
+
+        grown.len = self.len - wrapped.len() as u64;
+        for (id, value) in wrapped {
+            grown.insert(id, value)?;
+        }
+        fs::rename(&grown.path, &self.path)?;
+        // old file is dropped with `grown`, which path is already gone.
+        std::mem::swap(&mut self.file, &mut grown.file);
+        self.bits = grown.bits;
+        Ok(())
+    }
+}
+
+/// Writes entries to the new file in order of their positions.
+struct Writer<'a> {
+    writer: io::BufWriter<&'a File>,
+    /// Position the next entry is written at.
+    pos: u64,
+    capacity: u64,
+}
+
+impl<'a> Writer<'a> {
+    fn new(file: &'a File, capacity: u64) -> Self {
+        Self { writer: io::BufWriter::new(file), pos: 0, capacity }
+    }
+
+    /// Writes `pending` entries at their positions, or right after the
+    /// previous one, if it's taken. Ones that don't fit before the end
+    /// of the file are moved to `wrapped`.
+    fn write_pending(
+        &mut self,
+        pending: &mut BinaryHeap<Reverse<(u64, TransactionID, u32)>>,
+        wrapped: &mut Vec<(TransactionID, u32)>,
+    ) -> io::Result<()> {
+        while let Some(Reverse((home, id, value))) = pending.pop() {
+            if self.pos >= self.capacity {
+                wrapped.push((id, value));
+                continue;
+            }
+            while self.pos < home {
+                self.writer.write_all(&[0; ENTRY_LEN])?;
+                self.pos += 1;
+            }
+            self.writer.write_all(&encode(id, value))?;
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Rest of the file is already empty.
+    fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for DiskMap {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Spreads sequential ids across the file.
+fn hash(id: TransactionID, bits: u32) -> u64 {
+    (id as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - bits)
+}
+
+/// Value is kept one higher, so that empty entry is all zeros.
+fn encode(id: TransactionID, value: u32) -> [u8; ENTRY_LEN] {
+    let mut data = [0; ENTRY_LEN];
+    data[..4].copy_from_slice(&id.to_le_bytes());
+    data[4..].copy_from_slice(&(value + 1).to_le_bytes());
+    data
+}
+
+fn decode(data: &[u8]) -> Option<(TransactionID, u32)> {
+    let mut id = [0; 4];
+    let mut value = [0; 4];
+    id.copy_from_slice(&data[..4]);
+    value.copy_from_slice(&data[4..ENTRY_LEN]);
+    match u32::from_le_bytes(value) {
+        0 => None,
+        value => Some((TransactionID::from_le_bytes(id), value - 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_with_entries_not_ids() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-disk-map", std::process::id()));
+        let mut map = DiskMap::create(&path).unwrap();
+
+        let ids: Vec<_> = (0..5000).map(|i| i * 7).chain(Some(u32::MAX)).collect();
+        for (i, &id) in ids.iter().enumerate() {
+            map.insert(id, i as u32).unwrap();
+        }
+        map.insert(7, 70).unwrap();
+
+        assert_eq!(map.get(7).unwrap(), Some(70));
+        assert_eq!(map.get(14).unwrap(), Some(2));
+        assert_eq!(map.get(u32::MAX).unwrap(), Some(5000));
+        assert_eq!(map.get(8).unwrap(), None);
+        assert_eq!(map.iter().unwrap().count(), 5001);
+        // doubled from 1024 entries untill it's at most half full.
+        assert_eq!(fs::metadata(&path).unwrap().len(), 16384 * ENTRY_LEN as u64);
+        assert!(map.insert(1, u32::MAX).is_err());
+
+        drop(map);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn finds_every_entry_after_growing() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-disk-map-grow", std::process::id()));
+        let mut map = DiskMap::create(&path).unwrap();
+
+        // ids that all belong at the end of the file, so they
+        // wrap around it, before and after it grows.
+        let last = (1 << MIN_BITS) - 1;
+        let mut ids: Vec<u32> = (0..).filter(|&id| hash(id, MIN_BITS) == last).take(8).collect();
+        ids.extend((0..10_000u32).map(|i| i.wrapping_mul(2_654_435_761)));
+        for (i, &id) in ids.iter().enumerate() {
+            map.insert(id, i as u32).unwrap();
+        }
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(map.get(id).unwrap(), Some(i as u32));
+        }
+        assert_eq!(map.iter().unwrap().count(), ids.len());
+    }
+}
@@ -0,0 +1,311 @@
+use std::fmt;
+
+use crate::types::{ClientID, TransactionID, Amount};
+use crate::currency::Currency;
+
+/// Reasons why the engine refused to parse or apply a transaction.
+///
+/// Every variant carries ids of the client and transaction that
+/// caused it, so callers can branch on the reason and still know
+/// which input it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum EngineError {
+    /// Transaction type isn't one of the supported ones.
+    UnknownType {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        tx_type: String,
+    },
+    /// Deposit, withdrawal or transfer came without an amount.
+    MissingAmount { client_id: ClientID, tx_id: TransactionID },
+    /// Adjustment came without a reason.
+    MissingReason { client_id: ClientID, tx_id: TransactionID },
+    /// Transfer came without the destination client.
+    MissingDestination { client_id: ClientID, tx_id: TransactionID },
+    /// Transfer's destination is the same as it's source.
+    SelfTransfer { client_id: ClientID, tx_id: TransactionID },
+    /// Conversion came without the target currency.
+    MissingTargetCurrency { client_id: ClientID, tx_id: TransactionID },
+    /// Conversion's target currency is the same as it's source.
+    SameCurrency { client_id: ClientID, tx_id: TransactionID },
+    /// There's no rate in the rate table for the conversion.
+    RateNotFound {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        from: Currency,
+        to: Currency,
+    },
+    /// Conversion credits nothing, e.g. amount is so small that
+    /// it's rounded to zero.
+    NothingCredited { client_id: ClientID, tx_id: TransactionID },
+    /// Amount of the transaction is negative.
+    NegativeAmount {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        amount: Amount,
+    },
+    /// Amount of the reference to the transaction is zero, so it
+    /// would move no funds.
+    ZeroAmount { client_id: ClientID, tx_id: TransactionID },
+    /// Account is locked/frozen, no transactions can be applied.
+    AccountLocked { client_id: ClientID, tx_id: TransactionID },
+    /// Account isn't locked, so it can't be unlocked.
+    AccountNotLocked { client_id: ClientID, tx_id: TransactionID },
+    /// Transaction with the same id was already applied.
+    DuplicateTx { client_id: ClientID, tx_id: TransactionID },
+    /// Not enough available funds to apply the transaction.
+    InsufficientFunds {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        needed: Amount,
+        available: Amount,
+    },
+    /// Referenced transaction belongs to another client.
+    ClientMismatch {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        owner: ClientID,
+    },
+    /// Referenced transaction doesn't exist.
+    TxNotFound { client_id: ClientID, tx_id: TransactionID },
+    /// Referenced transaction is already under dispute.
+    AlreadyUnderDispute { client_id: ClientID, tx_id: TransactionID },
+    /// Referenced transaction isn't under dispute.
+    NotUnderDispute { client_id: ClientID, tx_id: TransactionID },
+    /// Referenced transaction can't be disputed.
+    NotDisputable { client_id: ClientID, tx_id: TransactionID },
+    /// Referenced transaction is too old to be disputed.
+    DisputeWindowExpired { client_id: ClientID, tx_id: TransactionID },
+    /// Disputed amount exceeds undisputed part of the referenced transaction.
+    DisputeExceedsTx {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        requested: Amount,
+        undisputed: Amount,
+    },
+    /// Resolved or charged back amount exceeds disputed part
+    /// of the referenced transaction.
+    ExceedsDisputed {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        requested: Amount,
+        disputed: Amount,
+    },
+    /// Referenced transaction isn't an authorization
+    /// with reserved funds left.
+    NotAuthorized { client_id: ClientID, tx_id: TransactionID },
+    /// Captured or voided amount exceeds reserved
+    /// part of the referenced authorization.
+    ExceedsReserved {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        requested: Amount,
+        reserved: Amount,
+    },
+    /// Transaction couldn't be written to the write-ahead log,
+    /// so it wasn't applied.
+    WalWrite {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        message: String,
+    },
+    /// Account's transaction store failed to read or write.
+    Storage {
+        client_id: ClientID,
+        tx_id: TransactionID,
+        message: String,
+    },
+}
+
+impl EngineError {
+    /// Id of the client that transaction belongs to.
+    pub fn client_id(&self) -> ClientID {
+        match self {
+            Self::UnknownType { client_id, .. }
+            | Self::MissingAmount { client_id, .. }
+            | Self::MissingReason { client_id, .. }
+            | Self::MissingDestination { client_id, .. }
+            | Self::SelfTransfer { client_id, .. }
+            | Self::MissingTargetCurrency { client_id, .. }
+            | Self::SameCurrency { client_id, .. }
+            | Self::RateNotFound { client_id, .. }
+            | Self::NothingCredited { client_id, .. }
+            | Self::NegativeAmount { client_id, .. }
+            | Self::ZeroAmount { client_id, .. }
+            | Self::AccountLocked { client_id, .. }
+            | Self::AccountNotLocked { client_id, .. }
+            | Self::DuplicateTx { client_id, .. }
+            | Self::InsufficientFunds { client_id, .. }
+            | Self::ClientMismatch { client_id, .. }
+            | Self::TxNotFound { client_id, .. }
+            | Self::AlreadyUnderDispute { client_id, .. }
+            | Self::NotUnderDispute { client_id, .. }
+            | Self::NotDisputable { client_id, .. }
+            | Self::DisputeWindowExpired { client_id, .. }
+            | Self::DisputeExceedsTx { client_id, .. }
+            | Self::ExceedsDisputed { client_id, .. }
+            | Self::NotAuthorized { client_id, .. }
+            | Self::ExceedsReserved { client_id, .. }
+            | Self::WalWrite { client_id, .. }
+            | Self::Storage { client_id, .. } => *client_id,
+        }
+    }
+
+    /// Id of the transaction that caused the error.
+    pub fn tx_id(&self) -> TransactionID {
+        match self {
+            Self::UnknownType { tx_id, .. }
+            | Self::MissingAmount { tx_id, .. }
+            | Self::MissingReason { tx_id, .. }
+            | Self::MissingDestination { tx_id, .. }
+            | Self::SelfTransfer { tx_id, .. }
+            | Self::MissingTargetCurrency { tx_id, .. }
+            | Self::SameCurrency { tx_id, .. }
+            | Self::RateNotFound { tx_id, .. }
+            | Self::NothingCredited { tx_id, .. }
+            | Self::NegativeAmount { tx_id, .. }
+            | Self::ZeroAmount { tx_id, .. }
+            | Self::AccountLocked { tx_id, .. }
+            | Self::AccountNotLocked { tx_id, .. }
+            | Self::DuplicateTx { tx_id, .. }
+            | Self::InsufficientFunds { tx_id, .. }
+            | Self::ClientMismatch { tx_id, .. }
+            | Self::TxNotFound { tx_id, .. }
+            | Self::AlreadyUnderDispute { tx_id, .. }
+            | Self::NotUnderDispute { tx_id, .. }
+            | Self::NotDisputable { tx_id, .. }
+            | Self::DisputeWindowExpired { tx_id, .. }
+            | Self::DisputeExceedsTx { tx_id, .. }
+            | Self::ExceedsDisputed { tx_id, .. }
+            | Self::NotAuthorized { tx_id, .. }
+            | Self::ExceedsReserved { tx_id, .. }
+            | Self::WalWrite { tx_id, .. }
+            | Self::Storage { tx_id, .. } => *tx_id,
+        }
+    }
+
+    /// Machine-readable reason code of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownType { .. } => "unknown_type",
+            Self::MissingAmount { .. } => "missing_amount",
+            Self::MissingReason { .. } => "missing_reason",
+            Self::MissingDestination { .. } => "missing_destination",
+            Self::SelfTransfer { .. } => "self_transfer",
+            Self::MissingTargetCurrency { .. } => "missing_target_currency",
+            Self::SameCurrency { .. } => "same_currency",
+            Self::RateNotFound { .. } => "rate_not_found",
+            Self::NothingCredited { .. } => "nothing_credited",
+            Self::NegativeAmount { .. } => "negative_amount",
+            Self::ZeroAmount { .. } => "zero_amount",
+            Self::AccountLocked { .. } => "account_locked",
+            Self::AccountNotLocked { .. } => "account_not_locked",
+            Self::DuplicateTx { .. } => "duplicate_tx",
+            Self::InsufficientFunds { .. } => "insufficient_funds",
+            Self::ClientMismatch { .. } => "client_mismatch",
+            Self::TxNotFound { .. } => "tx_not_found",
+            Self::AlreadyUnderDispute { .. } => "already_under_dispute",
+            Self::NotUnderDispute { .. } => "not_under_dispute",
+            Self::NotDisputable { .. } => "not_disputable",
+            Self::DisputeWindowExpired { .. } => "dispute_window_expired",
+            Self::DisputeExceedsTx { .. } => "dispute_exceeds_tx",
+            Self::ExceedsDisputed { .. } => "exceeds_disputed",
+            Self::NotAuthorized { .. } => "not_authorized",
+            Self::ExceedsReserved { .. } => "exceeds_reserved",
+            Self::WalWrite { .. } => "wal_write_failed",
+            Self::Storage { .. } => "storage_error",
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownType { tx_type, .. } => {
+                write!(f, "unknown transaction type: {:?}", tx_type)?
+            },
+            Self::MissingAmount { .. } => {
+                write!(f, "for this transaction type, amount can't be none")?
+            },
+            Self::MissingReason { .. } => {
+                write!(f, "for adjustment, reason can't be none")?
+            },
+            Self::MissingDestination { .. } => {
+                write!(f, "for transfer, destination client can't be none")?
+            },
+            Self::SelfTransfer { .. } => {
+                write!(f, "can't transfer to the same client")?
+            },
+            Self::MissingTargetCurrency { .. } => {
+                write!(f, "for convert, target currency can't be none")?
+            },
+            Self::SameCurrency { .. } => {
+                write!(f, "can't convert to the same currency")?
+            },
+            Self::RateNotFound { from, to, .. } => {
+                write!(f, "no rate from {:?} to {:?}", from.as_str(), to.as_str())?
+            },
+            Self::NothingCredited { .. } => {
+                write!(f, "converted amount is rounded to nothing")?
+            },
+            Self::NegativeAmount { amount, .. } => {
+                write!(f, "amount can't be negative: {}", amount)?
+            },
+            Self::ZeroAmount { .. } => {
+                write!(f, "amount can't be zero")?
+            },
+            Self::AccountLocked { .. } => {
+                write!(f, "can't apply transaction to a locked account")?
+            },
+            Self::AccountNotLocked { .. } => {
+                write!(f, "can't unlock account that isn't locked")?
+            },
+            Self::DuplicateTx { .. } => {
+                write!(f, "transaction with same id already applied")?
+            },
+            Self::InsufficientFunds { needed, available, .. } => {
+                write!(f, "insufficient funds: needed {}, available {}", needed, available)?
+            },
+            Self::ClientMismatch { owner, .. } => {
+                write!(f, "referenced transaction belongs to client {}", owner)?
+            },
+            Self::TxNotFound { .. } => {
+                write!(f, "referenced transaction not found")?
+            },
+            Self::AlreadyUnderDispute { .. } => {
+                write!(f, "transaction is already under dispute")?
+            },
+            Self::NotUnderDispute { .. } => {
+                write!(f, "transaction is not under dispute")?
+            },
+            Self::NotDisputable { .. } => {
+                write!(f, "transaction can't be disputed")?
+            },
+            Self::DisputeWindowExpired { .. } => {
+                write!(f, "transaction is too old to be disputed")?
+            },
+            Self::DisputeExceedsTx { requested, undisputed, .. } => {
+                write!(f, "disputed amount {} exceeds undisputed {}", requested, undisputed)?
+            },
+            Self::ExceedsDisputed { requested, disputed, .. } => {
+                write!(f, "amount {} exceeds disputed {}", requested, disputed)?
+            },
+            Self::NotAuthorized { .. } => {
+                write!(f, "referenced transaction has no reserved funds")?
+            },
+            Self::ExceedsReserved { requested, reserved, .. } => {
+                write!(f, "amount {} exceeds reserved {}", requested, reserved)?
+            },
+            Self::WalWrite { message, .. } => {
+                write!(f, "can't write to the log: {}", message)?
+            },
+            Self::Storage { message, .. } => {
+                write!(f, "transaction store failed: {}", message)?
+            },
+        };
+        write!(f, " (client: {}, tx: {})", self.client_id(), self.tx_id())
+    }
+}
+
+impl std::error::Error for EngineError {}
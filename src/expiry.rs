@@ -0,0 +1,178 @@
+use std::io;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+
+use crate::types::{ClientID, TransactionID, Amount, Timestamp};
+use crate::currency::Currency;
+
+/// When transaction happened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Moment {
+    /// Position of the transaction in the bank's input, starting from 1.
+    pub seq: u64,
+    /// Time of the transaction from the input, if it had one.
+    pub timestamp: Option<Timestamp>,
+}
+
+impl Moment {
+    /// Latest of the two moments, field by field, since
+    /// timestamps in the input don't have to be ordered.
+    pub fn max(self, other: Self) -> Self {
+        Self {
+            seq: self.seq.max(other.seq),
+            timestamp: self.timestamp.max(other.timestamp),
+        }
+    }
+}
+
+/// How old something is, either in time or in number of transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Age {
+    /// Seconds between timestamps. Transactions without
+    /// timestamp never get that old.
+    Seconds(u64),
+    /// Transactions in the bank's input in between.
+    Transactions(u64),
+}
+
+impl Age {
+    /// Point in `Age`'s units, after which something that happened
+    /// at `since` is older than `self`.
+    pub fn deadline(&self, since: Moment) -> Option<u64> {
+        match *self {
+            Age::Seconds(seconds) => since.timestamp.map(|ts| ts.saturating_add(seconds)),
+            Age::Transactions(count) => Some(since.seq.saturating_add(count)),
+        }
+    }
+
+    /// `now` in `Age`'s units.
+    pub fn now(&self, now: Moment) -> Option<u64> {
+        match self {
+            Age::Seconds(_) => now.timestamp,
+            Age::Transactions(_) => Some(now.seq),
+        }
+    }
+
+    /// Whether something that happened at `since` is older than `self` at `now`.
+    pub fn is_exceeded(&self, since: Moment, now: Moment) -> bool {
+        match (self.deadline(since), self.now(now)) {
+            (Some(deadline), Some(now)) => now > deadline,
+            _ => false,
+        }
+    }
+}
+
+/// Parses number with unit: `s`, `m`, `h`, `d` for time
+/// and `tx` for transactions, e.g. `30d` or `1000tx`.
+impl FromStr for Age {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (count, unit) = s.split_at(split);
+        let count: u64 = count.parse()
+            .map_err(|_| format!("invalid age: {:?}", s))?;
+
+        let seconds = match unit {
+            "tx" => return Ok(Age::Transactions(count)),
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(format!("unknown unit of age: {:?}", s)),
+        };
+        Ok(Age::Seconds(count.saturating_mul(seconds)))
+    }
+}
+
+/// What happens to the dispute that stays open past the deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryAction {
+    Resolve,
+    Chargeback,
+}
+
+impl FromStr for ExpiryAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resolve" => Ok(Self::Resolve),
+            "chargeback" => Ok(Self::Chargeback),
+            _ => Err(format!("unknown expiry action: {:?}", s)),
+        }
+    }
+}
+
+/// Expiry of the open disputes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisputeExpiry {
+    /// How long dispute can stay open.
+    pub after: Age,
+    pub action: ExpiryAction,
+}
+
+/// Synthetic event of the dispute that stayed open past the
+/// deadline and was settled automatically.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Expiration {
+    #[serde(rename = "client")]
+    pub client_id: ClientID,
+    /// Id of the disputed transaction.
+    #[serde(rename = "tx")]
+    pub tx_id: TransactionID,
+    pub action: ExpiryAction,
+    /// Disputed amount that was settled.
+    pub amount: Amount,
+    /// Currency of the `amount`, empty for the default one.
+    pub currency: Currency,
+    /// Point in units of the expiry age (unix time or position
+    /// in the input), after which the dispute expired.
+    pub deadline: u64,
+}
+
+/// Serializes expirations to writer as csv.
+pub fn write_csv<'a, W, I>(writer: W, expirations: I) -> Result<(), csv::Error>
+where W: io::Write,
+      I: IntoIterator<Item = &'a Expiration>,
+{
+    let mut wtr = csv::Writer::from_writer(writer);
+    for expiration in expirations {
+        wtr.serialize(expiration)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seq: u64, timestamp: Option<Timestamp>) -> Moment {
+        Moment { seq, timestamp }
+    }
+
+    #[test]
+    fn parse_age() {
+        assert_eq!("30d".parse(), Ok(Age::Seconds(30 * 24 * 60 * 60)));
+        assert_eq!("90s".parse(), Ok(Age::Seconds(90)));
+        assert_eq!("1000tx".parse(), Ok(Age::Transactions(1000)));
+        assert!("30".parse::<Age>().is_err());
+        assert!("d".parse::<Age>().is_err());
+    }
+
+    #[test]
+    fn exceeded_age() {
+        let seconds = Age::Seconds(10);
+        assert!(!seconds.is_exceeded(at(1, Some(100)), at(2, Some(110))));
+        assert!(seconds.is_exceeded(at(1, Some(100)), at(2, Some(111))));
+        // without timestamps, time can't be told.
+        assert!(!seconds.is_exceeded(at(1, None), at(2, Some(1000))));
+
+        let transactions = Age::Transactions(2);
+        assert!(!transactions.is_exceeded(at(1, None), at(3, None)));
+        assert!(transactions.is_exceeded(at(1, None), at(4, None)));
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+use crate::types::TransactionID;
+
+/// Most ids chunk keeps in a sorted array, after that it switches
+/// to a bitmap, which is the same size.
+const SPARSE_MAX: usize = 4096;
+/// Words in the bitmap of the chunk, 2^16 bits.
+const DENSE_WORDS: usize = 1024;
+
+/// Compact set of transaction ids, used to detect duplicates.
+///
+/// Ids are split into chunks by their high 16 bits. Chunk keeps low
+/// 16 bits in a sorted array while it's sparse and in a bitmap once
+/// it's dense, so both random and sequential ids take at most 2 bytes
+/// per id, and sequential ones only a bit.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IdSet {
+    chunks: BTreeMap<u16, Chunk>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Chunk {
+    Sparse(Vec<u16>),
+    Dense(Box<[u64; DENSE_WORDS]>),
+}
+
+impl IdSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, id: TransactionID) -> bool {
+        let (high, low) = split(id);
+        match self.chunks.get(&high) {
+            Some(Chunk::Sparse(ids)) => ids.binary_search(&low).is_ok(),
+            Some(Chunk::Dense(bits)) => bits[low as usize / 64] & bit(low) != 0,
+            None => false,
+        }
+    }
+
+    /// Adds `id` to the set. Returns whether it wasn't there yet.
+    pub fn insert(&mut self, id: TransactionID) -> bool {
+        let (high, low) = split(id);
+        let chunk = self.chunks.entry(high).or_insert_with(|| Chunk::Sparse(vec![]));
+
+        match chunk {
+            Chunk::Sparse(ids) => match ids.binary_search(&low) {
+                Ok(_) => false,
+                Err(pos) => {
+                    ids.insert(pos, low);
+                    if ids.len() > SPARSE_MAX {
+                        *chunk = Chunk::Dense(to_bitmap(ids));
+                    }
+                    true
+                },
+            },
+            Chunk::Dense(bits) => {
+                let word = &mut bits[low as usize / 64];
+                let is_new = *word & bit(low) == 0;
+                *word |= bit(low);
+                is_new
+            },
+        }
+    }
+
+    /// Removes `id` from the set. Chunk stays a bitmap, once it's dense.
+    pub fn remove(&mut self, id: TransactionID) {
+        let (high, low) = split(id);
+        match self.chunks.get_mut(&high) {
+            Some(Chunk::Sparse(ids)) => {
+                if let Ok(pos) = ids.binary_search(&low) {
+                    ids.remove(pos);
+                }
+                if ids.is_empty() {
+                    self.chunks.remove(&high);
+                }
+            },
+            Some(Chunk::Dense(bits)) => bits[low as usize / 64] &= !bit(low),
+            None => {},
+        }
+    }
+
+    /// Ids in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = TransactionID> + '_ {
+        self.chunks.iter().flat_map(|(&high, chunk)| {
+            let lows: Box<dyn Iterator<Item = u16>> = match chunk {
+                Chunk::Sparse(ids) => Box::new(ids.iter().copied()),
+                Chunk::Dense(bits) => Box::new(
+                    (0..=u16::MAX).filter(move |&low| bits[low as usize / 64] & bit(low) != 0)
+                ),
+            };
+            lows.map(move |low| (high as TransactionID) << 16 | low as TransactionID)
+        })
+    }
+
+    /// Consecutive ids merged into inclusive ranges, in ascending order.
+    fn ranges(&self) -> Vec<(TransactionID, TransactionID)> {
+        let mut ranges: Vec<(TransactionID, TransactionID)> = vec![];
+        for id in self.iter() {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == id => *end = id,
+                _ => ranges.push((id, id)),
+            }
+        }
+        ranges
+    }
+}
+
+fn split(id: TransactionID) -> (u16, u16) {
+    ((id >> 16) as u16, id as u16)
+}
+
+fn bit(low: u16) -> u64 {
+    1 << (low % 64)
+}
+
+fn to_bitmap(ids: &[u16]) -> Box<[u64; DENSE_WORDS]> {
+    let mut bits = Box::new([0; DENSE_WORDS]);
+    for &low in ids {
+        bits[low as usize / 64] |= bit(low);
+    }
+    bits
+}
+
+/// Serialized as a list of inclusive ranges, since ids are mostly sequential.
+impl Serialize for IdSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.ranges().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IdSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ranges = Vec::<(TransactionID, TransactionID)>::deserialize(deserializer)?;
+        let mut set = IdSet::new();
+        for (start, end) in ranges {
+            for id in start..=end {
+                set.insert(id);
+            }
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_sparse_and_dense() {
+        let mut set = IdSet::new();
+
+        assert!(set.insert(u32::MAX));
+        assert!(!set.insert(u32::MAX));
+        // goes over sparse limit in the first chunk.
+        for id in (0..10_000).rev() {
+            assert!(set.insert(id * 3));
+        }
+        assert!(!set.insert(9));
+
+        assert!(set.contains(29_997));
+        assert!(!set.contains(29_998));
+        assert!(set.contains(u32::MAX));
+
+        let ids: Vec<_> = set.iter().collect();
+        assert_eq!(ids.len(), 10_001);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn remove_sparse_and_dense() {
+        let mut set = IdSet::new();
+        for id in 0..10_000 {
+            set.insert(id);
+        }
+        set.insert(u32::MAX);
+
+        set.remove(9);
+        set.remove(u32::MAX);
+        set.remove(20_000);
+        assert!(!set.contains(9));
+        assert!(set.contains(10));
+        assert!(!set.contains(u32::MAX));
+        assert_eq!(set.iter().count(), 9_999);
+        // empty chunk is gone, so set is the same as without it.
+        assert_eq!(set, (0..10_000).filter(|&id| id != 9).fold(IdSet::new(), |mut set, id| {
+            set.insert(id);
+            set
+        }));
+    }
+
+    #[test]
+    fn serialize_as_ranges() {
+        let mut set = IdSet::new();
+        for id in (1..=5).chain(7..=8).chain(Some(70_000)) {
+            set.insert(id);
+        }
+
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(json, "[[1,5],[7,8],[70000,70000]]");
+        assert_eq!(serde_json::from_str::<IdSet>(&json).unwrap(), set);
+    }
+}
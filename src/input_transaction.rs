@@ -1,6 +1,29 @@
+use std::str::FromStr;
 use serde::Deserialize;
 
-use crate::types::{ClientID, TransactionID, Amount};
+use crate::types::{ClientID, TransactionID, Amount, Timestamp};
+use crate::currency::Currency;
+
+/// Format of the input transactions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    /// Csv with a header row.
+    Csv,
+    /// One json object per line.
+    JsonLines,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::JsonLines),
+            _ => Err(format!("unknown input format: {:?}", s)),
+        }
+    }
+}
 
 /// Raw transaction that we receive as an input.
 #[derive(Debug, Deserialize)]
@@ -17,6 +40,28 @@ pub struct InputTransaction {
     /// Can be optional for some types of transactions,
     /// for details see: [Transaction](crate::transaction::Transaction)
     pub amount: Option<Amount>,
+    /// Reason code for `lock`/`freeze`/`unlock` transactions, where it's
+    /// optional, and `adjustment`, where it's mandatory.
+    /// Column can be missing from the input.
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Destination client of the `transfer`.
+    /// Column can be missing from the input.
+    #[serde(default)]
+    pub to: Option<ClientID>,
+    /// Currency of the `amount`, default one if it's none. Disputes and
+    /// other references are in the currency of the referenced transaction.
+    /// Column can be missing from the input.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Currency that `convert` credits.
+    /// Column can be missing from the input.
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+    /// Optional unix time of the transaction in seconds.
+    /// Column can be missing from the input.
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
 }
 
 #[cfg(test)]
@@ -45,4 +90,40 @@ client,tx,type,amount
         assert!(res.amount.is_some());
         assert_eq!(res.amount.unwrap().to_string(), "10.543");
     }
+
+    #[test]
+    fn deserialize_with_reason() {
+        let input = "\
+client,tx,type,amount,reason
+1,1,unlock,,reinstated
+1,2,lock,,
+";
+        let mut rdr = csv::Reader::from_reader(input.as_bytes());
+        let res: Vec<InputTransaction> = rdr.deserialize().map(Result::unwrap).collect();
+        assert_eq!(res[0].reason.as_deref(), Some("reinstated"));
+        assert!(res[1].reason.is_none());
+    }
+
+    #[test]
+    fn deserialize_json() {
+        let res: InputTransaction = serde_json::from_str(
+            r#"{"type":"deposit","client":1,"tx":2,"amount":"10.543"}"#
+        ).unwrap();
+        assert_eq!(res.tx_id, 2);
+        assert_eq!(res.amount.unwrap().to_string(), "10.543");
+
+        let res: InputTransaction = serde_json::from_str(
+            r#"{"type":"dispute","client":1,"tx":2}"#
+        ).unwrap();
+        assert!(res.amount.is_none());
+        assert!(res.reason.is_none());
+    }
+
+    #[test]
+    fn deserialize_json_number_amount() {
+        let res: InputTransaction = serde_json::from_str(
+            r#"{"type":"deposit","client":1,"tx":2,"amount":1.5}"#
+        ).unwrap();
+        assert_eq!(res.amount.unwrap().to_string(), "1.5");
+    }
 }
@@ -0,0 +1,68 @@
+//! Simple payments engine, that calculates accounts' balances
+//! based on the list of transactions.
+//!
+//! Transactions are applied to the [Bank], which stores and manages
+//! [Account]-s. There are two implementations of it: [BasicBank]
+//! which applies transactions on the current thread and
+//! [ConcurrentBank] which distributes clients across threads.
+//!
+//! ```
+//! use payments_engine_rs::{Bank, BasicBank, Transaction, TransactionInfo};
+//!
+//! let mut bank = BasicBank::new();
+//! bank.apply_tx(Transaction::Deposit(
+//!     TransactionInfo::new(1, 1, "10.5".parse().unwrap()),
+//! )).unwrap();
+//!
+//! let accounts = bank.accounts();
+//! assert_eq!(accounts[0].available.to_string(), "10.5");
+//! ```
+
+pub mod types;
+pub mod currency;
+pub mod error;
+mod decimal_serde;
+pub mod input_transaction;
+pub mod transaction;
+mod disk_map;
+pub mod tx_store;
+pub mod account;
+pub mod output_account;
+mod id_set;
+pub mod tx_index;
+pub mod audit;
+pub mod rejects;
+pub mod expiry;
+pub mod rates;
+pub mod policy;
+pub mod snapshot;
+pub mod wal;
+
+pub mod bank;
+pub mod basic_bank;
+pub mod concurrent_bank;
+pub mod shard;
+pub mod rebalance;
+pub mod pipeline;
+#[cfg(test)]
+pub(crate) mod test_util;
+
+pub use error::EngineError;
+pub use currency::Currency;
+pub use transaction::{Transaction, TransactionInfo, TransactionRef, AccountAction};
+pub use tx_store::{TxStore, TxRecord, TxKind, MemoryTxStore, DiskTxStore};
+pub use account::Account;
+pub use input_transaction::InputFormat;
+pub use output_account::{OutputAccount, OutputFormat, AccountOrder};
+pub use expiry::{Moment, Age, DisputeExpiry, ExpiryAction, Expiration};
+pub use rates::{RateTable, Rounding, RoundingMode};
+pub use policy::Policy;
+pub use rejects::Rejects;
+pub use snapshot::Snapshot;
+pub use wal::{Wal, SyncPolicy};
+pub use bank::Bank;
+pub use basic_bank::BasicBank;
+pub use concurrent_bank::ConcurrentBank;
+pub use shard::{ShardStrategy, Sharding};
+pub use rebalance::Rebalancing;
+pub use pipeline::Pipeline;
@@ -1,45 +1,466 @@
 use std::io;
+use std::fs::File;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 use clap::{App, Arg};
 
-mod types;
-mod decimal_serde;
-mod input_transaction;
-mod transaction;
-mod account;
-mod output_account;
+use payments_engine_rs::{
+    Bank, BasicBank, Policy, Rejects, Snapshot, SyncPolicy,
+    InputFormat, OutputAccount, OutputFormat, AccountOrder, DisputeExpiry,
+    RateTable, Rounding, ShardStrategy, Sharding, Rebalancing, Pipeline, Wal,
+};
+use payments_engine_rs::{audit, concurrent_bank, expiry, output_account, rates, shard};
 
-mod bank;
-use bank::Bank;
+/// Source of input transactions.
+enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Input {
+    /// Expands cli argument into inputs. `-` means stdin,
+    /// glob patterns are expanded in alphabetical order.
+    fn from_arg(arg: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        if arg == "-" {
+            return Ok(vec![Input::Stdin]);
+        }
+
+        if !arg.contains(&['*', '?', '['][..]) {
+            return Ok(vec![Input::File(arg.into())]);
+        }
+
+        let paths = glob::glob(arg)
+            .map_err(|err| format!("invalid pattern {:?}: {}", arg, err))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("can't read {:?}: {}", err.path(), err.error()))?;
+
+        if paths.is_empty() {
+            return Err(format!("no input files match {:?}", arg).into());
+        }
+        Ok(paths.into_iter().map(Input::File).collect())
+    }
 
-mod basic_bank;
-use basic_bank::BasicBank;
+    fn name(&self) -> String {
+        match self {
+            Input::Stdin => "-".to_owned(),
+            Input::File(path) => path.display().to_string(),
+        }
+    }
 
-mod concurrent_bank;
-use concurrent_bank::ConcurrentBank;
+    fn open(&self) -> Result<Box<dyn io::Read + Send>, Box<dyn Error>> {
+        Ok(match self {
+            Input::Stdin => Box::new(io::stdin()),
+            Input::File(path) => Box::new(
+                File::open(path)
+                    .map_err(|err| format!("can't open {}: {}", path.display(), err))?
+            ),
+        })
+    }
+}
 
 fn main() {
     // parse cli args
     let matches = App::new("simple payments engine")
         .version("0.1")
         .arg(Arg::with_name("INPUT")
-             .help("input file")
+             .help("input files or glob patterns, processed in order. `-` for stdin")
              .required(true)
+             .multiple(true)
              .index(1))
         .arg(Arg::with_name("concurrent")
              .help("concurrent mode")
              .short("c")
              .long("concurrent")
              .takes_value(false))
+        .arg(Arg::with_name("queue-capacity")
+             .help("transactions that can wait for each thread in concurrent mode")
+             .long("queue-capacity")
+             .value_name("COUNT")
+             .default_value("1024"))
+        .arg(Arg::with_name("batch-size")
+             .help("transactions sent to a thread at once in concurrent mode")
+             .long("batch-size")
+             .value_name("COUNT")
+             .default_value("1"))
+        .arg(Arg::with_name("flush-after")
+             .help("milliseconds a batch that isn't full waits for more transactions")
+             .long("flush-after")
+             .value_name("MS")
+             .default_value("10"))
+        .arg(Arg::with_name("sharding")
+             .help("how clients are distributed across threads in concurrent mode")
+             .long("sharding")
+             .possible_values(&["modulo", "hashed", "load-aware"])
+             .default_value("modulo"))
+        .arg(Arg::with_name("shard-map")
+             .help("csv file with `client,shard` columns, others are distributed by --sharding")
+             .long("shard-map")
+             .value_name("PATH")
+             .takes_value(true))
+        .arg(Arg::with_name("rebalance-window")
+             .help("moves clients away from the busiest thread after every COUNT transactions in concurrent mode")
+             .long("rebalance-window")
+             .value_name("COUNT")
+             .takes_value(true))
+        .arg(Arg::with_name("parse-threads")
+             .help("parses csv input on COUNT threads, while transactions are applied in order")
+             .long("parse-threads")
+             .value_name("COUNT")
+             .takes_value(true))
+        .arg(Arg::with_name("input-format")
+             .help("format of the input")
+             .long("input-format")
+             .possible_values(&["csv", "jsonl"])
+             .default_value("csv"))
+        .arg(Arg::with_name("output-format")
+             .help("format of the output")
+             .long("output-format")
+             .possible_values(&["csv", "json", "jsonl"])
+             .default_value("csv"))
+        .arg(Arg::with_name("sort")
+             .help("order of the output accounts")
+             .long("sort")
+             .possible_values(&["client", "total", "locked"])
+             .default_value("client"))
+        .arg(Arg::with_name("rejects")
+             .help("write rejected rows with reasons to csv file")
+             .long("rejects")
+             .value_name("PATH")
+             .takes_value(true))
+        .arg(Arg::with_name("audit")
+             .help("write audit log of locks/unlocks to csv file")
+             .long("audit")
+             .value_name("PATH")
+             .takes_value(true))
+        .arg(Arg::with_name("from-snapshot")
+             .help("restore bank state from snapshot file before processing inputs")
+             .long("from-snapshot")
+             .value_name("PATH")
+             .takes_value(true))
+        .arg(Arg::with_name("write-snapshot")
+             .help("write bank state to snapshot file after processing inputs")
+             .long("write-snapshot")
+             .value_name("PATH")
+             .takes_value(true))
+        .arg(Arg::with_name("wal")
+             .help("write-ahead log to recover from and resume the same inputs after a crash")
+             .long("wal")
+             .value_name("PATH")
+             .takes_value(true)
+             .conflicts_with("concurrent"))
+        .arg(Arg::with_name("wal-sync")
+             .help("when to fsync the write-ahead log: `always`, `never` or every N records")
+             .long("wal-sync")
+             .value_name("POLICY")
+             .default_value("always"))
+        .arg(Arg::with_name("tx-store-dir")
+             .help("keep transaction history on disk in this directory, instead of memory")
+             .long("tx-store-dir")
+             .value_name("DIR")
+             .takes_value(true))
+        .arg(Arg::with_name("tx-cache")
+             .help("transactions cached in memory when history is kept on disk (per thread)")
+             .long("tx-cache")
+             .value_name("COUNT")
+             .default_value("1000000"))
+        .arg(Arg::with_name("allow-withdrawal-disputes")
+             .help("allow disputes of withdrawal transactions")
+             .long("allow-withdrawal-disputes")
+             .takes_value(false))
+        .arg(Arg::with_name("allow-negative-fees")
+             .help("allow fees to push available funds below zero")
+             .long("allow-negative-fees")
+             .takes_value(false))
+        .arg(Arg::with_name("breakdown")
+             .help("add reserved, fees and adjustments columns to the output")
+             .long("breakdown")
+             .takes_value(false))
+        .arg(Arg::with_name("dispute-window")
+             .help("refuse disputes of transactions older than this, e.g. `30d`, `12h` or `1000tx`")
+             .long("dispute-window")
+             .value_name("AGE")
+             .takes_value(true))
+        .arg(Arg::with_name("dispute-expiry")
+             .help("settle disputes that stay open longer than this, e.g. `7d` or `1000tx`")
+             .long("dispute-expiry")
+             .value_name("AGE")
+             .takes_value(true))
+        .arg(Arg::with_name("expiry-action")
+             .help("how disputes are settled when they expire")
+             .long("expiry-action")
+             .possible_values(&["resolve", "chargeback"])
+             .default_value("resolve"))
+        .arg(Arg::with_name("authorization-expiry")
+             .help("void authorizations that stay open longer than this, e.g. `7d` or `1000tx`")
+             .long("authorization-expiry")
+             .value_name("AGE")
+             .takes_value(true))
+        .arg(Arg::with_name("expirations")
+             .help("write expired disputes to csv file")
+             .long("expirations")
+             .value_name("PATH")
+             .takes_value(true))
+        .arg(Arg::with_name("rates")
+             .help("csv file with exchange rates of the `convert` transactions")
+             .long("rates")
+             .value_name("PATH")
+             .takes_value(true))
+        .arg(Arg::with_name("conversion-precision")
+             .help("decimal places of the converted amounts")
+             .long("conversion-precision")
+             .value_name("DIGITS")
+             .default_value("4"))
+        .arg(Arg::with_name("conversion-rounding")
+             .help("how converted amounts are rounded")
+             .long("conversion-rounding")
+             .possible_values(&["down", "up", "half-up", "half-even"])
+             .default_value("down"))
+        .arg(Arg::with_name("spread-report")
+             .help("write spread collected by conversions to csv file")
+             .long("spread-report")
+             .value_name("PATH")
+             .takes_value(true))
         .get_matches();
 
-    let filename = matches.value_of("INPUT").unwrap();
+    if let Err(err) = run_cli(&matches) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run_cli(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mut inputs = vec![];
+    for arg in matches.values_of("INPUT").unwrap() {
+        inputs.extend(Input::from_arg(arg)?);
+    }
+
     let is_concurrent = matches.is_present("concurrent");
+    let rejects = match matches.value_of("rejects") {
+        Some(path) => Some(Rejects::new(
+            File::create(path)
+                .map_err(|err| format!("can't create {}: {}", path, err))?
+        )),
+        None => None,
+    };
+    let mut policy = Policy::default();
+    policy.withdrawal_disputes = matches.is_present("allow-withdrawal-disputes");
+    policy.negative_fees = matches.is_present("allow-negative-fees");
+    if let Some(age) = matches.value_of("dispute-window") {
+        policy.dispute_window = Some(age.parse()?);
+    }
+    if let Some(age) = matches.value_of("authorization-expiry") {
+        policy.authorization_expiry = Some(age.parse()?);
+    }
+    if let Some(age) = matches.value_of("dispute-expiry") {
+        policy.dispute_expiry = Some(DisputeExpiry {
+            after: age.parse()?,
+            action: matches.value_of("expiry-action").unwrap().parse()?,
+        });
+    }
+    if let Some(path) = matches.value_of("rates") {
+        let rounding = Rounding {
+            precision: matches.value_of("conversion-precision").unwrap().parse()
+                .map_err(|err| format!("invalid --conversion-precision: {}", err))?,
+            mode: matches.value_of("conversion-rounding").unwrap().parse()?,
+        };
+        let rates = RateTable::read_file(path)
+            .map_err(|err| format!("can't read rates {}: {}", path, err))?;
+        policy.rates = Some(Arc::new(rates.with_rounding(rounding)));
+    }
+
+    let pipeline = match matches.value_of("parse-threads") {
+        Some(count) => {
+            let count: usize = count.parse()
+                .map_err(|err| format!("invalid --parse-threads: {}", err))?;
+            Some(Pipeline::new(count))
+        },
+        None => None,
+    };
+
+    let options = Options {
+        input_format: matches.value_of("input-format").unwrap().parse()?,
+        pipeline,
+        output_format: matches.value_of("output-format").unwrap().parse()?,
+        order: matches.value_of("sort").unwrap().parse()?,
+        breakdown: matches.is_present("breakdown"),
+        policy,
+        rejects: rejects.clone(),
+        audit: matches.value_of("audit"),
+        expirations: matches.value_of("expirations"),
+        spread_report: matches.value_of("spread-report"),
+        from_snapshot: matches.value_of("from-snapshot"),
+        write_snapshot: matches.value_of("write-snapshot"),
+        wal: matches.value_of("wal"),
+    };
+
+    let tx_store_dir = matches.value_of("tx-store-dir").map(Path::new);
+    let tx_cache: usize = matches.value_of("tx-cache").unwrap().parse()
+        .map_err(|err| format!("invalid --tx-cache: {}", err))?;
+    let queue_capacity: usize = matches.value_of("queue-capacity").unwrap().parse()
+        .map_err(|err| format!("invalid --queue-capacity: {}", err))?;
 
     if !is_concurrent {
-        let bank = BasicBank::from_input_transactions_csv_file(filename);
-        bank.accounts_to_csv(io::stdout().lock()).unwrap();
+        let mut bank = restore::<BasicBank>(&options)?;
+        if let Some(dir) = tx_store_dir {
+            bank = bank.with_disk_store(dir.join("transactions.bin"), tx_cache)
+                .map_err(|err| format!("can't create store in {}: {}", dir.display(), err))?;
+        }
+        if let Some(path) = matches.value_of("wal") {
+            let sync: SyncPolicy = matches.value_of("wal-sync").unwrap().parse()?;
+            bank = bank.with_wal(path, sync)
+                .map_err(|err| format!("can't recover from {}: {}", path, err))?;
+            bank.skip_replayed();
+        }
+        run(bank, &inputs, &options)?;
     } else {
-        let bank = ConcurrentBank::from_input_transactions_csv_file(filename);
-        bank.accounts_to_csv(io::stdout().lock()).unwrap();
+        let sharding: Sharding = matches.value_of("sharding").unwrap().parse()?;
+        let strategy: Box<dyn ShardStrategy> = match matches.value_of("shard-map") {
+            Some(path) => Box::new(
+                shard::Mapping::read_file(path, sharding.strategy())
+                    .map_err(|err| format!("can't read shard map {}: {}", path, err))?
+            ),
+            None => sharding.strategy(),
+        };
+        let builder = match read_snapshot(&options)? {
+            Some(snapshot) => {
+                concurrent_bank::Builder::from_snapshot(snapshot, options.policy.clone())
+            },
+            None => concurrent_bank::Builder::new(options.policy.clone()),
+        };
+        let mut builder = builder
+            .with_shard_strategy(strategy)
+            .with_queue_capacity(queue_capacity);
+        let batch_size: usize = matches.value_of("batch-size").unwrap().parse()
+            .map_err(|err| format!("invalid --batch-size: {}", err))?;
+        if batch_size > 1 {
+            let flush_after: u64 = matches.value_of("flush-after").unwrap().parse()
+                .map_err(|err| format!("invalid --flush-after: {}", err))?;
+            builder = builder.with_batching(batch_size, Duration::from_millis(flush_after));
+        }
+        if let Some(window) = matches.value_of("rebalance-window") {
+            let mut rebalancing = Rebalancing::default();
+            rebalancing.window = window.parse()
+                .map_err(|err| format!("invalid --rebalance-window: {}", err))?;
+            builder = builder.with_rebalancing(rebalancing);
+        }
+        if let Some(dir) = tx_store_dir {
+            builder = builder.with_disk_stores(dir, tx_cache);
+        }
+        let bank = builder.build().map_err(|err| match tx_store_dir {
+            Some(dir) => format!("can't create store in {}: {}", dir.display(), err),
+            None => format!("can't start bank: {}", err),
+        })?;
+        run(bank, &inputs, &options)?;
+    }
+
+    if let Some(rejects) = rejects {
+        rejects.flush()
+            .map_err(|err| format!("can't write rejects: {}", err))?;
+    }
+    Ok(())
+}
+
+/// How inputs are processed and results written.
+struct Options<'a> {
+    input_format: InputFormat,
+    /// Parses csv input in parallel, if it's there.
+    pipeline: Option<Pipeline>,
+    output_format: OutputFormat,
+    order: AccountOrder,
+    /// Whether output has the extended breakdown of the balance.
+    breakdown: bool,
+    policy: Policy,
+    rejects: Option<Rejects>,
+    audit: Option<&'a str>,
+    expirations: Option<&'a str>,
+    spread_report: Option<&'a str>,
+    from_snapshot: Option<&'a str>,
+    write_snapshot: Option<&'a str>,
+    /// Write-ahead log, that starts over once snapshot is written.
+    wal: Option<&'a str>,
+}
+
+/// Snapshot to restore bank from, if there's one.
+fn read_snapshot(options: &Options) -> Result<Option<Snapshot>, Box<dyn Error>> {
+    match options.from_snapshot {
+        Some(path) => {
+            let snapshot = Snapshot::read_file(path)
+                .map_err(|err| format!("can't read snapshot {}: {}", path, err))?;
+            Ok(Some(snapshot))
+        },
+        None => Ok(None),
     }
 }
+
+/// Bank restored from the snapshot if there's one, otherwise empty.
+fn restore<B: Bank>(options: &Options) -> Result<B, Box<dyn Error>> {
+    Ok(match read_snapshot(options)? {
+        Some(snapshot) => B::from_snapshot(snapshot, options.policy.clone()),
+        None => B::new_with_policy(options.policy.clone()),
+    })
+}
+
+fn run<B: Bank>(mut bank: B, inputs: &[Input], options: &Options) -> Result<(), Box<dyn Error>> {
+    for input in inputs {
+        let reader = input.open()?;
+        let rejects = options.rejects.as_ref().map(|rejects| rejects.for_source(&input.name()));
+        match (options.pipeline, options.input_format, &rejects) {
+            (Some(pipeline), InputFormat::Csv, Some(rejects)) => {
+                pipeline.apply_csv_with_rejects(&mut bank, reader, rejects)
+            },
+            (Some(pipeline), InputFormat::Csv, None) => pipeline.apply_csv(&mut bank, reader),
+            (_, format, Some(rejects)) => bank.apply_input_with_rejects(reader, format, rejects),
+            (_, format, None) => bank.apply_input(reader, format),
+        }.map_err(|err| format!("can't read {}: {}", input.name(), err))?;
+    }
+
+    let accounts: Vec<_> = match options.write_snapshot {
+        Some(path) => {
+            let snapshot = bank.into_snapshot()
+                .map_err(|err| format!("can't read state of the bank: {}", err))?;
+            snapshot.write_file(path)
+                .map_err(|err| format!("can't write snapshot {}: {}", path, err))?;
+            // snapshot has everything the log has, so it
+            // mustn't be replayed on top of it.
+            if let Some(wal) = options.wal {
+                Wal::reset(wal).map_err(|err| format!("can't reset {}: {}", wal, err))?;
+            }
+            snapshot.into_accounts()
+        }
+        None => bank.into_accounts_iter().collect(),
+    };
+
+    if let Some(path) = options.audit {
+        let audit_file = File::create(path)
+            .map_err(|err| format!("can't create {}: {}", path, err))?;
+        audit::write_csv(audit_file, accounts.iter().flat_map(|acc| acc.audit_log()))
+            .map_err(|err| format!("can't write {}: {}", path, err))?;
+    }
+
+    if let Some(path) = options.expirations {
+        let expirations_file = File::create(path)
+            .map_err(|err| format!("can't create {}: {}", path, err))?;
+        expiry::write_csv(expirations_file, accounts.iter().flat_map(|acc| acc.expirations()))
+            .map_err(|err| format!("can't write {}: {}", path, err))?;
+    }
+
+    if let Some(path) = options.spread_report {
+        let spread_file = File::create(path)
+            .map_err(|err| format!("can't create {}: {}", path, err))?;
+        rates::write_spread_csv(spread_file, accounts.iter().flat_map(|acc| acc.conversions()))
+            .map_err(|err| format!("can't write {}: {}", path, err))?;
+    }
+
+    let mut accounts: Vec<_> = accounts.iter()
+        .flat_map(|account| OutputAccount::rows(account, options.breakdown))
+        .collect();
+    options.order.sort(&mut accounts);
+
+    output_account::write_accounts(io::stdout().lock(), accounts, options.output_format)
+        .map_err(|err| format!("can't write output: {}", err))?;
+
+    Ok(())
+}
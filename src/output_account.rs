@@ -1,14 +1,45 @@
+use std::io::{self, Write};
+use std::str::FromStr;
 use serde::Serialize;
 
 use crate::types::{ClientID, Amount};
+use crate::currency::Currency;
 use crate::decimal_serde::serialize as serialize_decimal;
+use crate::decimal_serde::serialize_option as serialize_optional_decimal;
+
+/// Format in which accounts are written out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Csv with a header row.
+    Csv,
+    /// Single json array.
+    Json,
+    /// One json object per line.
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::JsonLines),
+            _ => Err(format!("unknown output format: {:?}", s)),
+        }
+    }
+}
 
 /// Account data that we serialize and output as a result,
 /// which represents final account data for the client.
-#[derive(Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct OutputAccount {
     #[serde(rename = "client")]
     pub client_id: ClientID,
+    /// Currency of the balance, `None` for the default one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
     #[serde(serialize_with = "serialize_decimal")]
     pub available: Amount,
     #[serde(serialize_with = "serialize_decimal")]
@@ -16,6 +47,98 @@ pub struct OutputAccount {
     #[serde(serialize_with = "serialize_decimal")]
     pub total: Amount,
     pub locked: bool,
+    /// Funds reserved by authorizations, only in the extended breakdown.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_decimal")]
+    pub reserved: Option<Amount>,
+    /// Sum of the fees charged, only in the extended breakdown.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_decimal")]
+    pub fees: Option<Amount>,
+    /// Sum of the manual corrections, only in the extended breakdown.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_decimal")]
+    pub adjustments: Option<Amount>,
+}
+
+/// Order in which accounts are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AccountOrder {
+    /// Ascending by client id.
+    #[default]
+    ClientId,
+    /// Descending by total balance, then by client id.
+    Total,
+    /// Locked accounts first, then by client id.
+    LockedFirst,
+}
+
+impl FromStr for AccountOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(Self::ClientId),
+            "total" => Ok(Self::Total),
+            "locked" => Ok(Self::LockedFirst),
+            _ => Err(format!("unknown account order: {:?}", s)),
+        }
+    }
+}
+
+impl AccountOrder {
+    /// Sorts accounts in this order. Result doesn't depend
+    /// on the initial order of the accounts. Rows of the same
+    /// client are ordered by currency.
+    pub fn sort(self, accounts: &mut [OutputAccount]) {
+        match self {
+            Self::ClientId => accounts.sort_by_key(|acc| (acc.client_id, acc.currency)),
+            Self::Total => accounts.sort_by(|a, b| {
+                b.total.cmp(&a.total)
+                    .then(a.client_id.cmp(&b.client_id))
+                    .then(a.currency.cmp(&b.currency))
+            }),
+            Self::LockedFirst => {
+                accounts.sort_by_key(|acc| (!acc.locked, acc.client_id, acc.currency))
+            },
+        }
+    }
+}
+
+/// Serializes accounts to writer in the given `format`. If any
+/// of the rows has a currency, all of them get the currency column,
+/// empty for the default currency.
+pub fn write_accounts<W, I>(writer: W, accounts: I, format: OutputFormat) -> io::Result<()>
+where W: io::Write,
+      I: IntoIterator<Item = OutputAccount>,
+{
+    let mut accounts: Vec<_> = accounts.into_iter().collect();
+    if accounts.iter().any(|acc| acc.currency.is_some()) {
+        for account in accounts.iter_mut() {
+            account.currency.get_or_insert_with(Currency::default);
+        }
+    }
+
+    if let OutputFormat::Csv = format {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for account in accounts {
+            wtr.serialize(account)?;
+        }
+        return wtr.flush();
+    }
+
+    let mut wtr = io::BufWriter::new(writer);
+    let (start, separator, end) = match format {
+        OutputFormat::Json => ("[", ",", "]\n"),
+        _ => ("", "\n", "\n"),
+    };
+
+    wtr.write_all(start.as_bytes())?;
+    for (i, account) in accounts.into_iter().enumerate() {
+        if i > 0 {
+            wtr.write_all(separator.as_bytes())?;
+        }
+        serde_json::to_writer(&mut wtr, &account)?;
+    }
+    wtr.write_all(end.as_bytes())?;
+    wtr.flush()
 }
 
 #[cfg(test)]
@@ -40,10 +163,14 @@ mod tests {
             let total = available + held;
             serialize_to_string(vec![OutputAccount {
                 client_id: 1,
+                currency: None,
                 available,
                 held,
                 total,
-                locked: false
+                locked: false,
+                reserved: None,
+                fees: None,
+                adjustments: None,
             }])
 
         };
@@ -53,4 +180,77 @@ mod tests {
         assert_eq!(vals[1], "10.5234");
         assert_eq!(vals[2], "30.2938");
     }
+
+    fn account(client_id: ClientID) -> OutputAccount {
+        OutputAccount {
+            client_id,
+            currency: None,
+            available: Amount::from_str("1.23456").unwrap(),
+            held: Amount::from_str("0").unwrap(),
+            total: Amount::from_str("1.23456").unwrap(),
+            locked: false,
+            reserved: None,
+            fees: None,
+            adjustments: None,
+        }
+    }
+
+    fn write_to_string(format: OutputFormat) -> String {
+        let mut buf = vec![];
+        write_accounts(&mut buf, vec![account(1), account(2)], format).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn write_json() {
+        assert_eq!(write_to_string(OutputFormat::Json), concat!(
+            r#"[{"client":1,"available":"1.2345","held":"0","total":"1.2345","locked":false},"#,
+            r#"{"client":2,"available":"1.2345","held":"0","total":"1.2345","locked":false}]"#,
+            "\n",
+        ));
+    }
+
+    #[test]
+    fn write_jsonl() {
+        assert_eq!(write_to_string(OutputFormat::JsonLines), concat!(
+            r#"{"client":1,"available":"1.2345","held":"0","total":"1.2345","locked":false}"#,
+            "\n",
+            r#"{"client":2,"available":"1.2345","held":"0","total":"1.2345","locked":false}"#,
+            "\n",
+        ));
+    }
+
+    #[test]
+    fn write_breakdown() {
+        let mut account = account(1);
+        account.reserved = Some(Amount::from_str("0").unwrap());
+        account.fees = Some(Amount::from_str("0.5").unwrap());
+        account.adjustments = Some(Amount::from_str("-1.23456").unwrap());
+
+        let mut buf = vec![];
+        write_accounts(&mut buf, vec![account], OutputFormat::Csv).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "\
+client,available,held,total,locked,reserved,fees,adjustments
+1,1.2345,0,1.2345,false,0,0.5,-1.2345
+");
+    }
+
+    #[test]
+    fn sort_accounts() {
+        let mut accounts: Vec<_> = (1..=4).map(account).collect();
+        accounts[0].total = Amount::from(1);
+        accounts[1].total = Amount::from(3);
+        accounts[2].total = Amount::from(3);
+        accounts[3].locked = true;
+
+        let client_ids = |order: AccountOrder, accounts: &mut Vec<OutputAccount>| {
+            accounts.reverse();
+            order.sort(accounts);
+            accounts.iter().map(|acc| acc.client_id).collect::<Vec<_>>()
+        };
+
+        assert_eq!(client_ids(AccountOrder::ClientId, &mut accounts), vec![1, 2, 3, 4]);
+        assert_eq!(client_ids(AccountOrder::Total, &mut accounts), vec![2, 3, 4, 1]);
+        assert_eq!(client_ids(AccountOrder::LockedFirst, &mut accounts), vec![4, 1, 2, 3]);
+    }
 }
@@ -0,0 +1,468 @@
+use std::convert::TryFrom;
+use std::io;
+use std::thread;
+
+use crate::types::Timestamp;
+use crate::transaction::Transaction;
+use crate::input_transaction::InputTransaction;
+use crate::bank::{Bank, raw_row};
+use crate::rejects::{Origin, Reject, RejectReason, Rejects};
+
+/// Size of the chunks input is split into by default,
+/// see [Pipeline::with_chunk_size].
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Reads csv input with multiple parser threads.
+///
+/// Input is split into chunks of about the same size at record
+/// boundaries, quoted fields with line breaks are kept whole. Chunks are
+/// parsed and converted to `Transaction`-s in parallel, while the current
+/// thread applies them to the `Bank` in the same order as they are in
+/// the input, e.g. for [ConcurrentBank](crate::ConcurrentBank) to route to
+/// it's threads. Order of all transactions is kept, not only of the ones
+/// of the same client, since transaction ids and transfers span clients.
+#[derive(Debug, Clone, Copy)]
+pub struct Pipeline {
+    parsers: usize,
+    chunk_size: usize,
+}
+
+impl Pipeline {
+    /// Pipeline with `parsers` threads, at least one.
+    pub fn new(parsers: usize) -> Self {
+        Self { parsers: parsers.max(1), chunk_size: DEFAULT_CHUNK_SIZE }
+    }
+
+    /// Splits input into chunks of about `chunk_size` bytes, instead of
+    /// [DEFAULT_CHUNK_SIZE]. Chunk ends at the first record boundary after
+    /// that, so it can be longer. Smaller chunks keep more threads busy on
+    /// a small input, while bigger ones cost less to hand over.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Same as [Bank::apply_input_transactions_csv], but parsed in parallel.
+    pub fn apply_csv<B, R>(&self, bank: &mut B, reader: R) -> Result<(), csv::Error>
+    where B: Bank,
+          R: io::Read + Send,
+    {
+        self.run(reader, false, |parsed| {
+            if let Ok((tx, timestamp)) = parsed.result {
+                // ignore result
+                let _ = bank.apply_tx_at(tx, timestamp);
+            }
+            Ok(())
+        })
+    }
+
+    /// Same as [Bank::apply_input_transactions_csv_with_rejects],
+    /// but parsed in parallel.
+    pub fn apply_csv_with_rejects<B, R>(
+        &self,
+        bank: &mut B,
+        reader: R,
+        rejects: &Rejects,
+    ) -> Result<(), csv::Error>
+    where B: Bank,
+          R: io::Read + Send,
+    {
+        self.run(reader, true, |parsed| {
+            // origins are always there, when they're asked for.
+            let origin = parsed.origin.unwrap_or(Origin { line: 0, raw: String::new() });
+            match parsed.result {
+                Ok((tx, timestamp)) => bank.apply_tx_or_reject(tx, timestamp, origin, rejects),
+                Err(reason) => rejects.report(Reject { origin, reason }),
+            }
+        })
+    }
+
+    /// Splits input on a separate thread, parses chunks on the parser
+    /// threads and passes parsed rows to `apply` in the input order.
+    fn run<R, F>(&self, reader: R, origins: bool, mut apply: F) -> Result<(), csv::Error>
+    where R: io::Read + Send,
+          F: FnMut(Parsed) -> Result<(), csv::Error>,
+    {
+        let mut splitter = Splitter::new(reader, self.chunk_size);
+        let headers = match splitter.headers()? {
+            Some(headers) => headers,
+            None => return Ok(()),
+        };
+        let headers = &headers;
+
+        thread::scope(|scope| {
+            let (work_tx, work_rx) = crossbeam_channel::bounded::<(Chunk, _)>(self.parsers * 2);
+            // replies are waited for in the order chunks were sent, so
+            // parsers can finish them in any order.
+            let (order_tx, order_rx) = crossbeam_channel::bounded(self.parsers * 4);
+
+            let splitter = scope.spawn(move || -> io::Result<()> {
+                while let Some(chunk) = splitter.next_chunk()? {
+                    let (reply, parsed) = crossbeam_channel::bounded(1);
+                    if work_tx.send((chunk, reply)).is_err() || order_tx.send(parsed).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+
+            for _ in 0..self.parsers {
+                let work_rx = work_rx.clone();
+                scope.spawn(move || {
+                    for (chunk, reply) in work_rx {
+                        // receiver is gone if applying failed, nothing to do then.
+                        let _ = reply.send(parse_chunk(chunk, headers, origins));
+                    }
+                });
+            }
+            drop(work_rx);
+
+            for parsed in order_rx {
+                // sender is only gone if parser panicked, it's reported on join.
+                for row in parsed.recv().into_iter().flatten() {
+                    apply(row)?;
+                }
+            }
+            splitter.join().unwrap()?;
+            Ok(())
+        })
+    }
+}
+
+/// Row of the input, converted to `Transaction` with it's timestamp.
+struct Parsed {
+    result: Result<(Transaction, Option<Timestamp>), RejectReason>,
+    /// Where it came from, only if it was asked for.
+    origin: Option<Origin>,
+}
+
+/// Records of the input, that start at `position`.
+struct Chunk {
+    bytes: Vec<u8>,
+    position: csv::Position,
+}
+
+/// Parses records of the `chunk` to `Transaction`-s.
+fn parse_chunk(chunk: Chunk, headers: &csv::StringRecord, origins: bool) -> Vec<Parsed> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        // checked against the headers, since chunk doesn't start with them.
+        .flexible(true)
+        .from_reader(&chunk.bytes[..]);
+    let start = &chunk.position;
+    let mut rows = vec![];
+
+    // read as bytes, same as the sequential parser.
+    for (i, record) in rdr.byte_records().enumerate() {
+        let (result, origin) = match record {
+            Ok(mut record) => {
+                let mut position = start.clone();
+                if let Some(pos) = record.position() {
+                    position
+                        .set_byte(start.byte() + pos.byte())
+                        .set_line(start.line() + pos.line() - 1)
+                        .set_record(start.record() + i as u64);
+                }
+                if record.len() != headers.len() {
+                    // same as the error of the parser, that reads the whole input.
+                    let msg = format!(
+                        "CSV error: record {} (line: {}, byte: {}): found record \
+                         with {} fields, but the previous record has {} fields",
+                        position.record(),
+                        position.line(),
+                        position.byte(),
+                        record.len(),
+                        headers.len(),
+                    );
+                    let raw = if origins { raw_row(&record) } else { String::new() };
+                    let origin = Origin { line: position.line(), raw };
+                    (Err(RejectReason::Parse(msg)), origin)
+                } else {
+                    let raw = if origins { raw_row(&record) } else { String::new() };
+                    let origin = Origin { line: position.line(), raw };
+                    record.set_position(Some(position));
+                    (parse_record(&record, headers.as_byte_record()), origin)
+                }
+            },
+            Err(err) => {
+                let line = err.position().map_or(start.line(), |pos| start.line() + pos.line() - 1);
+                let origin = Origin { line, raw: String::new() };
+                (Err(RejectReason::Parse(err.to_string())), origin)
+            },
+        };
+        rows.push(Parsed { result, origin: Some(origin).filter(|_| origins) });
+    }
+    rows
+}
+
+/// Deserializes and converts the `record`.
+fn parse_record(
+    record: &csv::ByteRecord,
+    headers: &csv::ByteRecord,
+) -> Result<(Transaction, Option<Timestamp>), RejectReason> {
+    let input = record.deserialize::<InputTransaction>(Some(headers))
+        .map_err(|err| RejectReason::Parse(err.to_string()))?;
+    let timestamp = input.timestamp;
+    Ok((Transaction::try_from(input)?, timestamp))
+}
+
+/// Where in the record csv parser is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    /// At the start of the field, where it can be quoted.
+    Start,
+    Unquoted,
+    Quoted,
+    /// Quote inside the quoted field, either it's end or the escaped quote.
+    QuoteInQuoted,
+}
+
+/// Splits input into chunks that end at the record boundary.
+///
+/// Only bytes are scanned, with the same quoting rules as the csv
+/// parser, so it goes much faster than the parsers.
+struct Splitter<R> {
+    reader: R,
+    chunk_size: usize,
+    /// Bytes read, but not handed over yet.
+    buf: Vec<u8>,
+    /// Number of the `buf` bytes already scanned,
+    /// `field` and the counts are as of them.
+    scanned: usize,
+    field: Field,
+    /// Whether current record has anything in it.
+    in_record: bool,
+    /// Position of the start of the `buf`.
+    position: csv::Position,
+    /// Lines and records in the scanned bytes.
+    lines: u64,
+    records: u64,
+    eof: bool,
+}
+
+impl<R: io::Read> Splitter<R> {
+    fn new(reader: R, chunk_size: usize) -> Self {
+        let mut position = csv::Position::new();
+        position.set_line(1);
+        Self {
+            reader,
+            chunk_size,
+            buf: vec![],
+            scanned: 0,
+            field: Field::Start,
+            in_record: false,
+            position,
+            lines: 0,
+            records: 0,
+            eof: false,
+        }
+    }
+
+    /// Header record, `None` if the input is empty.
+    fn headers(&mut self) -> Result<Option<csv::StringRecord>, csv::Error> {
+        // empty lines before it are skipped, same as by the parser.
+        while let Some(chunk) = self.split(1)? {
+            if chunk.bytes.iter().any(|&byte| byte != b'\n' && byte != b'\r') {
+                let mut rdr = csv::Reader::from_reader(&chunk.bytes[..]);
+                return Ok(Some(rdr.headers()?.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Chunk>> {
+        self.split(self.chunk_size)
+    }
+
+    /// Next chunk of at least `size` bytes, or less at the end of input,
+    /// that ends at the record boundary.
+    fn split(&mut self, size: usize) -> io::Result<Option<Chunk>> {
+        loop {
+            let boundary = self.scan(size);
+            if let Some((end, lines, records)) = boundary {
+                return Ok(Some(self.take(end, lines, records)));
+            }
+            if self.eof {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                // last record doesn't have to end with a line break.
+                self.records += self.in_record as u64;
+                self.in_record = false;
+                let (lines, records) = (self.lines, self.records);
+                return Ok(Some(self.take(self.buf.len(), lines, records)));
+            }
+            self.fill(size)?;
+        }
+    }
+
+    /// Reads at least `size` more bytes, unless input ends before that.
+    fn fill(&mut self, size: usize) -> io::Result<()> {
+        let target = self.buf.len() + size;
+        let mut block = [0; 64 * 1024];
+        while self.buf.len() < target {
+            let read = match self.reader.read(&mut block) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                },
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            self.buf.extend_from_slice(&block[..read]);
+        }
+        Ok(())
+    }
+
+    /// Scans new bytes for the first record boundary after `size` bytes.
+    /// Returns it's offset in the `buf`, and lines and records before it.
+    fn scan(&mut self, size: usize) -> Option<(usize, u64, u64)> {
+        while self.scanned < self.buf.len() {
+            let byte = self.buf[self.scanned];
+            self.scanned += 1;
+
+            if byte == b'\n' {
+                self.lines += 1;
+            }
+            let record_end = match (self.field, byte) {
+                (Field::Quoted, b'"') => {
+                    self.field = Field::QuoteInQuoted;
+                    false
+                },
+                (Field::Quoted, _) => false,
+                (Field::Start, b'"') => {
+                    self.field = Field::Quoted;
+                    false
+                },
+                (Field::QuoteInQuoted, b'"') => {
+                    self.field = Field::Quoted;
+                    false
+                },
+                (_, b'\n') => {
+                    self.field = Field::Start;
+                    true
+                },
+                (_, b',') => {
+                    self.field = Field::Start;
+                    false
+                },
+                // `\r` before `\n`, only the `\n` ends the record.
+                (Field::Start, b'\r') => false,
+                _ => {
+                    self.field = Field::Unquoted;
+                    false
+                },
+            };
+
+            if record_end {
+                // empty lines are skipped by the parser, not counted as records.
+                self.records += self.in_record as u64;
+                self.in_record = false;
+                if self.scanned >= size {
+                    return Some((self.scanned, self.lines, self.records));
+                }
+            } else if byte != b'\r' {
+                self.in_record = true;
+            }
+        }
+        None
+    }
+
+    /// Hands over first `end` bytes of the `buf` as a chunk, with
+    /// `lines` and `records` in them.
+    fn take(&mut self, end: usize, lines: u64, records: u64) -> Chunk {
+        let rest = self.buf.split_off(end);
+        let bytes = std::mem::replace(&mut self.buf, rest);
+
+        let position = self.position.clone();
+        self.position
+            .set_byte(position.byte() + end as u64)
+            .set_line(position.line() + lines)
+            .set_record(position.record() + records);
+        self.scanned -= end;
+        self.lines -= lines;
+        self.records -= records;
+        Chunk { bytes, position }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_bank::BasicBank;
+    use crate::concurrent_bank::ConcurrentBank;
+    use crate::test_util::SharedBuf;
+
+    const INPUT: &str = "\
+type,client,tx,amount,reason
+deposit,1,1,10.0,
+deposit,2,2,5.0,\"multi
+line, with comma\"
+
+withdrawal,1,3,2.5,
+deposit,x,4,1.0,
+dispute,1,1,,\"say \"\"why\"\"\"
+deposit,3,5
+withdrawal,2,6,100.0,
+deposit,1,2,1.0,
+resolve,1,1,,
+lock,3,7,,\"fraud\"
+deposit,3,8,1.0,
+";
+
+    #[test]
+    fn chunks_end_at_record_boundaries() {
+        let mut splitter = Splitter::new(INPUT.as_bytes(), 1);
+        splitter.headers().unwrap();
+
+        let chunks: Vec<_> = std::iter::from_fn(|| splitter.next_chunk().unwrap()).collect();
+        let rows: Vec<_> = chunks.iter()
+            .map(|chunk| std::str::from_utf8(&chunk.bytes).unwrap())
+            .collect();
+        assert_eq!(rows[1], "deposit,2,2,5.0,\"multi\nline, with comma\"\n");
+        assert_eq!(rows[2], "\n");
+        assert_eq!(rows[5], "dispute,1,1,,\"say \"\"why\"\"\"\n");
+        assert_eq!(rows.concat(), INPUT.split_once('\n').unwrap().1);
+
+        assert_eq!(chunks[3].position.line(), 6);
+        assert_eq!(chunks[3].position.record(), 3);
+        assert_eq!(chunks[3].position.byte(), INPUT.find("withdrawal,1,3").unwrap() as u64);
+    }
+
+    #[test]
+    fn same_as_sequential() {
+        let mut expected = BasicBank::new();
+        let expected_rejects = SharedBuf::default();
+        let rejects = Rejects::new(expected_rejects.clone());
+        expected.apply_input_transactions_csv_with_rejects(INPUT.as_bytes(), &rejects).unwrap();
+        rejects.flush().unwrap();
+
+        for &chunk_size in &[1, 20, 1000] {
+            let pipeline = Pipeline::new(3).with_chunk_size(chunk_size);
+
+            let mut bank = BasicBank::new();
+            let buf = SharedBuf::default();
+            let rejects = Rejects::new(buf.clone());
+            pipeline.apply_csv_with_rejects(&mut bank, INPUT.as_bytes(), &rejects).unwrap();
+            rejects.flush().unwrap();
+            assert_eq!(bank.accounts(), expected.accounts());
+            assert_eq!(
+                buf.contents(),
+                expected_rejects.contents(),
+            );
+
+            let mut bank = ConcurrentBank::new_with_thread_count(2);
+            pipeline.apply_csv(&mut bank, INPUT.as_bytes()).unwrap();
+            assert_eq!(bank.accounts(), expected.accounts());
+        }
+    }
+
+    #[test]
+    fn empty_input() {
+        let mut bank = BasicBank::new();
+        Pipeline::new(2).apply_csv(&mut bank, "".as_bytes()).unwrap();
+        Pipeline::new(2).apply_csv(&mut bank, "type,client,tx,amount\n".as_bytes()).unwrap();
+        assert!(bank.accounts().is_empty());
+    }
+}
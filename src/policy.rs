@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use crate::transaction::Transaction;
+use crate::expiry::{Age, DisputeExpiry};
+use crate::rates::RateTable;
+
+/// Rules that [Account](crate::account::Account) follows
+/// when applying transactions.
+///
+/// `Default` policy follows the spec document.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Policy {
+    /// Whether `Transaction::Withdrawal` can be disputed.
+    ///
+    /// Disputing withdrawal credits it's amount back into `held`.
+    /// On resolve withdrawal stands and amount is released from `held`,
+    /// on chargeback amount is returned to `available`.
+    pub withdrawal_disputes: bool,
+    /// Whether `Transaction::Fee` can push `available` below zero.
+    /// Otherwise fee is refused if there aren't enough funds.
+    pub negative_fees: bool,
+    /// Disputes of the transactions older than this are refused.
+    pub dispute_window: Option<Age>,
+    /// Disputes that stay open longer than this are settled automatically.
+    pub dispute_expiry: Option<DisputeExpiry>,
+    /// Authorizations that stay open longer than this are voided automatically.
+    pub authorization_expiry: Option<Age>,
+    /// Rates of `Transaction::Convert`. Without them
+    /// all conversions are refused.
+    pub rates: Option<Arc<RateTable>>,
+}
+
+impl Policy {
+    /// Whether transaction can be disputed later,
+    /// so it has to be kept in account's history.
+    pub fn is_disputable(&self, tx: &Transaction) -> bool {
+        match tx {
+            Transaction::Deposit(_) => true,
+            Transaction::Withdrawal(_) => self.withdrawal_disputes,
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use rust_decimal::RoundingStrategy;
+use serde::{Serialize, Deserialize};
+
+use rust_decimal::prelude::{One, Zero};
+
+use crate::types::{Amount, Timestamp};
+use crate::currency::Currency;
+use crate::decimal_serde::PRECISION;
+
+/// Exchange rate between two currencies, row of the rate table.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rate {
+    pub from: Currency,
+    pub to: Currency,
+    /// Units of `to` for one unit of `from`.
+    pub rate: Amount,
+    /// Part of the converted amount kept by the bank, e.g. `0.002`.
+    /// Column can be missing from the table.
+    #[serde(default)]
+    pub spread: Amount,
+    /// Unix time in seconds, since when the rate applies.
+    pub effective_from: Timestamp,
+}
+
+impl Rate {
+    /// Checks that `rate` is positive and `spread` leaves
+    /// some of the converted amount to the client.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rate <= Amount::zero() {
+            return Err(format!("rate has to be positive: {}", self.rate));
+        }
+        if self.spread < Amount::zero() || self.spread >= Amount::one() {
+            return Err(format!("spread has to be at least 0 and less than 1: {}", self.spread));
+        }
+        Ok(())
+    }
+}
+
+/// How converted amounts are rounded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    /// Towards zero, same as amounts in the output.
+    Down,
+    /// Away from zero.
+    Up,
+    /// To the nearest, halfway away from zero.
+    HalfUp,
+    /// To the nearest, halfway to the even one.
+    HalfEven,
+}
+
+impl FromStr for RoundingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "down" => Ok(Self::Down),
+            "up" => Ok(Self::Up),
+            "half-up" => Ok(Self::HalfUp),
+            "half-even" => Ok(Self::HalfEven),
+            _ => Err(format!("unknown rounding mode: {:?}", s)),
+        }
+    }
+}
+
+/// Rounding of the converted amounts.
+///
+/// `Default` rounds down to 4 decimal places,
+/// same as amounts are written out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rounding {
+    /// Decimal places that are kept.
+    pub precision: u32,
+    pub mode: RoundingMode,
+}
+
+impl Default for Rounding {
+    fn default() -> Self {
+        Self { precision: PRECISION, mode: RoundingMode::Down }
+    }
+}
+
+impl Rounding {
+    pub fn round(&self, amount: Amount) -> Amount {
+        let strategy = match self.mode {
+            RoundingMode::Down => RoundingStrategy::RoundDown,
+            RoundingMode::Up => RoundingStrategy::RoundUp,
+            RoundingMode::HalfUp => RoundingStrategy::RoundHalfUp,
+            RoundingMode::HalfEven => RoundingStrategy::BankersRounding,
+        };
+        amount.round_dp_with_strategy(self.precision, strategy)
+    }
+}
+
+/// Amount converted at the rate from the table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quote {
+    /// Rate that was applied.
+    pub rate: Rate,
+    /// Amount credited in the target currency, after
+    /// the spread and rounding.
+    pub credited: Amount,
+    /// Rest of the converted amount, that the bank kept.
+    pub spread: Amount,
+}
+
+/// Exchange rates of the currency pairs, each with the history
+/// of changes, loaded from the local csv file with
+/// `from,to,rate,spread,effective_from` columns.
+#[derive(Debug, Clone, Default)]
+pub struct RateTable {
+    /// Rates of every pair, ordered by `effective_from`.
+    rates: HashMap<(Currency, Currency), Vec<Rate>>,
+    rounding: Rounding,
+}
+
+impl RateTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Same table, but converted amounts are rounded with `rounding`.
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Reads the table from csv with a header row. Invalid
+    /// rates are refused, see [Rate::validate].
+    pub fn from_csv<R: io::Read>(reader: R) -> Result<Self, csv::Error> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let headers = rdr.headers()?.clone();
+
+        let mut table = Self::new();
+        for record in rdr.records() {
+            let record = record?;
+            let rate: Rate = record.deserialize(Some(&headers))?;
+            table.insert(rate).map_err(|msg| {
+                let line = record.position().map_or(0, |pos| pos.line());
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+            })?;
+        }
+        Ok(table)
+    }
+
+    /// Reads the table from csv file at `path`.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Self, csv::Error> {
+        Self::from_csv(File::open(path)?)
+    }
+
+    /// Adds the rate, replacing the one of the same pair
+    /// that is effective from the same time.
+    pub fn insert(&mut self, rate: Rate) -> Result<(), String> {
+        rate.validate()?;
+        let rates = self.rates.entry((rate.from, rate.to)).or_default();
+        match rates.binary_search_by_key(&rate.effective_from, |rate| rate.effective_from) {
+            Ok(i) => rates[i] = rate,
+            Err(i) => rates.insert(i, rate),
+        }
+        Ok(())
+    }
+
+    /// Rate from `from` to `to` that is effective at `timestamp`,
+    /// or the latest one if there's no timestamp.
+    pub fn rate(&self, from: Currency, to: Currency, timestamp: Option<Timestamp>) -> Option<&Rate> {
+        let rates = self.rates.get(&(from, to))?;
+        match timestamp {
+            Some(timestamp) => rates.iter()
+                .take_while(|rate| rate.effective_from <= timestamp)
+                .last(),
+            None => rates.last(),
+        }
+    }
+
+    /// Converts `amount` from `from` to `to` at the rate effective
+    /// at `timestamp`. `None` if there's no such rate.
+    ///
+    /// Credited amount is never more than the converted one rounded
+    /// down, so rounding up can't make the spread negative.
+    pub fn convert(
+        &self,
+        from: Currency,
+        to: Currency,
+        amount: Amount,
+        timestamp: Option<Timestamp>,
+    ) -> Option<Quote> {
+        let rate = *self.rate(from, to, timestamp)?;
+        let converted = amount * rate.rate;
+        let most = converted.round_dp_with_strategy(self.rounding.precision, RoundingStrategy::RoundDown);
+        let credited = self.rounding.round(converted * (Amount::one() - rate.spread)).min(most);
+        Some(Quote { rate, credited, spread: converted - credited })
+    }
+}
+
+/// Conversions between a pair of currencies, summed up.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpreadTotal {
+    pub from: Currency,
+    pub to: Currency,
+    /// Sum debited in `from`.
+    pub debited: Amount,
+    /// Sum credited in `to`.
+    pub credited: Amount,
+    /// Sum kept by the bank in `to`, with the rounding leftovers.
+    pub spread: Amount,
+}
+
+/// Serializes spread collected by the conversions to writer as csv,
+/// one row per currency pair, so totals of the same pair are summed.
+pub fn write_spread_csv<'a, W, I>(writer: W, totals: I) -> Result<(), csv::Error>
+where W: io::Write,
+      I: IntoIterator<Item = &'a SpreadTotal>,
+{
+    let mut pairs: BTreeMap<_, SpreadTotal> = BTreeMap::new();
+    for total in totals {
+        pairs.entry((total.from, total.to))
+            .and_modify(|sum| {
+                sum.debited += total.debited;
+                sum.credited += total.credited;
+                sum.spread += total.spread;
+            })
+            .or_insert(*total);
+    }
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    for total in pairs.values() {
+        wtr.serialize(total)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "\
+from,to,rate,spread,effective_from
+USD,EUR,0.9,0.01,100
+USD,EUR,0.8,0,200
+EUR,USD,1.1,0,100
+";
+
+    fn dec(val: &str) -> Amount {
+        Amount::from_str(val).unwrap()
+    }
+
+    #[test]
+    fn effective_rates() {
+        let table = RateTable::from_csv(TABLE.as_bytes()).unwrap();
+        let usd = "USD".parse().unwrap();
+        let eur = "EUR".parse().unwrap();
+        let rate = |timestamp| table.rate(usd, eur, timestamp).map(|rate| rate.rate);
+
+        assert_eq!(rate(Some(99)), None);
+        assert_eq!(rate(Some(100)), Some(dec("0.9")));
+        assert_eq!(rate(Some(199)), Some(dec("0.9")));
+        assert_eq!(rate(Some(200)), Some(dec("0.8")));
+        assert_eq!(rate(None), Some(dec("0.8")));
+        assert!(table.rate(usd, "GBP".parse().unwrap(), None).is_none());
+    }
+
+    #[test]
+    fn convert_with_rounding() {
+        let table = RateTable::from_csv(TABLE.as_bytes()).unwrap();
+        let usd = "USD".parse().unwrap();
+        let eur = "EUR".parse().unwrap();
+
+        // 1.23456 * 0.9 = 1.111104, 1% of it is kept.
+        let quote = table.convert(usd, eur, dec("1.23456"), Some(150)).unwrap();
+        assert_eq!(quote.credited, dec("1.0999"));
+        assert_eq!(quote.spread, dec("0.011204"));
+
+        let table = table.with_rounding(Rounding { precision: 2, mode: RoundingMode::HalfUp });
+        let quote = table.convert(usd, eur, dec("1.23456"), Some(150)).unwrap();
+        assert_eq!(quote.credited, dec("1.10"));
+
+        // without spread, rounding up would credit more than converted.
+        let table = table.with_rounding(Rounding { precision: 4, mode: RoundingMode::Up });
+        let quote = table.convert(usd, eur, dec("1.23456"), Some(200)).unwrap();
+        assert_eq!(quote.credited, dec("0.9876"));
+        assert_eq!(quote.spread, dec("0.000048"));
+    }
+
+    #[test]
+    fn invalid_rates_refused() {
+        let table = |row: &str| {
+            let csv = format!("from,to,rate,spread,effective_from\nUSD,EUR,0.9,0,100\n{}\n", row);
+            RateTable::from_csv(csv.as_bytes()).map(|_| ()).map_err(|err| err.to_string())
+        };
+
+        assert!(table("USD,EUR,0.8,0.999,200").is_ok());
+        assert_eq!(table("USD,EUR,0,0,200"), Err("line 3: rate has to be positive: 0".to_owned()));
+        assert!(table("USD,EUR,-1,0,200").is_err());
+        assert!(table("USD,EUR,0.8,1,200").is_err());
+        assert!(table("USD,EUR,0.8,-0.01,200").is_err());
+
+        let mut table = RateTable::new();
+        let usd = "USD".parse().unwrap();
+        let eur = "EUR".parse().unwrap();
+        let rate = Rate { from: usd, to: eur, rate: dec("0.9"), spread: dec("1.5"), effective_from: 0 };
+        assert!(table.insert(rate).is_err());
+        assert!(table.rate(usd, eur, None).is_none());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use crate::types::ClientID;
+
+/// When [ConcurrentBank](crate::ConcurrentBank) moves clients from
+/// the busiest thread to the idle ones, see
+/// [Builder::with_rebalancing](crate::concurrent_bank::Builder::with_rebalancing).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct Rebalancing {
+    /// Number of transactions, after which throughput of the threads
+    /// is compared and counting starts again.
+    pub window: u64,
+    /// How many times more than the average the busiest thread
+    /// has to get in the window, for it's clients to be moved.
+    pub imbalance: f64,
+    /// Most clients moved after a window.
+    pub max_moves: usize,
+}
+
+impl Default for Rebalancing {
+    fn default() -> Self {
+        Self { window: 10_000, imbalance: 1.25, max_moves: 16 }
+    }
+}
+
+/// Client moved from one thread to the other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Move {
+    pub client_id: ClientID,
+    pub from: usize,
+    pub to: usize,
+}
+
+/// Transactions sent to every thread and client in the current window.
+#[derive(Debug, Default)]
+pub(crate) struct Throughput {
+    seen: u64,
+    threads: Vec<u64>,
+    /// Thread and transactions of every client seen in the window.
+    clients: HashMap<ClientID, (usize, u64)>,
+}
+
+impl Throughput {
+    /// Counts transaction of `client_id` sent to `thread` of `count` threads.
+    pub fn record(&mut self, client_id: ClientID, thread: usize, count: usize) {
+        self.threads.resize(count, 0);
+        self.threads[thread] += 1;
+        self.seen += 1;
+        let client = self.clients.entry(client_id).or_insert((thread, 0));
+        *client = (thread, client.1 + 1);
+    }
+
+    /// Number of transactions counted in the window.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// Ends the window and plans which clients of the busiest thread go
+    /// to the least busy ones. It's busiest client stays, since it's
+    /// what keeps the thread busy, others go, starting with the busiest
+    /// ones, as long as that makes threads more even.
+    pub fn plan(&mut self, rebalancing: &Rebalancing) -> Vec<Move> {
+        let threads = std::mem::take(&mut self.threads);
+        let clients = std::mem::take(&mut self.clients);
+        self.seen = 0;
+
+        let total: u64 = threads.iter().sum();
+        let (hot, hot_load) = match threads.iter().enumerate().max_by_key(|&(_, &load)| load) {
+            Some((hot, &load)) if threads.len() > 1 => (hot, load),
+            _ => return vec![],
+        };
+        let average = total as f64 / threads.len() as f64;
+        if hot_load as f64 <= average * rebalancing.imbalance {
+            return vec![];
+        }
+
+        let mut hot_clients: Vec<_> = clients.into_iter()
+            .filter(|&(_, (thread, _))| thread == hot)
+            .map(|(client_id, (_, load))| (load, client_id))
+            .collect();
+        // ties broken by id, so the same input moves the same clients.
+        hot_clients.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut loads = threads;
+        let mut moves = vec![];
+        for &(load, client_id) in hot_clients.iter().skip(1) {
+            if moves.len() >= rebalancing.max_moves {
+                break;
+            }
+            let cold = (0..loads.len()).min_by_key(|&thread| loads[thread]).unwrap_or(hot);
+            // thread it goes to can't become busier than the one it leaves.
+            if loads[cold] + load > loads[hot] - load {
+                continue;
+            }
+            loads[hot] -= load;
+            loads[cold] += load;
+            moves.push(Move { client_id, from: hot, to: cold });
+        }
+        moves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(throughput: &mut Throughput, client_id: ClientID, thread: usize, times: u64) {
+        for _ in 0..times {
+            throughput.record(client_id, thread, 3);
+        }
+    }
+
+    #[test]
+    fn moves_clients_away_from_busy_thread() {
+        let mut throughput = Throughput::default();
+        record(&mut throughput, 1, 0, 60);
+        record(&mut throughput, 2, 0, 20);
+        record(&mut throughput, 3, 0, 10);
+        record(&mut throughput, 4, 0, 1);
+        record(&mut throughput, 5, 1, 9);
+
+        let moves = throughput.plan(&Rebalancing::default());
+        assert_eq!(moves, vec![
+            Move { client_id: 2, from: 0, to: 2 },
+            Move { client_id: 3, from: 0, to: 1 },
+            Move { client_id: 4, from: 0, to: 1 },
+        ]);
+        // window starts again.
+        assert_eq!(throughput.seen(), 0);
+        assert!(throughput.plan(&Rebalancing::default()).is_empty());
+    }
+
+    #[test]
+    fn even_threads_stay() {
+        let mut throughput = Throughput::default();
+        record(&mut throughput, 1, 0, 11);
+        record(&mut throughput, 2, 1, 10);
+        record(&mut throughput, 3, 2, 9);
+        assert!(throughput.plan(&Rebalancing::default()).is_empty());
+
+        // single client can't be split.
+        record(&mut throughput, 1, 0, 100);
+        assert!(throughput.plan(&Rebalancing::default()).is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+
+use crate::error::EngineError;
+
+/// Where in the input transaction came from.
+#[derive(Debug, Clone)]
+pub struct Origin {
+    /// Line number in the input, starting from 1 (header line).
+    pub line: u64,
+    /// Row as it was in the input.
+    pub raw: String,
+}
+
+/// Why input row was rejected.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RejectReason {
+    /// Row couldn't be read/deserialized.
+    Parse(String),
+    /// Row was parsed, but refused by the engine.
+    Engine(EngineError),
+}
+
+impl RejectReason {
+    /// Machine-readable reason code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Parse(_) => "parse_error",
+            Self::Engine(err) => err.code(),
+        }
+    }
+}
+
+impl From<EngineError> for RejectReason {
+    fn from(err: EngineError) -> Self {
+        Self::Engine(err)
+    }
+}
+
+/// Input row that was rejected.
+#[derive(Debug)]
+pub struct Reject {
+    pub origin: Origin,
+    pub reason: RejectReason,
+}
+
+/// Row of the rejects report.
+#[derive(Serialize)]
+struct OutputReject<'a> {
+    source: &'a str,
+    line: u64,
+    reason: &'static str,
+    message: String,
+    raw: &'a str,
+}
+
+impl<'a> OutputReject<'a> {
+    fn new(source: &'a str, reject: &'a Reject) -> Self {
+        let message = match &reject.reason {
+            RejectReason::Parse(msg) => msg.clone(),
+            RejectReason::Engine(err) => err.to_string(),
+        };
+
+        Self {
+            source,
+            line: reject.origin.line,
+            reason: reject.reason.code(),
+            message,
+            raw: &reject.origin.raw,
+        }
+    }
+}
+
+/// Csv report of rejected rows.
+///
+/// Cheap to clone and can be shared between threads, since
+/// in [ConcurrentBank](crate::concurrent_bank::ConcurrentBank)
+/// rows get rejected inside the bank threads.
+#[derive(Clone)]
+pub struct Rejects {
+    writer: Arc<Mutex<csv::Writer<Box<dyn io::Write + Send>>>>,
+    /// Name of the input rows come from, when there are multiple.
+    source: Option<Arc<str>>,
+}
+
+impl Rejects {
+    pub fn new<W>(writer: W) -> Self
+    where W: io::Write + Send + 'static,
+    {
+        let writer: Box<dyn io::Write + Send> = Box::new(writer);
+        Self {
+            writer: Arc::new(Mutex::new(csv::Writer::from_writer(writer))),
+            source: None,
+        }
+    }
+
+    /// Same report, but rows reported through returned `Rejects`
+    /// are marked as coming from the `source` input.
+    pub fn for_source(&self, source: &str) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// Writes rejected row to the report.
+    pub fn report(&self, reject: Reject) -> Result<(), csv::Error> {
+        let source = self.source.as_deref().unwrap_or_default();
+        let mut writer = self.writer.lock().unwrap();
+        writer.serialize(OutputReject::new(source, &reject))
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::SharedBuf;
+
+    #[test]
+    fn report_rejects() {
+        let buf = SharedBuf::default();
+        let rejects = Rejects::new(buf.clone());
+
+        rejects.for_source("a.csv").report(Reject {
+            origin: Origin { line: 2, raw: "deposit,1,1,".to_owned() },
+            reason: EngineError::MissingAmount { client_id: 1, tx_id: 1 }.into(),
+        }).unwrap();
+        rejects.report(Reject {
+            origin: Origin { line: 3, raw: "deposit,x".to_owned() },
+            reason: RejectReason::Parse("bad row".to_owned()),
+        }).unwrap();
+        rejects.flush().unwrap();
+
+        let output = buf.contents();
+        let lines: Vec<_> = output.lines().collect();
+
+        assert_eq!(lines[0], "source,line,reason,message,raw");
+        assert!(lines[1].starts_with("a.csv,2,missing_amount,"));
+        assert!(lines[1].ends_with(",\"deposit,1,1,\""));
+        assert_eq!(lines[2], ",3,parse_error,bad row,\"deposit,x\"");
+    }
+}
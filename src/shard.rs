@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use serde::Deserialize;
+
+use crate::types::ClientID;
+
+/// Decides which thread of the [ConcurrentBank](crate::ConcurrentBank)
+/// manages the account of the client.
+///
+/// Client has to get the same shard every time, since it's account
+/// lives there, so strategies that decide based on the load have to
+/// remember their decisions.
+pub trait ShardStrategy: Send {
+    /// Shard of `client_id`, index into `loads`, which are the numbers
+    /// of transactions waiting in the queue of every shard.
+    fn shard(&mut self, client_id: ClientID, loads: &[usize]) -> usize;
+
+    /// Whether shard of `client_id` depends on the `loads`. When it
+    /// doesn't, they aren't measured and can be outdated.
+    fn needs_loads(&self, _client_id: ClientID) -> bool {
+        true
+    }
+}
+
+/// `client_id % count`. Ids that differ by a multiple of the count,
+/// e.g. `1, 9, 17` with 8 shards, all get the same shard.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Modulo;
+
+impl ShardStrategy for Modulo {
+    fn shard(&mut self, client_id: ClientID, loads: &[usize]) -> usize {
+        client_id as usize % loads.len()
+    }
+
+    fn needs_loads(&self, _client_id: ClientID) -> bool {
+        false
+    }
+}
+
+/// Mixes bits of the client id before taking modulo,
+/// so regular patterns of ids spread across shards.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Hashed;
+
+impl ShardStrategy for Hashed {
+    fn shard(&mut self, client_id: ClientID, loads: &[usize]) -> usize {
+        (mix(client_id) % loads.len() as u64) as usize
+    }
+
+    fn needs_loads(&self, _client_id: ClientID) -> bool {
+        false
+    }
+}
+
+/// Finalizer of the splitmix64, every bit of the id affects every bit of the result.
+fn mix(client_id: ClientID) -> u64 {
+    let mut x = client_id as u64;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Row of the mapping file.
+#[derive(Debug, Deserialize)]
+struct MappingRow {
+    #[serde(rename = "client")]
+    client_id: ClientID,
+    shard: usize,
+}
+
+/// Shards from the mapping file with `client,shard` columns. Clients
+/// that aren't in it are left to the `fallback`. Shards past the
+/// number of threads wrap around.
+pub struct Mapping {
+    shards: HashMap<ClientID, usize>,
+    fallback: Box<dyn ShardStrategy>,
+}
+
+impl Mapping {
+    pub fn new(shards: HashMap<ClientID, usize>, fallback: Box<dyn ShardStrategy>) -> Self {
+        Self { shards, fallback }
+    }
+
+    /// Reads the mapping from csv with a header row.
+    pub fn from_csv<R: io::Read>(
+        reader: R,
+        fallback: Box<dyn ShardStrategy>,
+    ) -> Result<Self, csv::Error> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+
+        let mut shards = HashMap::new();
+        for row in rdr.deserialize() {
+            let row: MappingRow = row?;
+            shards.insert(row.client_id, row.shard);
+        }
+        Ok(Self::new(shards, fallback))
+    }
+
+    /// Reads the mapping from csv file at `path`.
+    pub fn read_file<P: AsRef<Path>>(
+        path: P,
+        fallback: Box<dyn ShardStrategy>,
+    ) -> Result<Self, csv::Error> {
+        Self::from_csv(File::open(path)?, fallback)
+    }
+}
+
+impl ShardStrategy for Mapping {
+    fn shard(&mut self, client_id: ClientID, loads: &[usize]) -> usize {
+        match self.shards.get(&client_id) {
+            Some(&shard) => shard % loads.len(),
+            None => self.fallback.shard(client_id, loads),
+        }
+    }
+
+    fn needs_loads(&self, client_id: ClientID) -> bool {
+        !self.shards.contains_key(&client_id) && self.fallback.needs_loads(client_id)
+    }
+}
+
+/// Gives new client the shard with the shortest queue, or the one
+/// with fewest clients if queues are equal, e.g. at the start.
+/// Shard of the client doesn't change after that.
+#[derive(Debug, Clone, Default)]
+pub struct LoadAware {
+    assigned: HashMap<ClientID, usize>,
+    /// Number of clients assigned to every shard.
+    clients: Vec<usize>,
+}
+
+impl ShardStrategy for LoadAware {
+    fn shard(&mut self, client_id: ClientID, loads: &[usize]) -> usize {
+        if let Some(&shard) = self.assigned.get(&client_id) {
+            return shard;
+        }
+
+        self.clients.resize(loads.len(), 0);
+        let shard = (0..loads.len())
+            .min_by_key(|&shard| (loads[shard], self.clients[shard]))
+            .unwrap_or(0);
+        self.clients[shard] += 1;
+        self.assigned.insert(client_id, shard);
+        shard
+    }
+
+    /// Only new clients need them, others keep their shards.
+    fn needs_loads(&self, client_id: ClientID) -> bool {
+        !self.assigned.contains_key(&client_id)
+    }
+}
+
+/// Built-in strategies that don't need any configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Sharding {
+    /// See [Modulo].
+    #[default]
+    Modulo,
+    /// See [Hashed].
+    Hashed,
+    /// See [LoadAware].
+    LoadAware,
+}
+
+impl FromStr for Sharding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "modulo" => Ok(Self::Modulo),
+            "hashed" => Ok(Self::Hashed),
+            "load-aware" => Ok(Self::LoadAware),
+            _ => Err(format!("unknown sharding: {:?}", s)),
+        }
+    }
+}
+
+impl Sharding {
+    pub fn strategy(self) -> Box<dyn ShardStrategy> {
+        match self {
+            Self::Modulo => Box::new(Modulo),
+            Self::Hashed => Box::new(Hashed),
+            Self::LoadAware => Box::new(LoadAware::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of clients from `client_ids` that every shard gets.
+    fn spread<I>(strategy: &mut dyn ShardStrategy, client_ids: I) -> Vec<usize>
+    where I: IntoIterator<Item = ClientID>,
+    {
+        let loads = [0; 8];
+        let mut clients = vec![0; 8];
+        for client_id in client_ids {
+            clients[strategy.shard(client_id, &loads)] += 1;
+        }
+        clients
+    }
+
+    #[test]
+    fn strided_ids() {
+        let strided = || (0..800).map(|i| i * 8 + 1);
+
+        assert_eq!(spread(&mut Modulo, strided()), vec![0, 800, 0, 0, 0, 0, 0, 0]);
+        assert!(spread(&mut Hashed, strided()).iter().all(|&clients| clients > 50));
+        assert_eq!(spread(&mut LoadAware::default(), strided()), vec![100; 8]);
+    }
+
+    #[test]
+    fn load_aware() {
+        let mut strategy = LoadAware::default();
+
+        assert_eq!(strategy.shard(1, &[5, 0, 3]), 1);
+        assert_eq!(strategy.shard(2, &[5, 4, 3]), 2);
+        // clients keep their shards.
+        assert_eq!(strategy.shard(1, &[0, 9, 0]), 1);
+        assert_eq!(strategy.shard(3, &[0, 9, 0]), 0);
+
+        assert!(!strategy.needs_loads(1));
+        assert!(strategy.needs_loads(4));
+    }
+
+    #[test]
+    fn mapping() {
+        let mut strategy = Mapping::from_csv(
+            "client,shard\n1,3\n2,9\n".as_bytes(),
+            Box::new(Modulo),
+        ).unwrap();
+        let loads = [0; 4];
+
+        assert_eq!(strategy.shard(1, &loads), 3);
+        assert_eq!(strategy.shard(2, &loads), 1);
+        assert_eq!(strategy.shard(6, &loads), 2);
+        assert!(!strategy.needs_loads(6));
+
+        let strategy = Mapping::from_csv(
+            "client,shard\n1,3\n".as_bytes(),
+            Box::new(LoadAware::default()),
+        ).unwrap();
+        assert!(!strategy.needs_loads(1));
+        assert!(strategy.needs_loads(2));
+    }
+}
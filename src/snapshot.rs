@@ -0,0 +1,174 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+
+use crate::types::{ClientID, TransactionID};
+use crate::account::Account;
+use crate::id_set::IdSet;
+use crate::expiry::Moment;
+
+/// Version of the snapshot format. Bumped on incompatible changes.
+pub const SNAPSHOT_VERSION: u32 = 7;
+
+/// First word of the snapshot file, followed by it's version.
+const MAGIC: &str = "payments-engine-snapshot";
+
+/// Complete state of the [Bank](crate::bank::Bank), so that
+/// processing can be continued from it later.
+///
+/// Serialized as a header line with format version,
+/// followed by json of the state.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Accounts ordered by client id.
+    accounts: Vec<Account>,
+    /// All transaction ids seen by the bank.
+    tx_ids: IdSet,
+    /// Owners of the transactions that can be disputed.
+    tx_owners: Vec<(TransactionID, ClientID)>,
+    /// Moment of the last transaction seen by the bank.
+    clock: Moment,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        mut accounts: Vec<Account>,
+        tx_ids: IdSet,
+        mut tx_owners: Vec<(TransactionID, ClientID)>,
+        clock: Moment,
+    ) -> Self {
+        accounts.sort_by_key(Account::client_id);
+        tx_owners.sort_unstable();
+        Self { accounts, tx_ids, tx_owners, clock }
+    }
+
+    pub(crate) fn into_parts(self) -> (Vec<Account>, IdSet, Vec<(TransactionID, ClientID)>) {
+        (self.accounts, self.tx_ids, self.tx_owners)
+    }
+
+    /// Moment of the last transaction seen by the bank,
+    /// numbering of transactions continues from it.
+    pub fn clock(&self) -> Moment {
+        self.clock
+    }
+
+    /// Accounts ordered by client id.
+    pub fn accounts(&self) -> &[Account] {
+        &self.accounts
+    }
+
+    pub fn into_accounts(self) -> Vec<Account> {
+        self.accounts
+    }
+
+    pub fn read_from<R: io::Read>(reader: R) -> Result<Self, SnapshotError> {
+        let mut reader = io::BufReader::new(reader);
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+
+        let mut words = header.split_whitespace();
+        if words.next() != Some(MAGIC) {
+            return Err(SnapshotError::NotSnapshot);
+        }
+        let version = words.next()
+            .and_then(|version| version.parse().ok())
+            .ok_or(SnapshotError::NotSnapshot)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn write_to<W: io::Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        let mut writer = io::BufWriter::new(writer);
+        writeln!(writer, "{} {}", MAGIC, SNAPSHOT_VERSION)?;
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+        Self::read_from(File::open(path)?)
+    }
+
+    /// Writes snapshot to a temporary file first and then renames it,
+    /// so that existing snapshot at `path` is never left half-written.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        self.write_to(&mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// Snapshot state couldn't be (de)serialized.
+    Format(serde_json::Error),
+    /// Input doesn't start with the snapshot header.
+    NotSnapshot,
+    /// Snapshot was written with unsupported format version.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Format(err) => write!(f, "invalid snapshot: {}", err),
+            Self::NotSnapshot => write!(f, "not a snapshot file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported snapshot version {}, expected {}",
+                version,
+                SNAPSHOT_VERSION,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Format(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_unsupported_version() {
+        let input = format!("{} {}\n{{}}", MAGIC, SNAPSHOT_VERSION + 1);
+        assert!(matches!(
+            Snapshot::read_from(input.as_bytes()),
+            Err(SnapshotError::UnsupportedVersion(v)) if v == SNAPSHOT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn read_not_snapshot() {
+        assert!(matches!(
+            Snapshot::read_from("type,client,tx,amount\n".as_bytes()),
+            Err(SnapshotError::NotSnapshot)
+        ));
+    }
+}
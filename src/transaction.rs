@@ -1,26 +1,173 @@
 use std::convert::TryFrom;
+use rust_decimal::prelude::Zero;
+use serde::{Serialize, Deserialize};
 
 use crate::types::{ClientID, TransactionID, Amount};
+use crate::currency::Currency;
 use crate::input_transaction::InputTransaction;
+use crate::error::EngineError;
 
 /// Ref to the existing transaction.
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransactionRef {
     pub client_id: ClientID,
     pub tx_id: TransactionID,
+    /// Part of the referenced transaction's amount that is disputed/
+    /// resolved/charged back/captured/voided. `None` means all of it.
+    pub amount: Option<Amount>,
 }
 
 /// Transaction info.
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransactionInfo {
     pub client_id: ClientID,
     pub tx_id: TransactionID,
     pub amount: Amount,
-    pub under_dispute: bool,
+    /// Currency of the `amount`.
+    #[serde(default)]
+    pub currency: Currency,
+}
+
+impl TransactionRef {
+    /// Ref to the whole amount of the existing transaction.
+    pub fn new(client_id: ClientID, tx_id: TransactionID) -> Self {
+        Self { client_id, tx_id, amount: None }
+    }
+
+    /// Ref to only `amount` of the existing transaction.
+    pub fn with_amount(mut self, amount: Amount) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+}
+
+/// Checks that part of the referenced transaction is positive, since
+/// zero would move no funds, but still count as a dispute.
+pub(crate) fn check_ref_amount(
+    client_id: ClientID,
+    tx_id: TransactionID,
+    amount: Option<Amount>,
+) -> Result<(), EngineError> {
+    match amount {
+        Some(amount) if amount.is_sign_negative() && !amount.is_zero() => {
+            Err(EngineError::NegativeAmount { client_id, tx_id, amount })
+        },
+        Some(amount) if amount.is_zero() => Err(EngineError::ZeroAmount { client_id, tx_id }),
+        _ => Ok(()),
+    }
+}
+
+impl TransactionInfo {
+    /// Transaction in the default currency.
+    pub fn new(client_id: ClientID, tx_id: TransactionID, amount: Amount) -> Self {
+        Self { client_id, tx_id, amount, currency: Currency::default() }
+    }
+
+    /// Same transaction in `currency`.
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+}
+
+/// Transfer of funds from the account of `client_id` to the account of `to`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferInfo {
+    /// Source client, whose funds are transferred.
+    pub client_id: ClientID,
+    /// Destination client.
+    pub to: ClientID,
+    pub tx_id: TransactionID,
+    pub amount: Amount,
+    /// Currency of the `amount`.
+    #[serde(default)]
+    pub currency: Currency,
+}
+
+impl TransferInfo {
+    /// Transfer in the default currency.
+    pub fn new(client_id: ClientID, to: ClientID, tx_id: TransactionID, amount: Amount) -> Self {
+        Self { client_id, to, tx_id, amount, currency: Currency::default() }
+    }
+
+    /// Same transfer in `currency`.
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+}
+
+/// Manual correction of the account's balance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adjustment {
+    pub client_id: ClientID,
+    pub tx_id: TransactionID,
+    /// Credited to the account if positive, debited if negative.
+    pub amount: Amount,
+    /// Currency of the `amount`.
+    #[serde(default)]
+    pub currency: Currency,
+    /// Reason of the correction, kept in the audit log.
+    pub reason: String,
+}
+
+impl Adjustment {
+    /// Correction in the default currency.
+    pub fn new(client_id: ClientID, tx_id: TransactionID, amount: Amount, reason: String) -> Self {
+        Self { client_id, tx_id, amount, currency: Currency::default(), reason }
+    }
+
+    /// Same correction in `currency`.
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+}
+
+/// Conversion of funds between two currency balances of the account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversion {
+    pub client_id: ClientID,
+    pub tx_id: TransactionID,
+    /// Debited in `currency`.
+    pub amount: Amount,
+    /// Source currency.
+    #[serde(default)]
+    pub currency: Currency,
+    /// Target currency, it's credited at the rate from the rate table.
+    pub to_currency: Currency,
+}
+
+impl Conversion {
+    pub fn new(
+        client_id: ClientID,
+        tx_id: TransactionID,
+        amount: Amount,
+        currency: Currency,
+        to_currency: Currency,
+    ) -> Self {
+        Self { client_id, tx_id, amount, currency, to_currency }
+    }
+}
+
+/// Manual change of the account's locked state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountAction {
+    pub client_id: ClientID,
+    pub tx_id: TransactionID,
+    /// Optional reason code of the action, kept in the audit log.
+    pub reason: Option<String>,
+}
+
+impl AccountAction {
+    pub fn new(client_id: ClientID, tx_id: TransactionID, reason: Option<String>) -> Self {
+        Self { client_id, tx_id, reason }
+    }
 }
 
 /// Different types of transactions that are supported.
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum Transaction {
     /// Money deposited/added to the account.
     Deposit(TransactionInfo),
@@ -32,11 +179,56 @@ pub enum Transaction {
     Resolve(TransactionRef),
     /// Chargeback `Dispute`. It freezes/locks the account.
     ChargeBack(TransactionRef),
+    /// Manually lock/freeze the account.
+    Lock(AccountAction),
+    /// Unlock/reinstate the locked account.
+    Unlock(AccountAction),
+    /// Money moved from one account to another. It's applied
+    /// to both accounts or to neither of them.
+    Transfer(TransferInfo),
+    /// Fee charged from the account. Depending on the policy,
+    /// it can push `available` below zero.
+    Fee(TransactionInfo),
+    /// Manual correction of the account's balance.
+    Adjustment(Adjustment),
+    /// Funds reserved for the later capture. They can't
+    /// be withdrawn untill they're voided.
+    Authorize(TransactionInfo),
+    /// Settle the reserved funds of the existing `Authorize`.
+    Capture(TransactionRef),
+    /// Release the reserved funds of the existing `Authorize`.
+    Void(TransactionRef),
+    /// Funds converted from one currency balance to another.
+    Convert(Conversion),
 }
 
 impl Transaction {
+    /// Whether `Transaction` references another, existing `Transaction`.
     pub fn is_ref(&self) -> bool {
-        !matches!(self, Self::Deposit(_) | Self::Withdrawal(_))
+        matches!(
+            self,
+            Self::Dispute(_) | Self::Resolve(_) | Self::ChargeBack(_) | Self::Capture(_) | Self::Void(_)
+        )
+    }
+
+    /// Whether `Transaction` is kept in account's history, so that
+    /// it can be referenced later.
+    pub fn is_recorded(&self) -> bool {
+        matches!(self, Self::Deposit(_) | Self::Withdrawal(_))
+    }
+
+    /// Whether `Transaction` has it's own id, that can't be
+    /// used by any other transaction.
+    pub fn claims_id(&self) -> bool {
+        self.is_recorded()
+            || matches!(
+                self,
+                Self::Transfer(_)
+                    | Self::Fee(_)
+                    | Self::Adjustment(_)
+                    | Self::Authorize(_)
+                    | Self::Convert(_)
+            )
     }
 
     pub fn get_client_id(&self) -> ClientID {
@@ -46,6 +238,15 @@ impl Transaction {
             Transaction::Dispute(tx) => tx.client_id,
             Transaction::Resolve(tx) => tx.client_id,
             Transaction::ChargeBack(tx) => tx.client_id,
+            Transaction::Lock(tx) => tx.client_id,
+            Transaction::Unlock(tx) => tx.client_id,
+            Transaction::Transfer(tx) => tx.client_id,
+            Transaction::Fee(tx) => tx.client_id,
+            Transaction::Adjustment(tx) => tx.client_id,
+            Transaction::Authorize(tx) => tx.client_id,
+            Transaction::Capture(tx) => tx.client_id,
+            Transaction::Void(tx) => tx.client_id,
+            Transaction::Convert(tx) => tx.client_id,
         }
     }
 
@@ -57,57 +258,298 @@ impl Transaction {
             Transaction::Dispute(tx) => tx.tx_id,
             Transaction::Resolve(tx) => tx.tx_id,
             Transaction::ChargeBack(tx) => tx.tx_id,
+            Transaction::Lock(tx) => tx.tx_id,
+            Transaction::Unlock(tx) => tx.tx_id,
+            Transaction::Transfer(tx) => tx.tx_id,
+            Transaction::Fee(tx) => tx.tx_id,
+            Transaction::Adjustment(tx) => tx.tx_id,
+            Transaction::Authorize(tx) => tx.tx_id,
+            Transaction::Capture(tx) => tx.tx_id,
+            Transaction::Void(tx) => tx.tx_id,
+            Transaction::Convert(tx) => tx.tx_id,
         }
     }
 
     /// Get string representation of the `Transaction` type.
-    #[allow(dead_code)]
     pub fn get_type(&self) -> &'static str {
         match self {
             Transaction::Deposit(_) => "deposit",
             Transaction::Withdrawal(_) => "withdrawal",
             Transaction::Dispute(_) => "dispute",
             Transaction::Resolve(_) => "resolve",
-            Transaction::ChargeBack(_) => "chargeback"
+            Transaction::ChargeBack(_) => "chargeback",
+            Transaction::Lock(_) => "lock",
+            Transaction::Unlock(_) => "unlock",
+            Transaction::Transfer(_) => "transfer",
+            Transaction::Fee(_) => "fee",
+            Transaction::Adjustment(_) => "adjustment",
+            Transaction::Authorize(_) => "authorize",
+            Transaction::Capture(_) => "capture",
+            Transaction::Void(_) => "void",
+            Transaction::Convert(_) => "convert",
         }
     }
 }
 
 impl TryFrom<InputTransaction> for Transaction {
-    // TODO: implement errors enum
-    type Error = &'static str;
+    type Error = EngineError;
 
     fn try_from(input: InputTransaction) -> Result<Self, Self::Error> {
-        let InputTransaction { client_id, tx_id, tx_type, amount } = input;
+        let InputTransaction {
+            client_id, tx_id, tx_type, amount, reason, to, currency, to_currency, ..
+        } = input;
+        let currency = currency.unwrap_or_default();
 
-        if let "deposit" | "withdrawal" = tx_type.as_str() {
-            let amount = amount.ok_or("for deposit and withdrawal, amount can't be none")?;
+        if tx_type == "adjustment" {
+            let amount = amount
+                .ok_or(EngineError::MissingAmount { client_id, tx_id })?;
+            let reason = reason
+                .filter(|reason| !reason.is_empty())
+                .ok_or(EngineError::MissingReason { client_id, tx_id })?;
+
+            let adjustment = Adjustment::new(client_id, tx_id, amount, reason)
+                .with_currency(currency);
+            return Ok(Transaction::Adjustment(adjustment));
+        }
+
+        if let "deposit" | "withdrawal" | "transfer" | "fee" | "authorize" | "convert" =
+            tx_type.as_str()
+        {
+            let amount = amount
+                .ok_or(EngineError::MissingAmount { client_id, tx_id })?;
 
             if amount.is_sign_negative() {
-                return Err("amount can't be negative");
+                return Err(EngineError::NegativeAmount { client_id, tx_id, amount });
+            }
+
+            if tx_type == "transfer" {
+                let to = to.ok_or(EngineError::MissingDestination { client_id, tx_id })?;
+                if to == client_id {
+                    return Err(EngineError::SelfTransfer { client_id, tx_id });
+                }
+                let transfer = TransferInfo::new(client_id, to, tx_id, amount)
+                    .with_currency(currency);
+                return Ok(Transaction::Transfer(transfer));
+            }
+
+            if tx_type == "convert" {
+                let to_currency = to_currency
+                    .ok_or(EngineError::MissingTargetCurrency { client_id, tx_id })?;
+                if to_currency == currency {
+                    return Err(EngineError::SameCurrency { client_id, tx_id });
+                }
+                let conversion = Conversion::new(client_id, tx_id, amount, currency, to_currency);
+                return Ok(Transaction::Convert(conversion));
             }
 
-            let tx_info = TransactionInfo {
-                client_id,
-                tx_id,
-                amount,
-                under_dispute: false,
-            };
+            let tx_info = TransactionInfo::new(client_id, tx_id, amount).with_currency(currency);
 
             return Ok(match tx_type.as_str() {
                 "deposit" => Transaction::Deposit(tx_info),
                 "withdrawal" => Transaction::Withdrawal(tx_info),
+                "fee" => Transaction::Fee(tx_info),
+                "authorize" => Transaction::Authorize(tx_info),
                 _ => unreachable!(),
             });
         }
 
-        let tx_ref = TransactionRef { client_id, tx_id };
+        if let "lock" | "freeze" | "unlock" = tx_type.as_str() {
+            let action = AccountAction { client_id, tx_id, reason };
+
+            return Ok(match tx_type.as_str() {
+                "unlock" => Transaction::Unlock(action),
+                _ => Transaction::Lock(action),
+            });
+        }
+
+        // type is checked first, so unknown one isn't reported as a bad amount.
+        let tx_ref: fn(TransactionRef) -> Transaction = match tx_type.as_str() {
+            "dispute" => Transaction::Dispute,
+            "resolve" => Transaction::Resolve,
+            "chargeback" => Transaction::ChargeBack,
+            "capture" => Transaction::Capture,
+            "void" => Transaction::Void,
+            _ => return Err(EngineError::UnknownType { client_id, tx_id, tx_type }),
+        };
+
+        check_ref_amount(client_id, tx_id, amount)?;
+        Ok(tx_ref(TransactionRef { client_id, tx_id, amount }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn input(tx_type: &str, amount: Option<&str>) -> InputTransaction {
+        InputTransaction {
+            tx_type: tx_type.to_owned(),
+            client_id: 1,
+            tx_id: 2,
+            amount: amount.map(|x| Amount::from_str(x).unwrap()),
+            reason: None,
+            to: None,
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn try_from_missing_amount() {
+        assert_eq!(
+            Transaction::try_from(input("deposit", None)).unwrap_err(),
+            EngineError::MissingAmount { client_id: 1, tx_id: 2 },
+        );
+    }
+
+    #[test]
+    fn try_from_negative_amount() {
+        assert_eq!(
+            Transaction::try_from(input("withdrawal", Some("-1.5"))).unwrap_err(),
+            EngineError::NegativeAmount {
+                client_id: 1,
+                tx_id: 2,
+                amount: Amount::from_str("-1.5").unwrap(),
+            },
+        );
+    }
 
-        match tx_type.as_str() {
-            "dispute" => Ok(Transaction::Dispute(tx_ref)),
-            "resolve" => Ok(Transaction::Resolve(tx_ref)),
-            "chargeback" => Ok(Transaction::ChargeBack(tx_ref)),
-            _ => Err("unknown transaction type"),
+    #[test]
+    fn try_from_negative_dispute_amount() {
+        assert_eq!(
+            Transaction::try_from(input("dispute", Some("-0.5"))).unwrap_err(),
+            EngineError::NegativeAmount {
+                client_id: 1,
+                tx_id: 2,
+                amount: Amount::from_str("-0.5").unwrap(),
+            },
+        );
+    }
+
+    #[test]
+    fn try_from_zero_ref_amount() {
+        for tx_type in &["dispute", "resolve", "chargeback", "capture", "void"] {
+            assert_eq!(
+                Transaction::try_from(input(tx_type, Some("0"))).unwrap_err(),
+                EngineError::ZeroAmount { client_id: 1, tx_id: 2 },
+            );
         }
+        assert!(Transaction::try_from(input("dispute", Some("-0"))).is_err());
+    }
+
+    #[test]
+    fn try_from_unknown_type_with_bad_amount() {
+        for amount in &["0", "-1"] {
+            assert_eq!(
+                Transaction::try_from(input("foo", Some(amount))).unwrap_err(),
+                EngineError::UnknownType { client_id: 1, tx_id: 2, tx_type: "foo".to_owned() },
+            );
+        }
+    }
+
+    #[test]
+    fn try_from_partial_dispute() {
+        match Transaction::try_from(input("dispute", Some("0.5"))).unwrap() {
+            Transaction::Dispute(tx_ref) => {
+                assert_eq!(tx_ref.amount, Some(Amount::from_str("0.5").unwrap()));
+            },
+            tx => panic!("unexpected transaction: {:?}", tx),
+        }
+    }
+
+    #[test]
+    fn try_from_freeze() {
+        let mut input = input("freeze", None);
+        input.reason = Some("fraud".to_owned());
+
+        match Transaction::try_from(input).unwrap() {
+            Transaction::Lock(action) => {
+                assert_eq!(action.reason.as_deref(), Some("fraud"));
+            },
+            tx => panic!("unexpected transaction: {:?}", tx),
+        }
+    }
+
+    #[test]
+    fn try_from_transfer() {
+        assert_eq!(
+            Transaction::try_from(input("transfer", Some("1.5"))).unwrap_err(),
+            EngineError::MissingDestination { client_id: 1, tx_id: 2 },
+        );
+
+        let mut to_self = input("transfer", Some("1.5"));
+        to_self.to = Some(1);
+        assert_eq!(
+            Transaction::try_from(to_self).unwrap_err(),
+            EngineError::SelfTransfer { client_id: 1, tx_id: 2 },
+        );
+
+        let mut transfer = input("transfer", Some("1.5"));
+        transfer.to = Some(3);
+        match Transaction::try_from(transfer).unwrap() {
+            Transaction::Transfer(transfer) => {
+                assert_eq!((transfer.client_id, transfer.to), (1, 3));
+                assert_eq!(transfer.amount, Amount::from_str("1.5").unwrap());
+            },
+            tx => panic!("unexpected transaction: {:?}", tx),
+        }
+    }
+
+    #[test]
+    fn try_from_convert() {
+        let usd = Currency::from_str("USD").unwrap();
+        assert_eq!(
+            Transaction::try_from(input("convert", Some("1.5"))).unwrap_err(),
+            EngineError::MissingTargetCurrency { client_id: 1, tx_id: 2 },
+        );
+
+        let mut same = input("convert", Some("1.5"));
+        same.currency = Some(usd);
+        same.to_currency = Some(usd);
+        assert_eq!(
+            Transaction::try_from(same).unwrap_err(),
+            EngineError::SameCurrency { client_id: 1, tx_id: 2 },
+        );
+
+        let mut convert = input("convert", Some("1.5"));
+        convert.to_currency = Some(usd);
+        match Transaction::try_from(convert).unwrap() {
+            Transaction::Convert(conversion) => {
+                assert_eq!((conversion.currency, conversion.to_currency), (Currency::default(), usd));
+            },
+            tx => panic!("unexpected transaction: {:?}", tx),
+        }
+    }
+
+    #[test]
+    fn try_from_adjustment() {
+        assert_eq!(
+            Transaction::try_from(input("adjustment", Some("-1.5"))).unwrap_err(),
+            EngineError::MissingReason { client_id: 1, tx_id: 2 },
+        );
+
+        let mut input = input("adjustment", Some("-1.5"));
+        input.reason = Some("double charge".to_owned());
+        match Transaction::try_from(input).unwrap() {
+            Transaction::Adjustment(adjustment) => {
+                assert_eq!(adjustment.amount, Amount::from_str("-1.5").unwrap());
+                assert_eq!(adjustment.reason, "double charge");
+            },
+            tx => panic!("unexpected transaction: {:?}", tx),
+        }
+    }
+
+    #[test]
+    fn try_from_unknown_type() {
+        assert_eq!(
+            Transaction::try_from(input("refund", None)).unwrap_err(),
+            EngineError::UnknownType {
+                client_id: 1,
+                tx_id: 2,
+                tx_type: "refund".to_owned(),
+            },
+        );
     }
 }
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::types::{ClientID, TransactionID};
+use crate::transaction::Transaction;
+use crate::error::EngineError;
+use crate::policy::Policy;
+use crate::id_set::IdSet;
+use crate::disk_map::DiskMap;
+
+/// Owners of the transactions across all clients of the bank.
+///
+/// Transaction ids are globally unique, but `Account` only knows
+/// about it's own transactions, so it can't detect the same id used
+/// by another client. Id is claimed when transaction is checked and
+/// given back with [TxIndex::unclaim], if `Account` refuses it, so
+/// that transaction can be retried with the same id.
+///
+/// Owners are only kept of transactions that can be disputed, so
+/// reference to the other client's transaction that can't be disputed
+/// is left for the `Account` to report as not found.
+#[derive(Debug, Default)]
+pub struct TxIndex {
+    ids: IdSet,
+    owners: Owners,
+    policy: Policy,
+}
+
+/// Owners of the disputable transactions, in memory
+/// or on disk, when history is kept on disk as well.
+#[derive(Debug)]
+enum Owners {
+    Memory(HashMap<TransactionID, ClientID>),
+    Disk(DiskMap),
+}
+
+impl Default for Owners {
+    fn default() -> Self {
+        Owners::Memory(HashMap::new())
+    }
+}
+
+impl TxIndex {
+    /// Index for the bank, whose accounts follow `policy`.
+    pub fn new_with_policy(policy: Policy) -> Self {
+        Self { policy, ..Self::default() }
+    }
+
+    /// Restores index from ids of all transactions and owners
+    /// of the disputable ones.
+    pub fn from_parts<I>(ids: IdSet, owners: I, policy: Policy) -> Self
+    where I: IntoIterator<Item = (TransactionID, ClientID)>,
+    {
+        Self { ids, owners: Owners::Memory(owners.into_iter().collect()), policy }
+    }
+
+    /// Keeps owners in a file at `path` instead of memory. Owners
+    /// already in the index are moved there.
+    pub fn with_owners_on_disk<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        let mut map = DiskMap::create(path)?;
+        for (tx_id, client_id) in self.owners()? {
+            map.insert(tx_id, client_id as u32)?;
+        }
+        self.owners = Owners::Disk(map);
+        Ok(self)
+    }
+
+    /// Ids of all transactions in the index.
+    pub fn ids(&self) -> &IdSet {
+        &self.ids
+    }
+
+    /// Ids and owners of disputable transactions in the index.
+    pub fn owners(&self) -> io::Result<Vec<(TransactionID, ClientID)>> {
+        match &self.owners {
+            Owners::Memory(owners) => Ok(owners.iter()
+                .map(|(&tx_id, &client_id)| (tx_id, client_id))
+                .collect()),
+            Owners::Disk(map) => map.iter()?
+                .map(|entry| entry.map(|(tx_id, client_id)| (tx_id, client_id as ClientID)))
+                // owners of the given back ids are left on disk.
+                .filter(|entry| entry.as_ref().map_or(true, |(tx_id, _)| self.ids.contains(*tx_id)))
+                .collect(),
+        }
+    }
+
+    /// Claims id of the new transaction for it's client and checks
+    /// that transaction references belong to the same client.
+    pub fn check(&mut self, tx: &Transaction) -> Result<(), EngineError> {
+        let client_id = tx.get_client_id();
+        let tx_id = tx.get_tx_id();
+
+        if tx.claims_id() {
+            if !self.ids.insert(tx_id) {
+                return Err(EngineError::DuplicateTx { client_id, tx_id });
+            }
+            if self.policy.is_disputable(tx) {
+                self.set_owner(tx_id, client_id).map_err(|err| storage_error(tx, err))?;
+            }
+        } else if tx.is_ref() && self.ids.contains(tx_id) {
+            match self.owner(tx_id).map_err(|err| storage_error(tx, err))? {
+                Some(owner) if owner != client_id => {
+                    return Err(EngineError::ClientMismatch { client_id, tx_id, owner });
+                },
+                // not found will be reported by the `Account`.
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gives back id of the transaction, that was refused after it was
+    /// checked. Owner on disk is left, it's ignored without the id.
+    pub fn unclaim(&mut self, tx_id: TransactionID) {
+        self.ids.remove(tx_id);
+        if let Owners::Memory(owners) = &mut self.owners {
+            owners.remove(&tx_id);
+        }
+    }
+
+    fn owner(&mut self, tx_id: TransactionID) -> io::Result<Option<ClientID>> {
+        match &mut self.owners {
+            Owners::Memory(owners) => Ok(owners.get(&tx_id).copied()),
+            Owners::Disk(map) => Ok(map.get(tx_id)?.map(|client_id| client_id as ClientID)),
+        }
+    }
+
+    fn set_owner(&mut self, tx_id: TransactionID, client_id: ClientID) -> io::Result<()> {
+        match &mut self.owners {
+            Owners::Memory(owners) => {
+                owners.insert(tx_id, client_id);
+                Ok(())
+            },
+            Owners::Disk(map) => map.insert(tx_id, client_id as u32),
+        }
+    }
+}
+
+fn storage_error(tx: &Transaction, err: io::Error) -> EngineError {
+    EngineError::Storage {
+        client_id: tx.get_client_id(),
+        tx_id: tx.get_tx_id(),
+        message: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{TransactionInfo, TransactionRef};
+
+    fn deposit(client_id: ClientID, tx_id: TransactionID) -> Transaction {
+        Transaction::Deposit(TransactionInfo {
+            client_id,
+            tx_id,
+            amount: Default::default(),
+            currency: Default::default(),
+        })
+    }
+
+    fn dispute(client_id: ClientID, tx_id: TransactionID) -> Transaction {
+        Transaction::Dispute(TransactionRef { client_id, tx_id, amount: None })
+    }
+
+    #[test]
+    fn duplicate_across_clients() {
+        let mut index = TxIndex::default();
+
+        assert!(index.check(&deposit(1, 5)).is_ok());
+        assert_eq!(
+            index.check(&deposit(2, 5)),
+            Err(EngineError::DuplicateTx { client_id: 2, tx_id: 5 }),
+        );
+    }
+
+    #[test]
+    fn ref_with_wrong_client() {
+        let mut index = TxIndex::default();
+
+        assert!(index.check(&deposit(1, 5)).is_ok());
+        assert!(index.check(&dispute(1, 5)).is_ok());
+        assert_eq!(
+            index.check(&dispute(2, 5)),
+            Err(EngineError::ClientMismatch { client_id: 2, tx_id: 5, owner: 1 }),
+        );
+        // unknown transactions are left for the account to report.
+        assert!(index.check(&dispute(2, 6)).is_ok());
+    }
+
+    #[test]
+    fn unclaimed_id_can_be_claimed_again() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-tx-owners-unclaim", std::process::id()));
+        for mut index in [TxIndex::default(), TxIndex::default().with_owners_on_disk(&path).unwrap()] {
+            assert!(index.check(&deposit(1, 5)).is_ok());
+            index.unclaim(5);
+
+            // unknown again, so it's left for the account to report.
+            assert!(index.check(&dispute(2, 5)).is_ok());
+            assert_eq!(index.owners().unwrap(), vec![]);
+            assert!(index.check(&deposit(2, 5)).is_ok());
+            assert_eq!(
+                index.check(&dispute(1, 5)),
+                Err(EngineError::ClientMismatch { client_id: 1, tx_id: 5, owner: 2 }),
+            );
+        }
+    }
+
+    #[test]
+    fn owners_on_disk() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-tx-owners", std::process::id()));
+        let mut index = TxIndex::default();
+        assert!(index.check(&deposit(1, 5)).is_ok());
+
+        // owners already in the index are moved to disk.
+        let mut index = index.with_owners_on_disk(&path).unwrap();
+        assert!(index.check(&deposit(2, 6)).is_ok());
+        assert_eq!(
+            index.check(&dispute(2, 5)),
+            Err(EngineError::ClientMismatch { client_id: 2, tx_id: 5, owner: 1 }),
+        );
+        assert_eq!(
+            index.check(&dispute(1, 6)),
+            Err(EngineError::ClientMismatch { client_id: 1, tx_id: 6, owner: 2 }),
+        );
+        let mut owners = index.owners().unwrap();
+        owners.sort_unstable();
+        assert_eq!(owners, vec![(5, 1), (6, 2)]);
+
+        drop(index);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::ser::{Error, SerializeMap};
+
+use rust_decimal::prelude::Zero;
+
+use crate::types::{ClientID, TransactionID, Amount};
+use crate::expiry::Moment;
+use crate::currency::Currency;
+use crate::disk_map::DiskMap;
+
+/// Kind of the transaction, that can be disputed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// What's kept in account's history of the transaction, that can be
+/// disputed. Only it's amount and dispute state.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TxRecord {
+    pub kind: TxKind,
+    pub amount: Amount,
+    /// Currency of the `amount`, disputes hold funds in it.
+    #[serde(default)]
+    pub currency: Currency,
+    /// Part of the `amount` that is currently under dispute.
+    pub disputed: Amount,
+    /// When transaction happened.
+    pub at: Moment,
+}
+
+impl TxRecord {
+    /// Record of the new transaction, that isn't under dispute.
+    pub fn new(kind: TxKind, amount: Amount, at: Moment) -> Self {
+        Self { kind, amount, currency: Currency::default(), disputed: Amount::zero(), at }
+    }
+
+    /// Same record in `currency`.
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    pub fn is_under_dispute(&self) -> bool {
+        !self.disputed.is_zero()
+    }
+
+    /// Part of the `amount` that isn't under dispute.
+    pub fn undisputed(&self) -> Amount {
+        self.amount - self.disputed
+    }
+}
+
+/// Records of the store with their ids, read one at a time.
+pub type Records<'a> = Box<dyn Iterator<Item = io::Result<(TransactionID, TxRecord)>> + 'a>;
+
+/// Storage of the account's transactions, that can be referenced
+/// by disputes.
+pub trait TxStore: fmt::Debug + Send {
+    /// Record of the transaction with `tx_id`, if it's stored.
+    fn get(&self, tx_id: TransactionID) -> io::Result<Option<TxRecord>>;
+
+    /// Stores record, replacing the one with the same id.
+    fn put(&mut self, tx_id: TransactionID, record: TxRecord) -> io::Result<()>;
+
+    /// All stored records with their ids, in no particular order.
+    fn all(&self) -> io::Result<Records<'_>>;
+}
+
+/// Serialized as a map of records by their ids, whatever the store is.
+/// Records are written as they're read, not collected first.
+impl Serialize for dyn TxStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for record in self.all().map_err(S::Error::custom)? {
+            let (tx_id, record) = record.map_err(S::Error::custom)?;
+            map.serialize_entry(&tx_id, &record)?;
+        }
+        map.end()
+    }
+}
+
+/// Always deserialized into [MemoryTxStore].
+impl<'de> Deserialize<'de> for Box<dyn TxStore> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let records = HashMap::deserialize(deserializer)?;
+        Ok(Box::new(MemoryTxStore { records }))
+    }
+}
+
+/// Keeps all records in memory.
+#[derive(Debug, Default)]
+pub struct MemoryTxStore {
+    records: HashMap<TransactionID, TxRecord>,
+}
+
+impl MemoryTxStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TxStore for MemoryTxStore {
+    fn get(&self, tx_id: TransactionID) -> io::Result<Option<TxRecord>> {
+        Ok(self.records.get(&tx_id).copied())
+    }
+
+    fn put(&mut self, tx_id: TransactionID, record: TxRecord) -> io::Result<()> {
+        self.records.insert(tx_id, record);
+        Ok(())
+    }
+
+    fn all(&self) -> io::Result<Records<'_>> {
+        Ok(Box::new(self.records.iter().map(|(&tx_id, &record)| Ok((tx_id, record)))))
+    }
+}
+
+/// Size of the transaction's slot in the record: kind, flags, client id,
+/// amount, disputed amount, sequence number, timestamp and currency.
+const SLOT_LEN: usize = 1 + 1 + 2 + 16 + 16 + 8 + 8 + 8;
+/// Size of the record in the file: transaction id and it's slot.
+const RECORD_LEN: usize = 4 + SLOT_LEN;
+
+const DEPOSIT: u8 = 1;
+const WITHDRAWAL: u8 = 2;
+
+/// Flag of the slot, whether it has a timestamp.
+const HAS_TIMESTAMP: u8 = 1;
+
+/// Keeps records in a file, which they're only appended to, with
+/// an index of where the latest record of every id is kept on disk
+/// as well. Only recently used records are cached in memory.
+///
+/// Since transaction ids are unique across clients, one file is shared
+/// by all accounts of the bank, each through it's own
+/// [DiskTxStore::for_client] handle. File is only a scratch space,
+/// it's removed when the last handle is dropped.
+#[derive(Debug, Clone)]
+pub struct DiskTxStore {
+    file: Arc<Mutex<StoreFile>>,
+    /// Only records of this client are visible, if set.
+    client_id: Option<ClientID>,
+}
+
+impl DiskTxStore {
+    /// Creates store in a new file at `path`, which keeps at most
+    /// `cache_capacity` records in memory. Index is kept next to
+    /// it, at `path` with `.index` added.
+    pub fn create<P: AsRef<Path>>(path: P, cache_capacity: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let mut index_path = path.as_os_str().to_owned();
+        index_path.push(".index");
+        let index = DiskMap::create(PathBuf::from(index_path))?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(StoreFile {
+                path,
+                log: RecordLog { file, len: 0, index },
+                cache: HashMap::new(),
+                order: BTreeMap::new(),
+                uses: 0,
+                cache_capacity,
+                owners: None,
+            })),
+            client_id: None,
+        })
+    }
+
+    /// Handle to the same file, that only sees records of `client_id`.
+    pub fn for_client(&self, client_id: ClientID) -> Self {
+        Self {
+            file: self.file.clone(),
+            client_id: Some(client_id),
+        }
+    }
+
+    fn is_visible(&self, slot: &Slot) -> bool {
+        match self.client_id {
+            Some(client_id) => client_id == slot.client_id,
+            None => true,
+        }
+    }
+}
+
+impl TxStore for DiskTxStore {
+    fn get(&self, tx_id: TransactionID) -> io::Result<Option<TxRecord>> {
+        let slot = self.file.lock().unwrap().get(tx_id)?;
+        Ok(slot.filter(|slot| self.is_visible(slot)).map(|slot| slot.record))
+    }
+
+    fn put(&mut self, tx_id: TransactionID, record: TxRecord) -> io::Result<()> {
+        let client_id = self.client_id.unwrap_or_default();
+        self.file.lock().unwrap().put(tx_id, Slot { client_id, record })
+    }
+
+    /// First call reads the whole file, so it's slow for big files,
+    /// but it finds ids of every client at once. Handles of the other
+    /// clients reuse them, untill a record is stored.
+    fn all(&self) -> io::Result<Records<'_>> {
+        let ids = self.file.lock().unwrap().ids(self.client_id)?;
+        Ok(Box::new(ids.into_iter().filter_map(move |tx_id| {
+            match self.file.lock().unwrap().peek(tx_id) {
+                Ok(slot) => slot.map(|slot| Ok((tx_id, slot.record))),
+                Err(err) => Some(Err(err)),
+            }
+        })))
+    }
+}
+
+/// Record with the client it belongs to.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    client_id: ClientID,
+    record: TxRecord,
+}
+
+/// Slot in the cache of the [StoreFile].
+#[derive(Debug, Clone, Copy)]
+struct Cached {
+    slot: Slot,
+    /// Whether it changed since it was written to the file.
+    dirty: bool,
+    /// When it was last used, key of it's id in [StoreFile::order].
+    used: u64,
+}
+
+#[derive(Debug)]
+struct StoreFile {
+    path: PathBuf,
+    log: RecordLog,
+    /// Recently used slots.
+    cache: HashMap<TransactionID, Cached>,
+    /// Ids in the `cache` by when they were last used,
+    /// least recently used ones come first.
+    order: BTreeMap<u64, TransactionID>,
+    /// Number of times slots were used, it orders them.
+    uses: u64,
+    cache_capacity: usize,
+    /// Ids of the stored records by client, from the last
+    /// scan of the file. Dropped when a record is stored.
+    owners: Option<HashMap<ClientID, Vec<TransactionID>>>,
+}
+
+impl StoreFile {
+    fn get(&mut self, tx_id: TransactionID) -> io::Result<Option<Slot>> {
+        let slot = match self.cache.get(&tx_id) {
+            Some(cached) => Some(cached.slot),
+            None => self.log.read(tx_id)?,
+        };
+        if let Some(slot) = slot {
+            self.cache(tx_id, slot, false)?;
+        }
+        Ok(slot)
+    }
+
+    /// Same as `get`, but doesn't cache the slot, so reading
+    /// through all of them doesn't push out the used ones.
+    fn peek(&mut self, tx_id: TransactionID) -> io::Result<Option<Slot>> {
+        match self.cache.get(&tx_id) {
+            Some(cached) => Ok(Some(cached.slot)),
+            None => self.log.read(tx_id),
+        }
+    }
+
+    fn put(&mut self, tx_id: TransactionID, slot: Slot) -> io::Result<()> {
+        self.owners = None;
+        self.cache(tx_id, slot, true)
+    }
+
+    /// Ids of the records of `client_id`, or of all records if it's `None`.
+    fn ids(&mut self, client_id: Option<ClientID>) -> io::Result<Vec<TransactionID>> {
+        if self.owners.is_none() {
+            self.write_dirty()?;
+        }
+        let owners = match &mut self.owners {
+            Some(owners) => owners,
+            None => {
+                let owners = self.log.scan()?;
+                self.owners.get_or_insert(owners)
+            },
+        };
+        Ok(match client_id {
+            Some(client_id) => owners.get(&client_id).cloned().unwrap_or_default(),
+            None => owners.values().flatten().copied().collect(),
+        })
+    }
+
+    /// Puts slot to the cache as the most recently used one, writing
+    /// out the least recently used ones if it's over capacity.
+    fn cache(&mut self, tx_id: TransactionID, slot: Slot, dirty: bool) -> io::Result<()> {
+        let was_dirty = match self.cache.remove(&tx_id) {
+            Some(cached) => {
+                self.order.remove(&cached.used);
+                cached.dirty
+            },
+            None => false,
+        };
+        self.uses += 1;
+        self.cache.insert(tx_id, Cached { slot, dirty: dirty || was_dirty, used: self.uses });
+        self.order.insert(self.uses, tx_id);
+
+        while self.cache.len() > self.cache_capacity {
+            let tx_id = match self.order.pop_first() {
+                Some((_, tx_id)) => tx_id,
+                None => break,
+            };
+            if let Some(Cached { slot, dirty: true, .. }) = self.cache.remove(&tx_id) {
+                self.log.append(tx_id, &slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_dirty(&mut self) -> io::Result<()> {
+        for (&tx_id, cached) in self.cache.iter_mut() {
+            if cached.dirty {
+                self.log.append(tx_id, &cached.slot)?;
+                cached.dirty = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StoreFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Records in order they were written. Changed record is written
+/// again, so the file also has outdated ones, but only the latest
+/// is in the `index`.
+#[derive(Debug)]
+struct RecordLog {
+    file: File,
+    /// Number of records in the `file`, outdated ones included.
+    len: u64,
+    /// Position of the latest record of every id, in records.
+    index: DiskMap,
+}
+
+impl RecordLog {
+    fn read(&mut self, tx_id: TransactionID) -> io::Result<Option<Slot>> {
+        let pos = match self.index.get(tx_id)? {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let mut data = [0; RECORD_LEN];
+        self.file.seek(SeekFrom::Start(pos as u64 * RECORD_LEN as u64))?;
+        self.file.read_exact(&mut data)?;
+        Ok(decode(&data).map(|(_, slot)| slot))
+    }
+
+    fn append(&mut self, tx_id: TransactionID, slot: &Slot) -> io::Result<()> {
+        let pos = u32::try_from(self.len)
+            .ok()
+            .filter(|&pos| pos < u32::MAX)
+            .ok_or_else(|| io::Error::other("too many records"))?;
+        self.file.seek(SeekFrom::Start(self.len * RECORD_LEN as u64))?;
+        self.file.write_all(&encode(tx_id, slot))?;
+        self.index.insert(tx_id, pos)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Reads all records, grouping ids of the latest ones by client.
+    fn scan(&mut self) -> io::Result<HashMap<ClientID, Vec<TransactionID>>> {
+        let Self { file, len, index } = self;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = io::BufReader::new(&*file);
+        let mut data = [0; RECORD_LEN];
+        let mut owners: HashMap<_, Vec<_>> = HashMap::new();
+
+        for pos in 0..*len {
+            reader.read_exact(&mut data)?;
+            if let Some((tx_id, slot)) = decode(&data) {
+                if index.get(tx_id)? == Some(pos as u32) {
+                    owners.entry(slot.client_id).or_default().push(tx_id);
+                }
+            }
+        }
+        Ok(owners)
+    }
+}
+
+fn encode(tx_id: TransactionID, slot: &Slot) -> [u8; RECORD_LEN] {
+    let mut data = [0; RECORD_LEN];
+    data[..4].copy_from_slice(&tx_id.to_le_bytes());
+    let slot_data = &mut data[4..];
+    slot_data[0] = match slot.record.kind {
+        TxKind::Deposit => DEPOSIT,
+        TxKind::Withdrawal => WITHDRAWAL,
+    };
+    slot_data[2..4].copy_from_slice(&slot.client_id.to_le_bytes());
+    slot_data[4..20].copy_from_slice(&slot.record.amount.serialize());
+    slot_data[20..36].copy_from_slice(&slot.record.disputed.serialize());
+    slot_data[36..44].copy_from_slice(&slot.record.at.seq.to_le_bytes());
+    if let Some(timestamp) = slot.record.at.timestamp {
+        slot_data[1] |= HAS_TIMESTAMP;
+        slot_data[44..52].copy_from_slice(&timestamp.to_le_bytes());
+    }
+    slot_data[52..60].copy_from_slice(&slot.record.currency.to_bytes());
+    data
+}
+
+/// Record of unknown kind is decoded as `None`.
+fn decode(data: &[u8; RECORD_LEN]) -> Option<(TransactionID, Slot)> {
+    let mut tx_id = [0; 4];
+    tx_id.copy_from_slice(&data[..4]);
+    let data = &data[4..];
+    let kind = match data[0] {
+        DEPOSIT => TxKind::Deposit,
+        WITHDRAWAL => TxKind::Withdrawal,
+        _ => return None,
+    };
+
+    let mut client_id = [0; 2];
+    let mut amount = [0; 16];
+    let mut disputed = [0; 16];
+    let mut seq = [0; 8];
+    let mut timestamp = [0; 8];
+    let mut currency = [0; 8];
+    client_id.copy_from_slice(&data[2..4]);
+    amount.copy_from_slice(&data[4..20]);
+    disputed.copy_from_slice(&data[20..36]);
+    seq.copy_from_slice(&data[36..44]);
+    timestamp.copy_from_slice(&data[44..52]);
+    currency.copy_from_slice(&data[52..60]);
+
+    let slot = Slot {
+        client_id: ClientID::from_le_bytes(client_id),
+        record: TxRecord {
+            kind,
+            amount: Amount::deserialize(amount),
+            currency: Currency::from_bytes(currency),
+            disputed: Amount::deserialize(disputed),
+            at: Moment {
+                seq: u64::from_le_bytes(seq),
+                timestamp: if data[1] & HAS_TIMESTAMP != 0 {
+                    Some(u64::from_le_bytes(timestamp))
+                } else {
+                    None
+                },
+            },
+        },
+    };
+    Some((TransactionID::from_le_bytes(tx_id), slot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn deposit(amount: i64) -> TxRecord {
+        TxRecord::new(TxKind::Deposit, Amount::new(amount, 2), Moment::default())
+    }
+
+    #[test]
+    fn spills_to_disk() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-tx-store", std::process::id()));
+        let store = DiskTxStore::create(&path, 2).unwrap();
+        let mut first = store.for_client(1);
+        let mut second = store.for_client(2);
+
+        for tx_id in 0..10 {
+            first.put(tx_id, deposit(tx_id as i64 + 1)).unwrap();
+        }
+        second.put(1000, deposit(5)).unwrap();
+
+        // update of the evicted record.
+        let mut record = first.get(3).unwrap().unwrap();
+        record.disputed = Amount::new(4, 2);
+        first.put(3, record).unwrap();
+        second.put(1001, deposit(5)).unwrap();
+        second.put(1002, deposit(5)).unwrap();
+
+        // moment survives the round trip through the file.
+        let at = Moment { seq: 7, timestamp: Some(1_600_000_000) };
+        let currency = "BTC".parse().unwrap();
+        second.put(1003, TxRecord { at, ..deposit(5) }.with_currency(currency)).unwrap();
+        second.put(1004, deposit(5)).unwrap();
+        second.put(1005, deposit(5)).unwrap();
+        let record = second.get(1003).unwrap().unwrap();
+        assert_eq!(record.at, at);
+        assert_eq!(record.currency, currency);
+
+        let record = first.get(3).unwrap().unwrap();
+        assert_eq!(record.amount, Amount::new(4, 2));
+        assert_eq!(record.disputed, Amount::new(4, 2));
+
+        assert!(first.get(1000).unwrap().is_none());
+        assert!(first.get(5000).unwrap().is_none());
+        assert_eq!(first.all().unwrap().count(), 10);
+        assert_eq!(second.all().unwrap().count(), 6);
+        assert_eq!(store.all().unwrap().count(), 16);
+
+        // ids found by the scan are dropped, once new record is stored.
+        second.put(1006, deposit(5)).unwrap();
+        assert_eq!(second.all().unwrap().count(), 7);
+        assert_eq!(first.all().unwrap().count(), 10);
+
+        drop((store, first, second));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn file_grows_with_records_not_ids() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-tx-store-sparse", std::process::id()));
+        let store = DiskTxStore::create(&path, 1).unwrap();
+        let mut client = store.for_client(1);
+
+        client.put(u32::MAX, deposit(1)).unwrap();
+        client.put(3, deposit(2)).unwrap();
+        // updated one is written again, but only the latest is read.
+        let mut record = client.get(u32::MAX).unwrap().unwrap();
+        record.disputed = Amount::new(1, 2);
+        client.put(u32::MAX, record).unwrap();
+        client.put(4, deposit(3)).unwrap();
+
+        let mut records: Vec<_> = client.all().unwrap().map(Result::unwrap).collect();
+        records.sort_by_key(|&(tx_id, _)| tx_id);
+        assert_eq!(records, vec![
+            (3, deposit(2)),
+            (4, deposit(3)),
+            (u32::MAX, record),
+        ]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4 * RECORD_LEN as u64);
+
+        drop((store, client));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn frequently_read_record_stays_cached() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-tx-store-lru", std::process::id()));
+        let store = DiskTxStore::create(&path, 2).unwrap();
+        let mut client = store.for_client(1);
+
+        client.put(0, deposit(1)).unwrap();
+        for tx_id in 1..10 {
+            client.put(tx_id, deposit(tx_id as i64 + 1)).unwrap();
+            // first one is read after every other is stored,
+            // so it's never the least recently used one.
+            assert!(store.file.lock().unwrap().cache.contains_key(&0));
+            assert_eq!(client.get(0).unwrap().unwrap().amount, Amount::new(1, 2));
+        }
+        assert_eq!(store.file.lock().unwrap().order.len(), 2);
+
+        drop((store, client));
+        assert!(!path.exists());
+    }
+}
@@ -3,3 +3,5 @@ use rust_decimal::Decimal;
 pub type ClientID = u16;
 pub type TransactionID = u32;
 pub type Amount = Decimal;
+/// Unix time in seconds.
+pub type Timestamp = u64;
@@ -0,0 +1,327 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::transaction::Transaction;
+use crate::expiry::Moment;
+
+/// Size of the record header: length and checksum of the payload.
+const HEADER_LEN: usize = 8;
+
+/// Transaction in the log, with the moment it happened at.
+pub type Record = (Transaction, Moment);
+
+/// When [Wal] asks OS to flush written records to the disk (fsync).
+///
+/// Records are handed to the OS right away regardless of it, so they
+/// survive crash of the process. Policy only matters for power loss.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SyncPolicy {
+    /// After every record. Safest, but slowest.
+    #[default]
+    Always,
+    /// After every `n` records.
+    Every(u32),
+    /// Never, it's left to the OS.
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => match s.parse() {
+                Ok(n) if n > 0 => Ok(Self::Every(n)),
+                _ => Err(format!("unknown sync policy: {:?}", s)),
+            },
+        }
+    }
+}
+
+/// Append-only write-ahead log of transactions.
+///
+/// Every record is framed as `length (u32 le) | crc32 (u32 le) | json`
+/// of the transaction and it's moment, so that the record torn by a crash in the middle
+/// of the write is detected on recovery.
+pub struct Wal {
+    file: File,
+    sync: SyncPolicy,
+    /// Records written since the last fsync.
+    unsynced: u32,
+    /// Position the next record is written at.
+    end: u64,
+    /// Position of the last record, so it can be taken back.
+    last: u64,
+    buf: Vec<u8>,
+}
+
+impl Wal {
+    /// Opens log at `path`, creating it if it doesn't exist yet, and
+    /// returns transactions that are already in it, with their moments,
+    /// in order they were written. Torn or corrupted tail of the log is cut off, since that's
+    /// what crash in the middle of the write leaves behind.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        sync: SyncPolicy,
+    ) -> Result<(Self, Vec<Record>), WalError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let mut txs = vec![];
+        let mut pos = 0;
+        while let Some((payload, end)) = read_record(&data, pos) {
+            txs.push(serde_json::from_slice(payload)?);
+            pos = end;
+        }
+
+        if pos < data.len() {
+            file.set_len(pos as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(pos as u64))?;
+
+        let end = pos as u64;
+        let wal = Self { file, sync, unsynced: 0, end, last: end, buf: vec![] };
+        Ok((wal, txs))
+    }
+
+    /// Empties log at `path`, once the state it leads to is kept
+    /// elsewhere, e.g. in a snapshot, so it isn't replayed on top of it.
+    pub fn reset<P: AsRef<Path>>(path: P) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(0)?;
+        file.sync_all()
+    }
+
+    /// Appends transaction, that happened `at`, to the log. When it
+    /// returns, record is written and synced according to the [SyncPolicy].
+    pub fn append(&mut self, tx: &Transaction, at: Moment) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.extend_from_slice(&[0; HEADER_LEN]);
+        serde_json::to_writer(&mut self.buf, &(tx, at))?;
+
+        let payload = &self.buf[HEADER_LEN..];
+        let len = payload.len() as u32;
+        let checksum = crc32fast::hash(payload);
+        self.buf[..4].copy_from_slice(&len.to_le_bytes());
+        self.buf[4..HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+
+        if let Err(err) = self.file.write_all(&self.buf) {
+            // part of the record that made it would hide records after it.
+            self.cut_at(self.end)?;
+            return Err(err);
+        }
+        self.last = self.end;
+        self.end += self.buf.len() as u64;
+        self.unsynced += 1;
+
+        match self.sync {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Takes back the last record, e.g. when transaction written
+    /// ahead turned out to be refused. Isn't synced, since record that
+    /// survives a crash is just refused again when it's replayed.
+    pub fn retract(&mut self) -> io::Result<()> {
+        self.cut_at(self.last)
+    }
+
+    fn cut_at(&mut self, pos: u64) -> io::Result<()> {
+        self.file.set_len(pos)?;
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.end = pos;
+        self.last = pos;
+        Ok(())
+    }
+
+    /// Flushes written records to the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if self.unsynced > 0 && self.sync != SyncPolicy::Never {
+            let _ = self.sync();
+        }
+    }
+}
+
+/// Reads record starting at `pos`. Returns it's payload and position
+/// where it ends, or `None` if it's incomplete or checksum doesn't match.
+fn read_record(data: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let header = data.get(pos..pos + HEADER_LEN)?;
+    let mut len = [0; 4];
+    let mut checksum = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    checksum.copy_from_slice(&header[4..]);
+
+    let start = pos + HEADER_LEN;
+    let end = start.checked_add(u32::from_le_bytes(len) as usize)?;
+    let payload = data.get(start..end)?;
+
+    if crc32fast::hash(payload) != u32::from_le_bytes(checksum) {
+        return None;
+    }
+    Some((payload, end))
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WalError {
+    Io(io::Error),
+    /// Record's checksum matches, but it isn't a valid transaction.
+    Format(serde_json::Error),
+}
+
+impl fmt::Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Format(err) => write!(f, "invalid log record: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for WalError {}
+
+impl From<io::Error> for WalError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for WalError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Format(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::types::Amount;
+    use crate::transaction::{TransactionInfo, TransactionRef};
+
+    /// Path in temp dir unique to the test, without leftovers of previous runs.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("payments-engine-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn at(seq: u64, timestamp: Option<u64>) -> Moment {
+        Moment { seq, timestamp }
+    }
+
+    fn txs() -> Vec<Transaction> {
+        vec![
+            Transaction::Deposit(TransactionInfo::new(1, 1, Amount::new(15, 1))),
+            Transaction::Dispute(TransactionRef::new(1, 1)),
+        ]
+    }
+
+    #[test]
+    fn append_and_recover() {
+        let path = temp_path("wal-append");
+        {
+            let (mut wal, logged) = Wal::open(&path, SyncPolicy::Every(2)).unwrap();
+            assert!(logged.is_empty());
+            wal.append(&txs()[0], at(1, Some(1_600_000_000))).unwrap();
+            wal.append(&txs()[1], at(3, None)).unwrap();
+        }
+
+        let (mut wal, logged) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        let (logged_txs, moments): (Vec<_>, Vec<_>) = logged.into_iter().unzip();
+        assert_eq!(format!("{:?}", logged_txs), format!("{:?}", txs()));
+        assert_eq!(moments, vec![at(1, Some(1_600_000_000)), at(3, None)]);
+
+        // appends after recovered records.
+        wal.append(&txs()[0], at(4, None)).unwrap();
+        drop(wal);
+        assert_eq!(Wal::open(&path, SyncPolicy::Never).unwrap().1.len(), 3);
+
+        Wal::reset(&path).unwrap();
+        assert!(Wal::open(&path, SyncPolicy::Never).unwrap().1.is_empty());
+
+        // taken back record is gone, later ones are appended in it's place.
+        let (mut wal, _) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        wal.append(&txs()[0], at(1, None)).unwrap();
+        wal.append(&txs()[1], at(2, None)).unwrap();
+        wal.retract().unwrap();
+        wal.append(&txs()[1], at(3, None)).unwrap();
+        drop(wal);
+        let moments: Vec<_> = Wal::open(&path, SyncPolicy::Never).unwrap().1
+            .into_iter().map(|(_, at)| at).collect();
+        assert_eq!(moments, vec![at(1, None), at(3, None)]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let path = temp_path("wal-torn");
+        {
+            let (mut wal, _) = Wal::open(&path, SyncPolicy::Always).unwrap();
+            for tx in &txs() {
+                wal.append(tx, Moment::default()).unwrap();
+            }
+        }
+        let full_len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full_len - 3).unwrap();
+        drop(file);
+
+        let (_, logged) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        assert_eq!(logged.len(), 1);
+        assert!(fs::metadata(&path).unwrap().len() < full_len - 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_record_is_cut_off() {
+        let path = temp_path("wal-corrupted");
+        {
+            let (mut wal, _) = Wal::open(&path, SyncPolicy::Always).unwrap();
+            for tx in &txs() {
+                wal.append(tx, Moment::default()).unwrap();
+            }
+        }
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let (_, logged) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        assert_eq!(logged.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parse_sync_policy() {
+        assert_eq!("always".parse(), Ok(SyncPolicy::Always));
+        assert_eq!("never".parse(), Ok(SyncPolicy::Never));
+        assert_eq!("100".parse(), Ok(SyncPolicy::Every(100)));
+        assert!("0".parse::<SyncPolicy>().is_err());
+    }
+}